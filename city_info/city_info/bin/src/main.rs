@@ -1,12 +1,92 @@
-use std::{process::ExitCode, time::Duration};
+use std::{path::PathBuf, process::ExitCode, time::Duration};
 
-use dispatcher::spawn_dispatcher;
+use dispatcher::{spawn_dispatcher, ApiEndpoint, DispatcherConfig, GazetteerSource};
 use rest_api::start_rest_api;
 use tokio::signal::unix::SignalKind;
 use tokio_util::sync::CancellationToken;
 use tracing::level_filters::LevelFilter;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
 
+/// Build our dispatcher config, letting the upstream base urls be overridden from the environment
+/// (e.g. to point at a self-hosted nominatim instance)
+fn dispatcher_config_from_env() -> DispatcherConfig {
+    let mut config = DispatcherConfig::default();
+
+    // nominatim asks that the user agent identifies us and how to get in touch, e.g. "city_info (ops@example.com)"
+    if let Ok(user_agent) = std::env::var("CITY_INFO_USER_AGENT") {
+        config.http_client.user_agent = user_agent;
+    }
+    if let Ok(proxy) = std::env::var("CITY_INFO_HTTP_PROXY") {
+        config.http_client.proxy = Some(proxy);
+    }
+    // extra PEM certificates to trust, separated like `PATH` entries
+    if let Some(paths) = std::env::var_os("CITY_INFO_ROOT_CERTS") {
+        config.http_client.root_certificates = std::env::split_paths(&paths).collect();
+    }
+
+    if let Ok(base_url) = std::env::var("CITY_INFO_WEATHER_URL") {
+        config.weather.endpoint.base_url = base_url;
+    }
+    if let Ok(base_url) = std::env::var("CITY_INFO_OPEN_METEO_URL") {
+        if base_url.is_empty() {
+            // an empty url turns the weather fallback off
+            config.weather_fallback = None;
+        } else if let Some(endpoint) = &mut config.weather_fallback {
+            endpoint.base_url = base_url;
+        }
+    }
+    if let Ok(base_url) = std::env::var("CITY_INFO_CITY_STATS_URL") {
+        config.city_stats.endpoint.base_url = base_url;
+    }
+    // geocode offline, either from the cities bundled with the binary or a GeoNames dump on disk
+    let gazetteer = match std::env::var("CITY_INFO_GAZETTEER") {
+        Ok(source) if source == "embedded" => Some(GazetteerSource::Embedded),
+        Ok(path) if !path.is_empty() => Some(GazetteerSource::Files {
+            cities: PathBuf::from(path),
+            admin1_codes: std::env::var_os("CITY_INFO_GAZETTEER_ADMIN1").map(PathBuf::from),
+        }),
+        _ => None,
+    };
+    if let Some(gazetteer) = gazetteer {
+        config.gazetteer = gazetteer;
+        for source in &mut config.sources {
+            if source == "city_stats" {
+                *source = String::from("gazetteer");
+            }
+        }
+    }
+    // find cities' timezones in a timezone-boundary-builder release, rather than by their nearest principal city
+    if let Some(path) = std::env::var_os("CITY_INFO_TIMEZONE_BOUNDARIES") {
+        config.timezone_boundaries = Some(PathBuf::from(path));
+    }
+    // a url takes precedence over the language, as it picks the language itself
+    if let Ok(base_url) = std::env::var("CITY_INFO_WIKIPEDIA_URL") {
+        config.wikipedia.endpoint.base_url = base_url;
+    } else if let Ok(language) = std::env::var("CITY_INFO_WIKIPEDIA_LANGUAGE") {
+        config.wikipedia.endpoint = ApiEndpoint::wikipedia(&language);
+    }
+    // which sources to ask, in order, e.g. "gazetteer,weather,wikipedia"
+    if let Ok(sources) = std::env::var("CITY_INFO_SOURCES") {
+        config.sources = source_names(&sources);
+    }
+    // which of those we aren't ready without, e.g. "gazetteer,weather"
+    if let Ok(sources) = std::env::var("CITY_INFO_REQUIRED_SOURCES") {
+        config.required_sources = source_names(&sources);
+    }
+
+    config
+}
+
+/// Split a comma separated list of source names
+fn source_names(sources: &str) -> Vec<String> {
+    sources
+        .split(',')
+        .map(str::trim)
+        .filter(|source| !source.is_empty())
+        .map(String::from)
+        .collect()
+}
+
 #[tokio::main]
 async fn main() -> ExitCode {
     // setup a tracing subscriber to route our process logs to stdout
@@ -27,8 +107,17 @@ async fn main() -> ExitCode {
     // set up a parent level cancellation token
     let parent_token = CancellationToken::new();
 
-    // start the dispatcher task running
-    let dispatcher_handle = spawn_dispatcher(parent_token.clone());
+    // start the dispatcher task running. If it can't start there's nothing useful we can do, so rather than
+    // serving requests that will all fail, we exit
+    let dispatcher_handle =
+        match spawn_dispatcher(dispatcher_config_from_env(), parent_token.clone()) {
+            Ok(dispatcher_handle) => dispatcher_handle,
+            Err(e) => {
+                tracing::error!("city_info server failed to start: {e}");
+                parent_token.cancel();
+                return ExitCode::FAILURE;
+            }
+        };
 
     // start the http_server task running and pass it the dispatcher handle so it can send requests
     let api_task = start_rest_api(dispatcher_handle, parent_token.clone());
@@ -0,0 +1,392 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::{
+    metrics::CacheMetrics, CityData, CityDataError, CityDataResult, CityDataSource, CityQuery,
+};
+
+/// How a `Cached` data source should cache its results
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// how long a successful result is served from the cache
+    pub ttl: Duration,
+    /// how long a "no city found" result is served from the cache. This is usually much shorter than
+    /// `ttl` so a typo doesn't stick around for long
+    pub negative_ttl: Duration,
+    /// the maximum number of cities to keep, once full the least recently used entry is evicted
+    pub capacity: usize,
+}
+
+impl CacheConfig {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            negative_ttl: Duration::from_secs(30),
+            capacity: 256,
+        }
+    }
+}
+
+/// Hit/miss counters for a `Cached` data source. These are shared (via `Arc`) so they can still be
+/// read after the data source has been moved in to its task
+#[derive(Debug, Default)]
+pub struct CacheStats {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl CacheStats {
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+struct CacheEntry {
+    // `None` means the upstream told us there is no such city
+    value: Option<CityData>,
+    expires_at: Instant,
+    // the value of `LruCache.clock` when this entry was last read or written
+    last_used: u64,
+}
+
+/// A very small LRU cache. Eviction is a linear scan for the least recently used entry, which is
+/// perfectly fine for the few hundred cities we expect to hold. A production implementation would
+/// likely reach for a crate with an intrusive linked list instead
+#[derive(Default)]
+struct LruCache {
+    entries: HashMap<String, CacheEntry>,
+    // a logical clock, bumped on every access, used to track recency
+    clock: u64,
+}
+
+impl LruCache {
+    /// Look up a live entry, dropping it if it has expired
+    fn get(&mut self, key: &str, now: Instant) -> Option<Option<CityData>> {
+        self.clock += 1;
+
+        let entry = self.entries.get_mut(key)?;
+        if entry.expires_at <= now {
+            self.entries.remove(key);
+            return None;
+        }
+
+        entry.last_used = self.clock;
+        Some(entry.value.clone())
+    }
+
+    fn insert(
+        &mut self,
+        key: String,
+        value: Option<CityData>,
+        expires_at: Instant,
+        capacity: usize,
+        now: Instant,
+    ) {
+        self.clock += 1;
+
+        if !self.entries.contains_key(&key) && self.entries.len() >= capacity {
+            // make room, preferring to drop anything that has already expired
+            self.entries.retain(|_, entry| entry.expires_at > now);
+
+            if self.entries.len() >= capacity {
+                let least_recently_used = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(key, _)| key.clone());
+
+                if let Some(evicted) = least_recently_used {
+                    self.entries.remove(&evicted);
+                }
+            }
+        }
+
+        self.entries.insert(
+            key,
+            CacheEntry {
+                value,
+                expires_at,
+                last_used: self.clock,
+            },
+        );
+    }
+}
+
+/// A caching decorator for any `CityDataSource`. Results are keyed by `CityQuery::key`, so
+/// "san jose" and " San  Jose " share an entry. Only successes and "no city found" results are cached,
+/// any other error is passed straight through so the next request tries again
+pub struct Cached<T>
+where
+    T: CityDataSource,
+{
+    inner: T,
+    config: CacheConfig,
+    // a std (rather than tokio) Mutex is fine here as it is never held across an `.await`
+    entries: Mutex<LruCache>,
+    stats: Arc<CacheStats>,
+    // if set, hits and misses are also exported as metrics
+    metrics: Option<CacheMetrics>,
+}
+
+impl<T> Cached<T>
+where
+    T: CityDataSource,
+{
+    pub fn new(inner: T, config: CacheConfig) -> Self {
+        Self {
+            inner,
+            config,
+            entries: Mutex::default(),
+            stats: Arc::default(),
+            metrics: None,
+        }
+    }
+
+    /// Export our hits and misses as metrics, labelled with the name of the `source` we're caching
+    #[must_use]
+    pub fn with_metrics(mut self, source: &str) -> Self {
+        self.metrics = Some(CacheMetrics::for_source(source));
+        self
+    }
+
+    /// Get a handle to this cache's hit/miss counters
+    pub fn stats(&self) -> Arc<CacheStats> {
+        self.stats.clone()
+    }
+
+    fn lookup(&self, key: &str) -> Option<Option<CityData>> {
+        self.entries
+            .lock()
+            .expect("cache mutex poisoned")
+            .get(key, Instant::now())
+    }
+
+    fn store(&self, key: String, value: Option<CityData>) {
+        let ttl = if value.is_some() {
+            self.config.ttl
+        } else {
+            self.config.negative_ttl
+        };
+        let now = Instant::now();
+
+        self.entries.lock().expect("cache mutex poisoned").insert(
+            key,
+            value,
+            now + ttl,
+            self.config.capacity.max(1),
+            now,
+        );
+    }
+}
+
+impl<T> CityDataSource for Cached<T>
+where
+    T: CityDataSource,
+{
+    async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+        let key = self.inner.request_key(&query);
+
+        if let Some(cached) = self.lookup(&key) {
+            self.stats.hits.fetch_add(1, Ordering::Relaxed);
+            if let Some(metrics) = &self.metrics {
+                metrics.hits.inc();
+            }
+            tracing::debug!("Cache hit for {key:?}");
+            return cached.ok_or(CityDataError::NotFound(query.city));
+        }
+
+        self.stats.misses.fetch_add(1, Ordering::Relaxed);
+        if let Some(metrics) = &self.metrics {
+            metrics.misses.inc();
+        }
+        tracing::debug!("Cache miss for {key:?}");
+
+        let result = self.inner.fetch_data(query).await;
+        match &result {
+            Ok(data) => self.store(key, Some(data.clone())),
+            Err(CityDataError::NotFound(_)) => self.store(key, None),
+            // anything else may well be transient, so don't remember it
+            Err(_) => {}
+        }
+
+        result
+    }
+
+    /// Health checks skip the cache, a cached answer says nothing about how the upstream is doing now
+    async fn health_check(&self) -> Option<CityDataResult<()>> {
+        self.inner.health_check().await
+    }
+
+    fn request_key(&self, query: &CityQuery) -> String {
+        self.inner.request_key(query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use crate::{
+        test_utils::CountingDataSource, CityData, CityDataError, CityDataResult, CityDataSource,
+        CityQuery, ForecastOptions,
+    };
+
+    use super::{CacheConfig, Cached};
+
+    fn make_cache(capacity: usize) -> (Cached<CountingDataSource>, Arc<AtomicUsize>) {
+        let source = CountingDataSource::default();
+        let calls = source.calls.clone();
+        let config = CacheConfig {
+            ttl: Duration::from_secs(60),
+            negative_ttl: Duration::from_secs(5),
+            capacity,
+        };
+
+        (Cached::new(source, config), calls)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_hit_and_expiry() {
+        let (cache, calls) = make_cache(8);
+
+        cache.fetch_data(CityQuery::new("San Jose")).await.unwrap();
+        // differently formatted names share the same entry
+        cache
+            .fetch_data(CityQuery::new(" san  JOSE "))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.stats().hits(), 1);
+        assert_eq!(cache.stats().misses(), 1);
+
+        // once the ttl passes we go back upstream
+        tokio::time::advance(Duration::from_secs(61)).await;
+        cache.fetch_data(CityQuery::new("San Jose")).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(cache.stats().misses(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_negative_results() {
+        let (cache, calls) = make_cache(8);
+
+        for _ in 0..2 {
+            let result = cache.fetch_data(CityQuery::new("Nowhereville")).await;
+            assert!(matches!(result, Err(CityDataError::NotFound(_))));
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // negative results expire on their own, shorter, ttl
+        tokio::time::advance(Duration::from_secs(6)).await;
+        _ = cache.fetch_data(CityQuery::new("Nowhereville")).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    /// Like `CountingDataSource`, but its answers depend on the forecast asked for
+    struct ForecastDataSource(CountingDataSource);
+
+    impl CityDataSource for ForecastDataSource {
+        async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+            self.0.fetch_data(query).await
+        }
+
+        fn request_key(&self, query: &CityQuery) -> String {
+            query.weather_key()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_key() {
+        let forecast = CityQuery::new("Chicago").with_forecast(ForecastOptions {
+            days: 3,
+            hourly: false,
+        });
+
+        // a source that doesn't care about forecasts answers both queries the same
+        let (cache, calls) = make_cache(8);
+        cache.fetch_data(CityQuery::new("Chicago")).await.unwrap();
+        cache.fetch_data(forecast.clone()).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let source = CountingDataSource::default();
+        let calls = source.calls.clone();
+        let cache = Cached::new(
+            ForecastDataSource(source),
+            CacheConfig::new(Duration::from_secs(60)),
+        );
+        cache.fetch_data(CityQuery::new("Chicago")).await.unwrap();
+        cache.fetch_data(forecast.clone()).await.unwrap();
+        cache.fetch_data(forecast).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_errors_not_cached() {
+        let (cache, calls) = make_cache(8);
+
+        for _ in 0..2 {
+            assert!(cache
+                .fetch_data(CityQuery::new("Broken Bow"))
+                .await
+                .is_err());
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(cache.stats().hits(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_lru_eviction() {
+        let (cache, calls) = make_cache(2);
+
+        cache.fetch_data(CityQuery::new("Austin")).await.unwrap();
+        cache.fetch_data(CityQuery::new("Boston")).await.unwrap();
+        // touch Austin so Boston becomes the least recently used
+        cache.fetch_data(CityQuery::new("Austin")).await.unwrap();
+        cache.fetch_data(CityQuery::new("Chicago")).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+
+        // Austin survived, Boston was evicted
+        cache.fetch_data(CityQuery::new("Austin")).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        cache.fetch_data(CityQuery::new("Boston")).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let (cache, _) = make_cache(8);
+        let cache = cache.with_metrics("cache_metrics_test");
+
+        cache.fetch_data(CityQuery::new("Oslo")).await.unwrap();
+        cache.fetch_data(CityQuery::new("Oslo")).await.unwrap();
+        cache.fetch_data(CityQuery::new("Oslo")).await.unwrap();
+
+        let exported = prometheus::TextEncoder::new()
+            .encode_to_string(&prometheus::gather())
+            .expect("expected metrics to encode");
+        assert!(exported.contains(
+            "city_info_source_cache_lookups_total{result=\"hit\",source=\"cache_metrics_test\"} 2"
+        ));
+        assert!(exported.contains(
+            "city_info_source_cache_lookups_total{result=\"miss\",source=\"cache_metrics_test\"} 1"
+        ));
+    }
+}
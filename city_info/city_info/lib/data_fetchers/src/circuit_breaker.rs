@@ -0,0 +1,291 @@
+use std::{sync::Mutex, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::{CityData, CityDataError, CityDataResult, CityDataSource, CityQuery};
+
+/// When a `CircuitBreaker` should trip, and how long it stays tripped
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CircuitBreakerConfig {
+    /// how many upstream failures in a row open the circuit
+    pub failure_threshold: u32,
+    /// how long the circuit stays open before we let a trial request through to see if the upstream
+    /// has recovered
+    pub cool_down: Duration,
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            cool_down: Duration::from_secs(30),
+        }
+    }
+}
+
+/// The classic circuit breaker states, see <https://martinfowler.com/bliki/CircuitBreaker.html>
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BreakerState {
+    /// all is well, requests flow through as normal
+    Closed { consecutive_failures: u32 },
+    /// the upstream is failing, requests fail fast until `until`
+    Open { until: Instant },
+    /// the cool down has passed and a single trial request is in flight, everyone else still fails fast.
+    /// If the trial never reports back (e.g. it was cancelled) another is allowed after `cool_down`
+    HalfOpen { trial_started: Instant },
+}
+
+/// A circuit breaking decorator for any `CityDataSource`. After `failure_threshold` upstream failures in a
+/// row the circuit "opens" and requests fail immediately with `CityDataError::SourceUnavailable` instead
+/// of waiting on an upstream we know is down. After `cool_down` a single request is let through, and its
+/// result decides whether the circuit closes again or stays open for another `cool_down`
+///
+/// Only failures that say something about the upstream's health count (timeouts, connection failures and
+/// 5xx responses). A city that doesn't exist, or a 429, means the upstream is up and talking to us
+pub struct CircuitBreaker<T>
+where
+    T: CityDataSource,
+{
+    inner: T,
+    config: CircuitBreakerConfig,
+    state: Mutex<BreakerState>,
+}
+
+/// Whether an error indicates the upstream itself is unhealthy
+fn is_upstream_failure(error: &CityDataError) -> bool {
+    error.is_transient() && !matches!(error, CityDataError::RateLimited { .. })
+}
+
+impl<T> CircuitBreaker<T>
+where
+    T: CityDataSource,
+{
+    pub fn new(inner: T, config: CircuitBreakerConfig) -> Self {
+        Self {
+            inner,
+            config,
+            state: Mutex::new(BreakerState::Closed {
+                consecutive_failures: 0,
+            }),
+        }
+    }
+
+    /// Decide whether a request may go through to the upstream
+    fn allow_request(&self) -> bool {
+        let mut state = self.state.lock().expect("circuit breaker mutex poisoned");
+        let now = Instant::now();
+
+        match *state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { until } if now < until => false,
+            BreakerState::Open { .. } => {
+                tracing::info!(
+                    "Circuit breaker cool down elapsed, half-opening to trial the upstream"
+                );
+                *state = BreakerState::HalfOpen { trial_started: now };
+                true
+            }
+            BreakerState::HalfOpen { trial_started }
+                if now.duration_since(trial_started) >= self.config.cool_down =>
+            {
+                tracing::info!("Circuit breaker trial request never completed, starting another");
+                *state = BreakerState::HalfOpen { trial_started: now };
+                true
+            }
+            BreakerState::HalfOpen { .. } => false,
+        }
+    }
+
+    /// Update our state with the outcome of a request
+    fn record(&self, upstream_failed: bool) {
+        let mut state = self.state.lock().expect("circuit breaker mutex poisoned");
+        let now = Instant::now();
+
+        *state = match (*state, upstream_failed) {
+            (BreakerState::Closed { .. }, false) => BreakerState::Closed {
+                consecutive_failures: 0,
+            },
+            (
+                BreakerState::Closed {
+                    consecutive_failures,
+                },
+                true,
+            ) if consecutive_failures + 1 >= self.config.failure_threshold => {
+                tracing::warn!(
+                    "Circuit breaker opening after {} consecutive failures, failing fast for {:?}",
+                    consecutive_failures + 1,
+                    self.config.cool_down
+                );
+                BreakerState::Open {
+                    until: now + self.config.cool_down,
+                }
+            }
+            (
+                BreakerState::Closed {
+                    consecutive_failures,
+                },
+                true,
+            ) => BreakerState::Closed {
+                consecutive_failures: consecutive_failures + 1,
+            },
+            (BreakerState::HalfOpen { .. }, false) => {
+                tracing::info!("Circuit breaker trial request succeeded, closing");
+                BreakerState::Closed {
+                    consecutive_failures: 0,
+                }
+            }
+            (BreakerState::HalfOpen { .. }, true) => {
+                tracing::warn!(
+                    "Circuit breaker trial request failed, re-opening for {:?}",
+                    self.config.cool_down
+                );
+                BreakerState::Open {
+                    until: now + self.config.cool_down,
+                }
+            }
+            // a request that started before we opened has finished, it doesn't change anything
+            (open @ BreakerState::Open { .. }, _) => open,
+        };
+    }
+}
+
+impl<T> CityDataSource for CircuitBreaker<T>
+where
+    T: CityDataSource,
+{
+    async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+        if !self.allow_request() {
+            tracing::debug!(
+                "Circuit breaker open, failing request for {:?} fast",
+                query.city
+            );
+            return Err(CityDataError::SourceUnavailable);
+        }
+
+        let result = self.inner.fetch_data(query).await;
+        self.record(result.as_ref().is_err_and(is_upstream_failure));
+
+        result
+    }
+
+    /// Health checks always go through, even while the circuit is open, as they're how we find out the
+    /// upstream is back. They don't affect the circuit either way
+    async fn health_check(&self) -> Option<CityDataResult<()>> {
+        self.inner.health_check().await
+    }
+
+    fn request_key(&self, query: &CityQuery) -> String {
+        self.inner.request_key(query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use crate::{CityData, CityDataError, CityDataResult, CityDataSource, CityQuery, PlaceRecord};
+
+    use super::{CircuitBreaker, CircuitBreakerConfig};
+
+    /// A data source that times out while `failing` is set, counting how many requests reach it
+    #[derive(Clone, Default)]
+    struct FlakyDataSource {
+        failing: Arc<AtomicBool>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl CityDataSource for FlakyDataSource {
+        async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+            let city = query.city;
+            self.calls.fetch_add(1, Ordering::SeqCst);
+
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(CityDataError::Timeout);
+            }
+            if city.starts_with("Nowhere") {
+                return Err(CityDataError::NotFound(city));
+            }
+
+            Ok(CityData::Place(PlaceRecord {
+                name: city.clone(),
+                display_name: city,
+                latitude: 0.0,
+                longitude: 0.0,
+                ..PlaceRecord::default()
+            }))
+        }
+    }
+
+    fn make_breaker() -> (CircuitBreaker<FlakyDataSource>, FlakyDataSource) {
+        let source = FlakyDataSource::default();
+        let config = CircuitBreakerConfig {
+            failure_threshold: 3,
+            cool_down: Duration::from_secs(10),
+        };
+
+        (CircuitBreaker::new(source.clone(), config), source)
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_opens_after_threshold() {
+        let (breaker, source) = make_breaker();
+        source.failing.store(true, Ordering::SeqCst);
+
+        for _ in 0..3 {
+            let result = breaker.fetch_data(CityQuery::new("Downtown")).await;
+            assert!(matches!(result, Err(CityDataError::Timeout)));
+        }
+
+        // the circuit is now open, so we fail fast without reaching the source
+        let result = breaker.fetch_data(CityQuery::new("Downtown")).await;
+        assert!(matches!(result, Err(CityDataError::SourceUnavailable)));
+        assert_eq!(source.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_success_resets_failures() {
+        let (breaker, source) = make_breaker();
+
+        for _ in 0..5 {
+            source.failing.store(true, Ordering::SeqCst);
+            _ = breaker.fetch_data(CityQuery::new("Downtown")).await;
+            _ = breaker.fetch_data(CityQuery::new("Downtown")).await;
+
+            source.failing.store(false, Ordering::SeqCst);
+            // a "not found" means the upstream is answering, so it counts in our favour
+            _ = breaker.fetch_data(CityQuery::new("Nowhere Special")).await;
+        }
+
+        assert!(breaker.fetch_data(CityQuery::new("Uptown")).await.is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_half_open_recovery() {
+        let (breaker, source) = make_breaker();
+        source.failing.store(true, Ordering::SeqCst);
+        for _ in 0..3 {
+            _ = breaker.fetch_data(CityQuery::new("Downtown")).await;
+        }
+
+        // after the cool down a trial request is let through, it fails so we re-open
+        tokio::time::advance(Duration::from_secs(11)).await;
+        let result = breaker.fetch_data(CityQuery::new("Downtown")).await;
+        assert!(matches!(result, Err(CityDataError::Timeout)));
+        let result = breaker.fetch_data(CityQuery::new("Downtown")).await;
+        assert!(matches!(result, Err(CityDataError::SourceUnavailable)));
+        assert_eq!(source.calls.load(Ordering::SeqCst), 4);
+
+        // the upstream recovers, the next trial succeeds and closes the circuit
+        source.failing.store(false, Ordering::SeqCst);
+        tokio::time::advance(Duration::from_secs(11)).await;
+        assert!(breaker.fetch_data(CityQuery::new("Downtown")).await.is_ok());
+        assert!(breaker.fetch_data(CityQuery::new("Uptown")).await.is_ok());
+        assert_eq!(source.calls.load(Ordering::SeqCst), 6);
+    }
+}
@@ -1,50 +1,86 @@
 use std::fmt::Display;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::{CityDataError, CityDataResult};
+use crate::{
+    http::{get, parse_json, probe_query, snippet, url_encode},
+    normalize_city_name, ApiEndpoint, CityData, CityDataError, CityDataResult, CityQuery,
+    Coordinates,
+};
 
-const CITY_STATS_API_PATH: &str = "https://nominatim.openstreetmap.org/search?q=";
-const CITY_STATS_API_ARGS: &str = "&format=json&limit=1"; // format response as json and limit to one result
+pub(crate) const CITY_STATS_API_PATH: &str = "https://nominatim.openstreetmap.org/search?q=";
+// format response as json, with an address breakdown for each of up to 10 candidates
+pub(crate) const CITY_STATS_API_ARGS: &str = "&format=json&addressdetails=1&limit=10";
 
-fn request_path_for_city(city: &str) -> String {
-    // replaces spaces with '+'
-    let space_subbed_city = city.replace(' ', "+");
+impl ApiEndpoint {
+    /// The public nominatim OSM search endpoint
+    pub fn nominatim() -> Self {
+        Self::new(CITY_STATS_API_PATH, CITY_STATS_API_ARGS)
+    }
+}
+
+fn request_path_for_query(endpoint: &ApiEndpoint, query: &CityQuery) -> CityDataResult<String> {
+    // the country code goes in to the url as it is, so make sure it can't add parameters of its own
+    query.validate()?;
+
+    let url = endpoint.url_for(&url_encode(&query.city));
 
-    format!("{CITY_STATS_API_PATH}{space_subbed_city}{CITY_STATS_API_ARGS}")
+    // nominatim can narrow by country itself, which keeps other countries' matches from using up our
+    // limit. There's no equivalent for states alongside a free-form `q`, so those are filtered afterwards
+    Ok(match &query.country_code {
+        Some(country_code) => format!("{url}&countrycodes={}", country_code.trim().to_lowercase()),
+        None => url,
+    })
+}
+
+/// A request nominatim should always be able to answer, for health checks (see `http::probe`)
+pub(crate) fn probe_url(endpoint: &ApiEndpoint) -> String {
+    endpoint.url_for(&url_encode(&probe_query().city))
 }
 
 async fn query_city_api(
     http_client: &reqwest::Client,
-    city_name: &str,
+    endpoint: &ApiEndpoint,
+    query: &CityQuery,
 ) -> CityDataResult<Vec<CityStatsResponse>> {
-    http_client
-        .get(request_path_for_city(city_name))
-        .send()
-        .await
-        .map_err(|e| CityDataError::FetchError(e.to_string()))?
-        .error_for_status()
-        .map_err(|e| CityDataError::FetchError(e.to_string()))?
-        .json::<Vec<CityStatsResponse>>()
-        .await
-        .inspect_err(|e| tracing::error!("Got error: {e:?}"))
-        .map_err(|_| CityDataError::FetchError(String::from("deserialize failed")))
+    let response = get(http_client, &request_path_for_query(endpoint, query)?).await?;
+
+    parse_json::<Vec<CityStatsResponse>>(response).await
 }
 
 /// Fetches city statistics using the nominatim OSM API:
 /// <https://nominatim.org/release-docs/latest/api/Search/>
 pub(crate) async fn fetch_city_stats(
     http_client: &reqwest::Client,
-    city_name: String,
-) -> CityDataResult<String> {
-    let city_stats_response = query_city_api(http_client, &city_name).await?;
+    endpoint: &ApiEndpoint,
+    query: CityQuery,
+) -> CityDataResult<CityData> {
+    let city_stats_response = query_city_api(http_client, endpoint, &query).await?;
+
+    let candidates = city_stats_response
+        .into_iter()
+        .enumerate()
+        .map(|(index, response)| PlaceRecord::from_response(index, response))
+        .collect::<CityDataResult<Vec<_>>>()?;
+
+    let mut candidates = rank_candidates(candidates, &query).into_iter();
+    let mut best_match = candidates
+        .next()
+        .ok_or(CityDataError::NotFound(query.city))?;
+    best_match.alternatives = candidates.collect();
+
+    Ok(CityData::Place(best_match))
+}
 
-    // Just grab the first result,
-    let city_details = city_stats_response
-        .first()
-        .ok_or(CityDataError::FetchError(String::from("no city found")))?;
+/// Drop any candidates outside the country or state the query asked for, and order the rest from most
+/// to least important. Nominatim's importance is roughly "how likely is this the place people mean",
+/// based on things like wikipedia links and population, so Paris, France ranks above Paris, Texas
+fn rank_candidates(mut candidates: Vec<PlaceRecord>, query: &CityQuery) -> Vec<PlaceRecord> {
+    candidates.retain(|candidate| candidate.matches(query));
+    // a stable sort, so ties keep nominatim's own ordering
+    candidates.sort_by(|a, b| b.importance.total_cmp(&a.importance));
 
-    Ok(city_details.to_string())
+    candidates
 }
 
 /// A struct representing a response from the nominatim OSM API
@@ -55,43 +91,384 @@ struct CityStatsResponse {
     #[serde(rename = "display_name")]
     // look for a field in the input named "display_string" and populate this struct field with its contents
     city_county_state_country_str: String,
+    #[serde(default)]
+    name: String,
+    // nominatim reports coordinates as strings, they're parsed when converting to a `PlaceRecord`
+    lat: String,
+    lon: String,
+    #[serde(default)]
+    importance: f64,
+    // the kind of feature, e.g. "city", "town", "administrative". `addresstype` is the more useful of the
+    // two but is missing from older nominatim versions
+    #[serde(rename = "type", default)]
+    osm_type: String,
+    #[serde(default)]
+    addresstype: Option<String>,
+    // only present as we ask for `addressdetails=1`
+    #[serde(default)]
+    address: CityStatsAddress,
+}
+
+/// The parts of a nominatim address breakdown we're interested in
+#[derive(Default, Deserialize)]
+struct CityStatsAddress {
+    country: Option<String>,
+    country_code: Option<String>,
+    state: Option<String>,
+}
+
+/// A geocoded place, as reported by nominatim (or the offline gazetteer, see `gazetteer::Gazetteer`)
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct PlaceRecord {
+    pub name: String,
+    pub display_name: String,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub country: Option<String>,
+    /// ISO 3166-1 alpha-2, lowercase (e.g. "us")
+    pub country_code: Option<String>,
+    pub state: Option<String>,
+    /// the kind of place, e.g. "city" or "town"
+    pub place_type: String,
+    /// nominatim's estimate of how prominent this place is, between 0 and 1
+    pub importance: f64,
+    /// how many people live there, if the source knows
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub population: Option<u64>,
+    /// in meters above sea level, if the source knows
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub elevation: Option<i32>,
+    /// the IANA timezone the place is in (e.g. "America/Los_Angeles"), if the source knows
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    /// other names the place goes by, e.g. in other languages
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub alternate_names: Vec<String>,
+    /// other places that matched the name, most important first. Empty if the match was unambiguous
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub alternatives: Vec<PlaceRecord>,
+}
+
+impl PlaceRecord {
+    /// Convert the `index`th entry of a nominatim response
+    fn from_response(index: usize, response: CityStatsResponse) -> CityDataResult<Self> {
+        Ok(Self {
+            latitude: parse_coordinate(index, "lat", &response.lat)?,
+            longitude: parse_coordinate(index, "lon", &response.lon)?,
+            name: response.name,
+            display_name: response.city_county_state_country_str,
+            country: response.address.country,
+            country_code: response
+                .address
+                .country_code
+                .map(|country_code| country_code.to_lowercase()),
+            state: response.address.state,
+            place_type: response.addresstype.unwrap_or(response.osm_type),
+            importance: response.importance,
+            // nominatim doesn't tell us any of these
+            population: None,
+            elevation: None,
+            timezone: None,
+            alternate_names: Vec::new(),
+            alternatives: Vec::new(),
+        })
+    }
+
+    /// Whether this place is within the country and state `query` is narrowed to (if it is at all)
+    pub(crate) fn matches(&self, query: &CityQuery) -> bool {
+        let country_matches = query.country_code.as_ref().is_none_or(|wanted| {
+            self.country_code
+                .as_ref()
+                .is_some_and(|country_code| country_code.eq_ignore_ascii_case(wanted.trim()))
+        });
+        let state_matches = query.state.as_ref().is_none_or(|wanted| {
+            self.state
+                .as_ref()
+                .is_some_and(|state| normalize_city_name(state) == normalize_city_name(wanted))
+        });
+
+        country_matches && state_matches
+    }
+
+    /// Where this place is, e.g. to look up other data for exactly this place rather than by name
+    pub fn coordinates(&self) -> Coordinates {
+        Coordinates {
+            latitude: self.latitude,
+            longitude: self.longitude,
+        }
+    }
+}
+
+/// Parse a coordinate out of the `index`th entry of a nominatim response, naming the field in the error
+/// if it's malformed
+fn parse_coordinate(index: usize, field: &str, value: &str) -> CityDataResult<f64> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| CityDataError::MalformedResponse {
+            path: format!("[{index}].{field}"),
+            message: String::from("expected a number"),
+            snippet: snippet(value),
+        })
 }
 
-/// impl Display for `CityStatsResponse` so we can call `to_string()` (or throw it into `format!()`)
-impl Display for CityStatsResponse {
+/// impl Display for `PlaceRecord` so we can call `to_string()` (or throw it into `format!()`)
+/// If the name was ambiguous, the other matches are listed so the user can narrow their search
+impl Display for PlaceRecord {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!(
-            "Stats for {}:",
-            self.city_county_state_country_str
-        ))
+        f.write_fmt(format_args!("Stats for {}:", self.display_name))?;
+
+        let mut details = Vec::new();
+        if let Some(population) = self.population {
+            details.push(format!("population {population}"));
+        }
+        if let Some(elevation) = self.elevation {
+            details.push(format!("elevation {elevation}m"));
+        }
+        if let Some(timezone) = &self.timezone {
+            details.push(format!("timezone {timezone}"));
+        }
+        if !details.is_empty() {
+            f.write_fmt(format_args!(" {}", details.join(", ")))?;
+        }
+
+        if !self.alternatives.is_empty() {
+            let alternatives = self
+                .alternatives
+                .iter()
+                .map(|alternative| alternative.display_name.as_str())
+                .collect::<Vec<_>>()
+                .join("; ");
+            f.write_fmt(format_args!("\nDid you mean: {alternatives}?"))?;
+        }
+
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::city_stats_api::query_city_api;
+    use std::collections::HashMap;
+
+    use axum::{extract::Query, routing::get, Router};
+
+    use crate::{
+        city_stats_api::query_city_api, test_utils::spawn_fixture_server, ApiEndpoint,
+        CityDataError, CityQuery,
+    };
 
-    use super::CityStatsResponse;
+    use super::{
+        rank_candidates, request_path_for_query, CityStatsAddress, CityStatsResponse, PlaceRecord,
+        CITY_STATS_API_ARGS,
+    };
+
+    fn make_test_response() -> CityStatsResponse {
+        CityStatsResponse {
+            city_county_state_country_str: String::from("Unit Test City"),
+            name: String::from("Unit Test"),
+            lat: String::from("37.3361663"),
+            lon: String::from("-121.890591"),
+            importance: 0.5,
+            osm_type: String::from("administrative"),
+            addresstype: Some(String::from("city")),
+            address: CityStatsAddress::default(),
+        }
+    }
+
+    fn make_candidate(
+        display_name: &str,
+        country_code: &str,
+        state: &str,
+        importance: f64,
+    ) -> PlaceRecord {
+        PlaceRecord {
+            name: String::from("Springfield"),
+            display_name: String::from(display_name),
+            country_code: Some(String::from(country_code)),
+            state: Some(String::from(state)),
+            place_type: String::from("city"),
+            importance,
+            ..PlaceRecord::default()
+        }
+    }
 
     #[tokio::test]
     async fn test_query_api() {
+        // serve a canned nominatim response, checking we were asked for the right city
+        let router = Router::new().route(
+            "/search",
+            get(|Query(params): Query<HashMap<String, String>>| async move {
+                assert_eq!(params.get("q").map(String::as_str), Some("San Jose"));
+                assert_eq!(params.get("format").map(String::as_str), Some("json"));
+                include_str!("../tests/fixtures/nominatim.json")
+            }),
+        );
+        let base_url = spawn_fixture_server(router).await;
+        let endpoint = ApiEndpoint::new(format!("{base_url}/search?q="), CITY_STATS_API_ARGS);
+
         let client = reqwest::Client::builder()
             .user_agent("rust_toys_test")
             .build()
             .expect("Failed to build user agent!");
 
-        query_city_api(&client, "San Jose").await.expect("WARNING: Failed to query or parse geocoding data for a known city, this means the API is not reachable or its response format has changed");
+        let response = query_city_api(&client, &endpoint, &CityQuery::new("San Jose")).await.expect("Failed to query or parse geocoding data from the fixture server, this means our response parsing has changed");
+        assert_eq!(response.len(), 2);
+        assert_eq!(response[0].name, "San José");
+        assert_eq!(response[1].address.country_code.as_deref(), Some("cr"));
+    }
+
+    #[test]
+    fn test_request_path() {
+        let endpoint = ApiEndpoint::nominatim();
+        let query = CityQuery::new("San Jose");
+        assert_eq!(
+            request_path_for_query(&endpoint, &query).expect("expected a valid query"),
+            "https://nominatim.openstreetmap.org/search?q=San+Jose&format=json&addressdetails=1&limit=10"
+        );
+
+        let narrowed = query.clone().with_country_code("CR");
+        assert!(request_path_for_query(&endpoint, &narrowed)
+            .expect("expected a valid query")
+            .ends_with("&limit=10&countrycodes=cr"));
+
+        // neither the city nor the country code can add parameters of their own
+        let injected = CityQuery::new("Paris&countrycodes=us#");
+        assert_eq!(
+            request_path_for_query(&endpoint, &injected).expect("expected a valid query"),
+            "https://nominatim.openstreetmap.org/search?q=Paris%26countrycodes%3Dus%23\
+             &format=json&addressdetails=1&limit=10"
+        );
+        let injected = query.with_country_code("us&limit=50");
+        assert!(matches!(
+            request_path_for_query(&endpoint, &injected),
+            Err(CityDataError::InvalidQuery(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_query_api_escapes_city() {
+        // the whole name should arrive as `q`, without adding or cutting off any parameters
+        let router = Router::new().route(
+            "/search",
+            get(|Query(params): Query<HashMap<String, String>>| async move {
+                assert_eq!(
+                    params.get("q").map(String::as_str),
+                    Some("Paris&countrycodes=us#top")
+                );
+                assert_eq!(params.get("countrycodes"), None);
+                assert_eq!(params.get("limit").map(String::as_str), Some("10"));
+                "[]"
+            }),
+        );
+        let base_url = spawn_fixture_server(router).await;
+        let endpoint = ApiEndpoint::new(format!("{base_url}/search?q="), CITY_STATS_API_ARGS);
+
+        let response = query_city_api(
+            &reqwest::Client::new(),
+            &endpoint,
+            &CityQuery::new("Paris&countrycodes=us#top"),
+        )
+        .await
+        .expect("expected the fixture server to answer");
+        assert!(response.is_empty());
     }
 
     #[test]
     fn test_format() {
-        let stats = CityStatsResponse {
-            city_county_state_country_str: String::from("Unit Test City"),
-        };
+        let stats =
+            PlaceRecord::from_response(0, make_test_response()).expect("expected a valid response");
 
         let expected_format = String::from("Stats for Unit Test City:");
 
         assert_eq!(format!("{stats}"), expected_format);
         assert_eq!(stats.to_string(), expected_format);
     }
+
+    #[test]
+    fn test_format_alternatives() {
+        let mut stats = make_candidate(
+            "Springfield, Illinois, United States",
+            "us",
+            "Illinois",
+            0.7,
+        );
+        stats.alternatives = vec![
+            make_candidate(
+                "Springfield, Missouri, United States",
+                "us",
+                "Missouri",
+                0.6,
+            ),
+            make_candidate(
+                "Springfield, Massachusetts, United States",
+                "us",
+                "Massachusetts",
+                0.5,
+            ),
+        ];
+
+        assert_eq!(
+            stats.to_string(),
+            "Stats for Springfield, Illinois, United States:\nDid you mean: Springfield, Missouri, United States; Springfield, Massachusetts, United States?"
+        );
+    }
+
+    #[test]
+    fn test_parse_coordinates() {
+        let place =
+            PlaceRecord::from_response(0, make_test_response()).expect("expected a valid response");
+        assert_eq!(place.latitude, 37.336_166_3);
+        assert_eq!(place.longitude, -121.890_591);
+        assert_eq!(place.place_type, "city");
+
+        let mut malformed = make_test_response();
+        malformed.lon = String::from("west-ish");
+        assert!(matches!(
+            PlaceRecord::from_response(3, malformed),
+            Err(CityDataError::MalformedResponse { path, .. }) if path == "[3].lon"
+        ));
+    }
+
+    #[test]
+    fn test_rank_candidates() {
+        let candidates = vec![
+            make_candidate(
+                "Springfield, Missouri, United States",
+                "us",
+                "Missouri",
+                0.6,
+            ),
+            make_candidate(
+                "Springfield, Illinois, United States",
+                "us",
+                "Illinois",
+                0.7,
+            ),
+            make_candidate(
+                "Springfield, Queensland, Australia",
+                "au",
+                "Queensland",
+                0.4,
+            ),
+        ];
+
+        // most important first
+        let ranked = rank_candidates(candidates.clone(), &CityQuery::new("Springfield"));
+        let states: Vec<_> = ranked.iter().filter_map(|c| c.state.as_deref()).collect();
+        assert_eq!(states, ["Illinois", "Missouri", "Queensland"]);
+
+        // narrowed by country and state, case doesn't matter
+        let query = CityQuery::new("Springfield").with_country_code("AU");
+        let ranked = rank_candidates(candidates.clone(), &query);
+        assert_eq!(ranked.len(), 1);
+        assert_eq!(ranked[0].state.as_deref(), Some("Queensland"));
+
+        let query = CityQuery::new("Springfield").with_state("missouri");
+        let ranked = rank_candidates(candidates.clone(), &query);
+        assert_eq!(ranked.len(), 1);
+        assert_eq!(ranked[0].country_code.as_deref(), Some("us"));
+
+        let query = CityQuery::new("Springfield").with_state("Oregon");
+        assert!(rank_candidates(candidates, &query).is_empty());
+    }
 }
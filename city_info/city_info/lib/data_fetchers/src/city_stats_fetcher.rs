@@ -1,48 +1,47 @@
-use tokio::sync::mpsc;
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    city_stats_api::fetch_city_stats, CityDataResult, CityDataSource, CityDataSourceHandle,
-    CityDataSourceTask,
+    city_stats_api::{fetch_city_stats, probe_url},
+    http::probe,
+    spawn_fetcher_task, ApiEndpoint, CityData, CityDataResult, CityDataSource,
+    CityDataSourceHandle, CityQuery, FetcherConfig, SpawnResult,
 };
 
 pub struct CityStatsFetcher {
     // An http client we can re-use to avoid re-initializing TLS stuff
-    // and do connection pooling
+    // and do connection pooling, usually shared with our other fetchers
     http_client: reqwest::Client,
+    // where to send our requests, see `ApiEndpoint::nominatim()` for the public default
+    endpoint: ApiEndpoint,
 }
 
 impl CityStatsFetcher {
-    fn new() -> Self {
-        let http_client = reqwest::Client::builder()
-            .user_agent("rust_toys_test") // this API requires a user-agent for usage tracking
-            .build()
-            // in the interest of simplicity, we use `expect()` which will panic if we fail to build the
-            // client. This should almost always be avoided in production code, but is fine here as
-            // build() should rarely fail for our use case
-            .expect("Failed to build user agent!");
-        Self { http_client }
+    /// Fetch from `endpoint` using `http_client`, which can be shared with other fetchers (see
+    /// `HttpClientConfig`)
+    pub fn new(endpoint: ApiEndpoint, http_client: reqwest::Client) -> Self {
+        Self {
+            http_client,
+            endpoint,
+        }
     }
 }
 
 impl CityDataSource for CityStatsFetcher {
-    async fn fetch_data(&self, city: String) -> CityDataResult<String> {
-        fetch_city_stats(&self.http_client, city).await
+    async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+        fetch_city_stats(&self.http_client, &self.endpoint, query).await
+    }
+
+    async fn health_check(&self) -> Option<CityDataResult<()>> {
+        Some(probe(&self.http_client, &probe_url(&self.endpoint)).await)
     }
 }
 
 pub fn spawn_city_stats_fetcher_task(
+    config: FetcherConfig,
+    http_client: reqwest::Client,
     cancellation_token: CancellationToken,
-) -> CityDataSourceHandle {
-    let fetcher = CityStatsFetcher::new();
-    let (sender, receiver) = mpsc::channel(16);
+) -> SpawnResult<CityDataSourceHandle> {
+    let fetcher = CityStatsFetcher::new(config.endpoint.clone(), http_client);
 
-    tokio::spawn(async move {
-        let mut task = CityDataSourceTask::new(fetcher);
-        task.run(receiver, cancellation_token).await;
-    });
-
-    CityDataSourceHandle {
-        data_request_sender: sender,
-    }
+    spawn_fetcher_task("city_stats", fetcher, &config, cancellation_token)
 }
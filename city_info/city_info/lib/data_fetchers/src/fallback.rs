@@ -0,0 +1,347 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use prometheus::IntCounter;
+
+use crate::{
+    metrics::fallback_answers_counter, CityData, CityDataError, CityDataResult, CityDataSource,
+    CityQuery,
+};
+
+/// Which provider in a `FallbackChain` answered each request. These are shared (via `Arc`) so they can
+/// still be read after the chain has been moved in to its task
+#[derive(Debug, Default)]
+pub struct FallbackStats {
+    // one counter per provider, created up front so counting never needs a lock
+    answered: HashMap<&'static str, AtomicU64>,
+    last_answered: Mutex<Option<&'static str>>,
+}
+
+impl FallbackStats {
+    /// How many requests `provider` has answered
+    pub fn answered_by(&self, provider: &str) -> u64 {
+        self.answered
+            .get(provider)
+            .map_or(0, |count| count.load(Ordering::Relaxed))
+    }
+
+    /// The provider that answered the most recent successful request
+    pub fn last_answered(&self) -> Option<&'static str> {
+        *self
+            .last_answered
+            .lock()
+            .expect("fallback stats mutex poisoned")
+    }
+
+    fn record(&self, provider: &'static str) {
+        if let Some(count) = self.answered.get(provider) {
+            count.fetch_add(1, Ordering::Relaxed);
+        }
+        *self
+            .last_answered
+            .lock()
+            .expect("fallback stats mutex poisoned") = Some(provider);
+    }
+}
+
+/// Tries each of its (named) providers in order, returning the first success. If every provider fails,
+/// the first provider's error is returned, as that's the source we'd most like to hear from. An error no
+/// other provider could do better with (e.g. an invalid query) is returned straight away
+///
+/// A provider that hangs would hold up the whole chain until the requester gives up, so each provider can
+/// be given a time limit (see `with_attempt_timeout`), after which it's counted as failed and the next
+/// provider is asked
+///
+/// The providers all have to be the same type, to chain different sources wrap them in an enum which
+/// impls `CityDataSource` by delegating to each variant (see `weather_fetcher::WeatherProvider`)
+pub struct FallbackChain<T>
+where
+    T: CityDataSource,
+{
+    providers: Vec<(&'static str, T)>,
+    stats: Arc<FallbackStats>,
+    attempt_timeout: Option<Duration>,
+    // each provider's counter in the `city_info_fallback_answers_total` metric, if we're recording them
+    metrics: HashMap<&'static str, IntCounter>,
+}
+
+impl<T> FallbackChain<T>
+where
+    T: CityDataSource,
+{
+    pub fn new(providers: Vec<(&'static str, T)>) -> Self {
+        let stats = FallbackStats {
+            answered: providers
+                .iter()
+                .map(|(name, _)| (*name, AtomicU64::new(0)))
+                .collect(),
+            last_answered: Mutex::default(),
+        };
+
+        Self {
+            providers,
+            stats: Arc::new(stats),
+            attempt_timeout: None,
+            metrics: HashMap::new(),
+        }
+    }
+
+    /// Give each provider at most `attempt_timeout` to answer (retries included) before asking the next
+    #[must_use]
+    pub fn with_attempt_timeout(mut self, attempt_timeout: Duration) -> Self {
+        self.attempt_timeout = Some(attempt_timeout);
+        self
+    }
+
+    /// Count which provider answered each request in the `city_info_fallback_answers_total` metric, labelled
+    /// with the name of the `source` this chain belongs to
+    #[must_use]
+    pub fn with_metrics(mut self, source: &str) -> Self {
+        self.metrics = self
+            .providers
+            .iter()
+            .map(|(name, _)| (*name, fallback_answers_counter(source, name)))
+            .collect();
+        self
+    }
+
+    /// Get a handle to this chain's record of who answered what
+    pub fn stats(&self) -> Arc<FallbackStats> {
+        self.stats.clone()
+    }
+}
+
+impl<T> CityDataSource for FallbackChain<T>
+where
+    T: CityDataSource,
+{
+    async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+        let mut first_error = None;
+
+        for (name, provider) in &self.providers {
+            let attempt = provider.fetch_data(query.clone());
+            let result = match self.attempt_timeout {
+                Some(attempt_timeout) => tokio::time::timeout(attempt_timeout, attempt)
+                    .await
+                    .unwrap_or(Err(CityDataError::Timeout)),
+                None => attempt.await,
+            };
+
+            match result {
+                Ok(data) => {
+                    tracing::debug!("{name} answered request for {:?}", query.city);
+                    self.stats.record(name);
+                    if let Some(answered) = self.metrics.get(name) {
+                        answered.inc();
+                    }
+                    return Ok(data);
+                }
+                Err(error) if !worth_falling_back(&error) => {
+                    tracing::warn!("{name} can't answer request for {:?}: {error}", query.city);
+                    return Err(error);
+                }
+                Err(error) => {
+                    tracing::warn!(
+                        "{name} failed to answer request for {:?}: {error}, falling back",
+                        query.city
+                    );
+                    first_error.get_or_insert(error);
+                }
+            }
+        }
+
+        Err(first_error.unwrap_or(CityDataError::SourceUnavailable))
+    }
+
+    /// The chain is healthy as long as one of its providers is, as that's enough to answer requests. If none
+    /// of the providers have a health check, neither does the chain
+    async fn health_check(&self) -> Option<CityDataResult<()>> {
+        let mut first_error = None;
+
+        for (name, provider) in &self.providers {
+            match provider.health_check().await {
+                Some(Ok(())) => return Some(Ok(())),
+                Some(Err(error)) => {
+                    tracing::debug!("{name} failed its health check: {error}");
+                    first_error.get_or_insert(error);
+                }
+                None => {}
+            }
+        }
+
+        first_error.map(Err)
+    }
+
+    /// Any provider could be the one that answers, so queries can only share a key if they'd share it with
+    /// every provider. They usually agree, in which case that's the key
+    fn request_key(&self, query: &CityQuery) -> String {
+        let mut keys = self
+            .providers
+            .iter()
+            .map(|(_, provider)| provider.request_key(query))
+            .collect::<Vec<_>>();
+        keys.dedup();
+        keys.join(" | ")
+    }
+}
+
+/// Whether another provider might answer a request that one failed with `error`, i.e. the provider was down
+/// or struggling (including its circuit breaker being open), or didn't know the place. Anything else, like
+/// an invalid query, would fail the same way whichever provider was asked
+fn worth_falling_back(error: &CityDataError) -> bool {
+    error.is_transient()
+        || matches!(
+            error,
+            CityDataError::NotFound(_)
+                | CityDataError::NotGeocoded(_)
+                | CityDataError::SourceUnavailable
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use crate::{
+        metrics::fallback_answers_counter, CityData, CityDataError, CityDataResult, CityDataSource,
+        CityQuery, PlaceRecord,
+    };
+
+    use super::FallbackChain;
+
+    /// A data source which always fails with a copy of `0`, or succeeds if it's `None`
+    struct StubDataSource(Option<CityDataError>);
+
+    impl CityDataSource for StubDataSource {
+        async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+            match &self.0 {
+                Some(error) => Err(error.clone()),
+                None => Ok(CityData::Place(PlaceRecord {
+                    name: query.city.clone(),
+                    display_name: query.city,
+                    ..PlaceRecord::default()
+                })),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_in_order() {
+        let chain = FallbackChain::new(vec![
+            ("first", StubDataSource(Some(CityDataError::Timeout))),
+            ("second", StubDataSource(None)),
+            ("third", StubDataSource(None)),
+        ]);
+
+        for _ in 0..2 {
+            assert!(chain
+                .fetch_data(CityQuery::new("Fallbackton"))
+                .await
+                .is_ok());
+        }
+
+        let stats = chain.stats();
+        assert_eq!(stats.answered_by("first"), 0);
+        assert_eq!(stats.answered_by("second"), 2);
+        assert_eq!(stats.answered_by("third"), 0);
+        assert_eq!(stats.last_answered(), Some("second"));
+    }
+
+    #[tokio::test]
+    async fn test_all_fail() {
+        let chain = FallbackChain::new(vec![
+            ("first", StubDataSource(Some(CityDataError::Timeout))),
+            (
+                "second",
+                StubDataSource(Some(CityDataError::SourceUnavailable)),
+            ),
+        ]);
+
+        // the first provider's error is the one we hear about
+        let result = chain.fetch_data(CityQuery::new("Nowhere")).await;
+        assert!(matches!(result, Err(CityDataError::Timeout)));
+        assert_eq!(chain.stats().last_answered(), None);
+
+        let empty: FallbackChain<StubDataSource> = FallbackChain::new(Vec::new());
+        let result = empty.fetch_data(CityQuery::new("Nowhere")).await;
+        assert!(matches!(result, Err(CityDataError::SourceUnavailable)));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_query_not_retried() {
+        let chain = FallbackChain::new(vec![
+            (
+                "first",
+                StubDataSource(Some(CityDataError::InvalidQuery(String::from("bad")))),
+            ),
+            ("second", StubDataSource(None)),
+        ]);
+
+        // no other provider could make sense of the query either, so it isn't asked
+        let result = chain.fetch_data(CityQuery::new("Nowhere")).await;
+        assert!(matches!(result, Err(CityDataError::InvalidQuery(_))));
+        assert_eq!(chain.stats().answered_by("second"), 0);
+    }
+
+    /// A data source which either never answers at all, or answers straight away
+    enum HungDataSource {
+        Hung,
+        Prompt,
+    }
+
+    impl CityDataSource for HungDataSource {
+        async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+            match self {
+                HungDataSource::Hung => std::future::pending().await,
+                HungDataSource::Prompt => Ok(CityData::Place(PlaceRecord {
+                    name: query.city.clone(),
+                    display_name: query.city,
+                    ..PlaceRecord::default()
+                })),
+            }
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_attempt_timeout() {
+        let chain = FallbackChain::new(vec![
+            ("hung", HungDataSource::Hung),
+            ("prompt", HungDataSource::Prompt),
+        ])
+        .with_attempt_timeout(Duration::from_secs(2));
+        let start = Instant::now();
+
+        // without the timeout we'd wait on the first provider forever
+        chain
+            .fetch_data(CityQuery::new("Hungerford"))
+            .await
+            .expect("expected the second provider to answer");
+        assert_eq!(start.elapsed(), Duration::from_secs(2));
+        assert_eq!(chain.stats().answered_by("prompt"), 1);
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let answered = fallback_answers_counter("fallback_metrics_test", "second");
+        let before = answered.get();
+        let chain = FallbackChain::new(vec![
+            ("first", StubDataSource(Some(CityDataError::Timeout))),
+            ("second", StubDataSource(None)),
+        ])
+        .with_metrics("fallback_metrics_test");
+
+        chain
+            .fetch_data(CityQuery::new("Fallbackton"))
+            .await
+            .expect("expected the second provider to answer");
+        assert_eq!(answered.get() - before, 1);
+    }
+}
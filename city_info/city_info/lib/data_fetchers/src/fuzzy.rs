@@ -0,0 +1,124 @@
+use serde::Serialize;
+
+use crate::PlaceRecord;
+
+/// How closely a name matched what was asked for. Variants are ordered best first, so sorting by this
+/// puts the closest matches at the front
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchQuality {
+    /// the name is exactly what was asked for
+    Exact,
+    /// what was asked for is the start of the name, e.g. "San Jos" for "San Jose"
+    Prefix,
+    /// the name is this many single character edits away from what was asked for, e.g. "Chicgo" is one
+    /// away from "Chicago"
+    Typo(usize),
+}
+
+impl MatchQuality {
+    /// How well `name` matches `query`, or `None` if they're too different to be worth suggesting. Both are
+    /// expected to already be normalized (see `normalize_city_name`)
+    ///
+    /// The longer the query, the more typos we tolerate, as a couple of edits can turn one short name in
+    /// to a completely different one (e.g. "Lima" and "Linz")
+    pub fn between(query: &str, name: &str) -> Option<Self> {
+        if name == query {
+            return Some(Self::Exact);
+        }
+        if name.starts_with(query) {
+            return Some(Self::Prefix);
+        }
+
+        let max_typos = match query.chars().count() {
+            0..=3 => 0,
+            4..=7 => 1,
+            _ => 2,
+        };
+        // the edit distance is at least the difference in length, so skip the work if that's already too far
+        if query.chars().count().abs_diff(name.chars().count()) > max_typos {
+            return None;
+        }
+
+        let typos = edit_distance(query, name);
+        (typos <= max_typos).then_some(Self::Typo(typos))
+    }
+}
+
+/// The Levenshtein distance between `a` and `b`: the fewest single character insertions, deletions or
+/// substitutions that turn one in to the other. See <https://en.wikipedia.org/wiki/Levenshtein_distance>
+///
+/// Note: this only keeps the previous row of the usual dynamic programming table around, so it needs
+/// O(len(b)) memory rather than O(len(a) * len(b))
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    // the distance from the first i characters of `a` (starting at none) to each prefix of `b`
+    let mut previous_row = (0..=b.len()).collect::<Vec<_>>();
+
+    for (i, a_char) in a.chars().enumerate() {
+        let mut row = Vec::with_capacity(b.len() + 1);
+        row.push(i + 1);
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous_row[j] + usize::from(a_char != *b_char);
+            let deletion = previous_row[j + 1] + 1;
+            let insertion = row[j] + 1;
+            row.push(substitution.min(deletion).min(insertion));
+        }
+        previous_row = row;
+    }
+
+    previous_row[b.len()]
+}
+
+/// A place that may be what someone meant, along with how closely its name matched
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CitySuggestion {
+    #[serde(flatten)]
+    pub place: PlaceRecord,
+    #[serde(rename = "match")]
+    pub quality: MatchQuality,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{edit_distance, MatchQuality};
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("chicago", "chicago"), 0);
+        assert_eq!(edit_distance("chicgo", "chicago"), 1);
+        assert_eq!(edit_distance("chicago", "chicgo"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "rome"), 4);
+        // characters, not bytes
+        assert_eq!(edit_distance("tromso", "tromsø"), 1);
+    }
+
+    #[test]
+    fn test_match_quality() {
+        assert_eq!(
+            MatchQuality::between("chicago", "chicago"),
+            Some(MatchQuality::Exact)
+        );
+        assert_eq!(
+            MatchQuality::between("san jos", "san jose"),
+            Some(MatchQuality::Prefix)
+        );
+        assert_eq!(
+            MatchQuality::between("chicgo", "chicago"),
+            Some(MatchQuality::Typo(1))
+        );
+        assert_eq!(
+            MatchQuality::between("johanesburg", "johannesburg"),
+            Some(MatchQuality::Typo(1))
+        );
+        // short names need to be spelled right
+        assert_eq!(MatchQuality::between("lima", "linz"), None);
+        assert_eq!(MatchQuality::between("rom", "tom"), None);
+        assert_eq!(MatchQuality::between("chcgo", "chicago"), None);
+
+        assert!(MatchQuality::Exact < MatchQuality::Prefix);
+        assert!(MatchQuality::Prefix < MatchQuality::Typo(1));
+        assert!(MatchQuality::Typo(1) < MatchQuality::Typo(2));
+    }
+}
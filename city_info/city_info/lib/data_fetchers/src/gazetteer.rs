@@ -0,0 +1,528 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    fuzzy::{CitySuggestion, MatchQuality},
+    normalize_city_name, spawn_data_source_task, CityData, CityDataError, CityDataResult,
+    CityDataSource, CityDataSourceHandle, CityQuery, PlaceRecord, SpawnResult,
+    DEFAULT_MAX_IN_FLIGHT,
+};
+
+/// A small extract of GeoNames' `cities15000.txt`, enough to geocode major cities (and a few ambiguous
+/// names) without any setup. GeoNames data is licensed under CC BY 4.0, see <https://www.geonames.org>
+const EMBEDDED_CITIES: &str = include_str!("../data/cities_sample.txt");
+/// The matching extract of GeoNames' `admin1CodesASCII.txt`, which names each country's states/regions
+const EMBEDDED_ADMIN1_CODES: &str = include_str!("../data/admin1_codes_sample.txt");
+
+/// The most other matches we list alongside the best one, in line with what we ask nominatim for
+const MAX_ALTERNATIVES: usize = 9;
+
+/// Where to load a `Gazetteer` from
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum GazetteerSource {
+    /// the small sample of major cities bundled with this crate
+    Embedded,
+    /// GeoNames dump files on disk, see <https://download.geonames.org/export/dump/>. `cities` is a
+    /// `citiesN.txt` style file (e.g. `cities15000.txt`), and `admin1_codes` an `admin1CodesASCII.txt`
+    /// used to name each city's state. Without it, states are left blank
+    Files {
+        cities: PathBuf,
+        admin1_codes: Option<PathBuf>,
+    },
+}
+
+#[derive(Debug, Error)]
+pub enum GazetteerError {
+    #[error("Failed to read gazetteer file {path:?}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Malformed gazetteer line {line}: {message}")]
+    Malformed { line: usize, message: String },
+}
+
+fn read_file(path: &Path) -> Result<String, GazetteerError> {
+    std::fs::read_to_string(path).map_err(|source| GazetteerError::Io {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+/// An in-memory index of cities, built from a GeoNames dump so that cities can be geocoded (and described)
+/// without any network access
+///
+/// Note: every name a city goes by (including alternate names, e.g. "Bombay" for Mumbai) is indexed, so
+/// lookups are a single hash map access. Names are compared after `normalize_city_name`
+#[derive(Debug, Default)]
+pub struct Gazetteer {
+    places: Vec<PlaceRecord>,
+    // normalized name -> indexes in to `places`
+    by_name: HashMap<String, Vec<usize>>,
+}
+
+impl Gazetteer {
+    /// Load and index the cities from `source`
+    ///
+    /// # Errors
+    /// If a file can't be read, or isn't in the GeoNames format
+    pub fn load(source: &GazetteerSource) -> Result<Self, GazetteerError> {
+        match source {
+            GazetteerSource::Embedded => Ok(Self::embedded()),
+            GazetteerSource::Files {
+                cities,
+                admin1_codes,
+            } => {
+                let admin1_codes = match admin1_codes {
+                    Some(path) => read_file(path)?,
+                    None => String::new(),
+                };
+                Self::parse(&read_file(cities)?, &admin1_codes)
+            }
+        }
+    }
+
+    /// The small sample of major cities bundled with this crate, see `GazetteerSource::Embedded`
+    pub fn embedded() -> Self {
+        Self::parse(EMBEDDED_CITIES, EMBEDDED_ADMIN1_CODES)
+            // the embedded files are covered by our tests, so can't fail to parse at runtime
+            .expect("Failed to parse the embedded gazetteer!")
+    }
+
+    /// Index the contents of a GeoNames `cities` file, naming states from `admin1_codes`
+    ///
+    /// # Errors
+    /// If either isn't in the GeoNames format
+    pub fn parse(cities: &str, admin1_codes: &str) -> Result<Self, GazetteerError> {
+        let admin1_names = parse_admin1_codes(admin1_codes)?;
+        let mut gazetteer = Self::default();
+
+        for (index, line) in data_lines(cities) {
+            let (place, names) = parse_city(index + 1, line, &admin1_names)?;
+
+            let place_index = gazetteer.places.len();
+            for name in names {
+                let entries = gazetteer
+                    .by_name
+                    .entry(normalize_city_name(name))
+                    .or_default();
+                // a place's name and ascii name are often the same, only index it once per name
+                if entries.last() != Some(&place_index) {
+                    entries.push(place_index);
+                }
+            }
+            gazetteer.places.push(place);
+        }
+
+        tracing::info!("Indexed {} places in the gazetteer", gazetteer.places.len());
+        Ok(gazetteer)
+    }
+
+    /// How many places are indexed
+    pub fn len(&self) -> usize {
+        self.places.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.places.is_empty()
+    }
+
+    /// Every place going by `query.city`, narrowed to its country and state (if any) and ordered from most
+    /// to least populous, as the biggest place with a name is usually the one people mean
+    pub fn lookup(&self, query: &CityQuery) -> Vec<PlaceRecord> {
+        let mut matches = self
+            .by_name
+            .get(&normalize_city_name(&query.city))
+            .into_iter()
+            .flatten()
+            .map(|&index| &self.places[index])
+            .filter(|place| place.matches(query))
+            .cloned()
+            .collect::<Vec<_>>();
+        matches.sort_by_key(|place| std::cmp::Reverse(place.population));
+
+        matches
+    }
+
+    /// Up to `limit` places that `query.city` may have been meant as, for autocompleting a partly typed
+    /// name or correcting a misspelled one. Places are narrowed to the query's country and state (if any),
+    /// and ranked by how well one of their names matched (see `MatchQuality`) then by population
+    ///
+    /// Note: this compares against every name in the index, rather than a single hash map access like
+    /// `lookup`, so is best kept to autocomplete and the "did you mean" path after a lookup failed. With a
+    /// large index that can take a while, so async callers should run it with `spawn_blocking`
+    pub fn suggest(&self, query: &CityQuery, limit: usize) -> Vec<CitySuggestion> {
+        let city = normalize_city_name(&query.city);
+        if city.is_empty() {
+            return Vec::new();
+        }
+
+        // the best match for each place, by index, as a place can match under several of its names
+        let mut best_matches: HashMap<usize, MatchQuality> = HashMap::new();
+        for (name, indexes) in &self.by_name {
+            let Some(quality) = MatchQuality::between(&city, name) else {
+                continue;
+            };
+            for &index in indexes {
+                best_matches
+                    .entry(index)
+                    .and_modify(|best| *best = quality.min(*best))
+                    .or_insert(quality);
+            }
+        }
+
+        let mut suggestions = best_matches
+            .into_iter()
+            .map(|(index, quality)| (&self.places[index], quality))
+            .filter(|(place, _)| place.matches(query))
+            .map(|(place, quality)| CitySuggestion {
+                place: place.clone(),
+                quality,
+            })
+            .collect::<Vec<_>>();
+        // places with the same population are ordered by name, so results don't change between calls
+        suggestions.sort_by(|a, b| {
+            a.quality
+                .cmp(&b.quality)
+                .then(b.place.population.cmp(&a.place.population))
+                .then_with(|| a.place.display_name.cmp(&b.place.display_name))
+        });
+        suggestions.truncate(limit);
+
+        suggestions
+    }
+}
+
+/// The non-blank, non-comment lines of a GeoNames file, along with their (0 based) line index
+fn data_lines(contents: &str) -> impl Iterator<Item = (usize, &str)> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.starts_with('#') && !line.trim().is_empty())
+}
+
+/// Parse an `admin1CodesASCII.txt` file, mapping e.g. "US.CA" to "California"
+fn parse_admin1_codes(contents: &str) -> Result<HashMap<String, String>, GazetteerError> {
+    data_lines(contents)
+        .map(|(index, line)| {
+            let mut columns = line.split('\t');
+            match (columns.next(), columns.next()) {
+                (Some(code), Some(name)) => Ok((code.to_owned(), name.to_owned())),
+                _ => Err(GazetteerError::Malformed {
+                    line: index + 1,
+                    message: String::from("expected an admin1 code and name"),
+                }),
+            }
+        })
+        .collect()
+}
+
+/// Parse line number `line` of a GeoNames cities file (see "geoname" table at
+/// <https://download.geonames.org/export/dump/readme.txt>), returning the place along with every name it
+/// should be indexed under
+fn parse_city<'a>(
+    line: usize,
+    contents: &'a str,
+    admin1_names: &HashMap<String, String>,
+) -> Result<(PlaceRecord, Vec<&'a str>), GazetteerError> {
+    let columns = contents.split('\t').collect::<Vec<_>>();
+    let malformed = |message: String| GazetteerError::Malformed { line, message };
+
+    if columns.len() < 18 {
+        return Err(malformed(format!(
+            "expected at least 18 tab separated columns, found {}",
+            columns.len()
+        )));
+    }
+    let parse_number = |column: usize, field: &str| {
+        columns[column].parse::<f64>().map_err(|_| {
+            malformed(format!(
+                "expected a number for {field}, found {:?}",
+                columns[column]
+            ))
+        })
+    };
+
+    let name = columns[1];
+    let alternate_names = columns[3]
+        .split(',')
+        .filter(|alternate| !alternate.is_empty() && *alternate != name)
+        .collect::<Vec<_>>();
+    let country_code = columns[8].to_uppercase();
+    let state = admin1_names
+        .get(&format!("{country_code}.{}", columns[10]))
+        .cloned();
+    // where there's no surveyed elevation, fall back to the digital elevation model (which uses -9999 for
+    // "no data")
+    let elevation = [columns[15], columns[16]]
+        .into_iter()
+        .find_map(|elevation| elevation.parse::<i32>().ok())
+        .filter(|&elevation| elevation != -9999);
+
+    let display_name = [Some(name), state.as_deref(), Some(&country_code)]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    let place = PlaceRecord {
+        name: name.to_owned(),
+        display_name,
+        latitude: parse_number(4, "latitude")?,
+        longitude: parse_number(5, "longitude")?,
+        country: None,
+        country_code: Some(country_code.to_lowercase()),
+        state,
+        place_type: String::from("city"),
+        importance: 0.0,
+        population: columns[14].parse().ok(),
+        elevation,
+        timezone: Some(columns[17].to_owned()).filter(|timezone| !timezone.is_empty()),
+        alternate_names: alternate_names
+            .iter()
+            .map(|&alternate| alternate.to_owned())
+            .collect(),
+        alternatives: Vec::new(),
+    };
+
+    let mut names = vec![name, columns[2]];
+    names.extend(alternate_names);
+
+    Ok((place, names))
+}
+
+/// A `CityDataSource` looking cities up in a `Gazetteer`. It answers in the same shape as the nominatim
+/// backed `CityStatsFetcher` (the best match, with any other matches as alternatives), so it can stand in
+/// for it as the dispatcher's geocoder
+pub struct GazetteerDataSource {
+    gazetteer: Arc<Gazetteer>,
+}
+
+impl GazetteerDataSource {
+    pub fn new(gazetteer: Arc<Gazetteer>) -> Self {
+        Self { gazetteer }
+    }
+}
+
+impl CityDataSource for GazetteerDataSource {
+    async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+        let mut matches = self.gazetteer.lookup(&query).into_iter();
+        let mut best_match = matches.next().ok_or(CityDataError::NotFound(query.city))?;
+        best_match.alternatives = matches.take(MAX_ALTERNATIVES).collect();
+
+        Ok(CityData::Place(best_match))
+    }
+}
+
+/// Spawn a task looking cities up in `gazetteer`, returning a handle to it. Lookups are in memory, so
+/// there's nothing to cache or throttle
+pub fn spawn_gazetteer_task(
+    gazetteer: Arc<Gazetteer>,
+    cancellation_token: CancellationToken,
+) -> SpawnResult<CityDataSourceHandle> {
+    let _span = tracing::info_span!("Fetcher", source = "gazetteer").entered();
+
+    spawn_data_source_task(
+        GazetteerDataSource::new(gazetteer),
+        DEFAULT_MAX_IN_FLIGHT,
+        cancellation_token,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::Arc};
+
+    use crate::{fuzzy::MatchQuality, CityData, CityDataError, CityDataSource, CityQuery};
+
+    use super::{Gazetteer, GazetteerDataSource, GazetteerError, GazetteerSource};
+
+    fn embedded() -> Gazetteer {
+        Gazetteer::load(&GazetteerSource::Embedded)
+            .expect("expected the embedded gazetteer to load")
+    }
+
+    /// The display names and match quality of up to 5 suggestions for `query`, best first
+    fn suggest(gazetteer: &Gazetteer, query: CityQuery) -> Vec<(String, MatchQuality)> {
+        gazetteer
+            .suggest(&query, 5)
+            .into_iter()
+            .map(|suggestion| (suggestion.place.display_name, suggestion.quality))
+            .collect()
+    }
+
+    /// The display names of everything matching `query`, best match first
+    fn lookup(gazetteer: &Gazetteer, query: CityQuery) -> Vec<String> {
+        gazetteer
+            .lookup(&query)
+            .into_iter()
+            .map(|place| place.display_name)
+            .collect()
+    }
+
+    #[test]
+    fn test_embedded() {
+        let gazetteer = embedded();
+        assert_eq!(gazetteer.len(), 36);
+
+        let places = gazetteer.lookup(&CityQuery::new("san jose"));
+        assert_eq!(places.len(), 2);
+        let san_jose = &places[0];
+        assert_eq!(san_jose.display_name, "San Jose, California, US");
+        assert_eq!(san_jose.country_code.as_deref(), Some("us"));
+        assert_eq!(san_jose.population, Some(1_026_908));
+        assert_eq!(san_jose.elevation, Some(26));
+        assert_eq!(san_jose.timezone.as_deref(), Some("America/Los_Angeles"));
+        assert!(san_jose.alternate_names.contains(&String::from("San José")));
+
+        // no surveyed elevation, so the elevation model's is used
+        assert_eq!(places[1].display_name, "San José, San José, CR");
+        assert_eq!(places[1].elevation, Some(1161));
+    }
+
+    #[test]
+    fn test_lookup() {
+        let gazetteer = embedded();
+
+        // most populous first
+        assert_eq!(
+            lookup(&gazetteer, CityQuery::new("Springfield")),
+            vec![
+                "Springfield, Missouri, US",
+                "Springfield, Massachusetts, US",
+                "Springfield, Illinois, US"
+            ]
+        );
+        assert_eq!(
+            lookup(
+                &gazetteer,
+                CityQuery::new("Springfield").with_state("illinois")
+            ),
+            vec!["Springfield, Illinois, US"]
+        );
+        assert_eq!(
+            lookup(&gazetteer, CityQuery::new("London").with_country_code("CA")),
+            vec!["London, Ontario, CA"]
+        );
+
+        // by alternate and ascii names
+        assert_eq!(
+            lookup(&gazetteer, CityQuery::new("Bombay")),
+            vec!["Mumbai, Maharashtra, IN"]
+        );
+        assert_eq!(
+            lookup(&gazetteer, CityQuery::new("sao  paulo")),
+            vec!["São Paulo, São Paulo, BR"]
+        );
+
+        assert!(lookup(&gazetteer, CityQuery::new("Atlantis")).is_empty());
+    }
+
+    #[test]
+    fn test_suggest() {
+        let gazetteer = embedded();
+
+        assert_eq!(
+            suggest(&gazetteer, CityQuery::new("Chicgo")),
+            vec![(String::from("Chicago, Illinois, US"), MatchQuality::Typo(1))]
+        );
+        // exact and prefix matches come before typos, then the most populous first
+        assert_eq!(
+            suggest(&gazetteer, CityQuery::new("San Jos")),
+            vec![
+                (
+                    String::from("San Jose, California, US"),
+                    MatchQuality::Prefix
+                ),
+                (String::from("San José, San José, CR"), MatchQuality::Prefix),
+            ]
+        );
+        assert_eq!(
+            suggest(&gazetteer, CityQuery::new("Sa")),
+            vec![
+                (
+                    String::from("São Paulo, São Paulo, BR"),
+                    MatchQuality::Prefix
+                ),
+                (
+                    String::from("San Jose, California, US"),
+                    MatchQuality::Prefix
+                ),
+                (
+                    String::from("San Francisco, California, US"),
+                    MatchQuality::Prefix
+                ),
+                (String::from("San José, San José, CR"), MatchQuality::Prefix),
+            ]
+        );
+        // narrowed like a lookup
+        assert_eq!(
+            suggest(&gazetteer, CityQuery::new("Sa").with_country_code("cr")),
+            vec![(String::from("San José, San José, CR"), MatchQuality::Prefix)]
+        );
+        // a place matching under several names is only suggested once, as its best match
+        assert_eq!(
+            suggest(&gazetteer, CityQuery::new("Tromso")),
+            vec![(
+                String::from("Tromsø, Troms og Finnmark, NO"),
+                MatchQuality::Exact
+            )]
+        );
+
+        assert_eq!(
+            gazetteer.suggest(&CityQuery::new("Springfield"), 1).len(),
+            1
+        );
+        assert!(suggest(&gazetteer, CityQuery::new(" ")).is_empty());
+        assert!(suggest(&gazetteer, CityQuery::new("Xyzzy")).is_empty());
+    }
+
+    #[test]
+    fn test_malformed() {
+        let result = Gazetteer::parse("# a comment\n\n1\tOnly a name\n", "");
+        assert!(matches!(
+            result,
+            Err(GazetteerError::Malformed { line: 3, .. })
+        ));
+
+        let mut columns = vec![""; 19];
+        columns[1] = "Nowhere";
+        columns[4] = "north";
+        let result = Gazetteer::parse(&columns.join("\t"), "");
+        assert!(matches!(
+            result,
+            Err(GazetteerError::Malformed { line: 1, message }) if message.contains("latitude")
+        ));
+
+        let result = Gazetteer::load(&GazetteerSource::Files {
+            cities: PathBuf::from("/nonexistent/cities15000.txt"),
+            admin1_codes: None,
+        });
+        assert!(matches!(result, Err(GazetteerError::Io { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_data_source() {
+        let source = GazetteerDataSource::new(Arc::new(embedded()));
+
+        let result = source.fetch_data(CityQuery::new("Paris")).await;
+        let Ok(CityData::Place(paris)) = result else {
+            panic!("Expected a place, got {result:?}");
+        };
+        assert_eq!(paris.display_name, "Paris, Île-de-France, FR");
+        assert_eq!(paris.alternatives.len(), 1);
+        assert_eq!(paris.alternatives[0].display_name, "Paris, Texas, US");
+        assert!(paris.to_string().starts_with(
+            "Stats for Paris, Île-de-France, FR: population 2138551, elevation 42m, timezone Europe/Paris"
+        ));
+
+        let result = source.fetch_data(CityQuery::new("Atlantis")).await;
+        assert!(matches!(result, Err(CityDataError::NotFound(city)) if city == "Atlantis"));
+    }
+}
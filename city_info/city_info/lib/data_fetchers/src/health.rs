@@ -0,0 +1,150 @@
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Serializer};
+
+use crate::CityDataResult;
+
+/// How often a `CityDataSourceTask` checks the health of its source (see `CityDataSource::health_check`)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HealthCheckConfig {
+    /// how long to wait between checks. The first check runs as soon as the task starts
+    pub interval: Duration,
+    /// how long a check can take before it's counted as failed
+    pub timeout: Duration,
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(30),
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Where a source's health checks currently stand
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    /// the source has no health check (or checks are turned off), so all we can do is assume it's fine
+    Unmonitored,
+    /// the first check hasn't finished yet
+    Pending,
+    /// the most recent check passed
+    Healthy,
+    /// the most recent check failed
+    Unhealthy,
+}
+
+impl HealthStatus {
+    /// Whether a source in this state should be trusted with requests
+    pub fn is_ready(self) -> bool {
+        matches!(self, HealthStatus::Unmonitored | HealthStatus::Healthy)
+    }
+}
+
+/// What a `CityDataSourceTask` knows about its source's health, as of its last check
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SourceHealth {
+    pub status: HealthStatus,
+    /// when a check last passed
+    pub last_success: Option<DateTime<Utc>>,
+    /// when a check last failed, and why
+    pub last_error: Option<HealthCheckFailure>,
+    /// how long the most recent check took
+    #[serde(rename = "latency_ms", serialize_with = "serialize_millis")]
+    pub latency: Option<Duration>,
+    /// how many checks in a row have failed
+    pub consecutive_failures: u32,
+}
+
+/// A failed health check
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HealthCheckFailure {
+    pub at: DateTime<Utc>,
+    pub error: String,
+}
+
+impl SourceHealth {
+    /// A source that hasn't been checked yet, in `status`
+    pub fn new(status: HealthStatus) -> Self {
+        Self {
+            status,
+            last_success: None,
+            last_error: None,
+            latency: None,
+            consecutive_failures: 0,
+        }
+    }
+
+    /// Update our record with the `result` of a check that took `latency`
+    pub fn record(&mut self, result: &CityDataResult<()>, latency: Duration) {
+        let now = DateTime::from(SystemTime::now());
+        self.latency = Some(latency);
+
+        match result {
+            Ok(()) => {
+                self.status = HealthStatus::Healthy;
+                self.last_success = Some(now);
+                self.consecutive_failures = 0;
+            }
+            Err(error) => {
+                self.status = HealthStatus::Unhealthy;
+                self.last_error = Some(HealthCheckFailure {
+                    at: now,
+                    error: error.to_string(),
+                });
+                self.consecutive_failures += 1;
+            }
+        }
+    }
+}
+
+fn serialize_millis<S>(duration: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    duration
+        .map(|duration| duration.as_secs_f64() * 1000.0)
+        .serialize(serializer)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::CityDataError;
+
+    use super::{HealthStatus, SourceHealth};
+
+    #[test]
+    fn test_record() {
+        let mut health = SourceHealth::new(HealthStatus::Pending);
+        assert!(!health.status.is_ready());
+
+        health.record(&Err(CityDataError::Timeout), Duration::from_millis(250));
+        health.record(&Err(CityDataError::Timeout), Duration::from_millis(250));
+        assert_eq!(health.status, HealthStatus::Unhealthy);
+        assert_eq!(health.consecutive_failures, 2);
+        assert!(health.last_success.is_none());
+
+        // a pass resets the failure count, but we remember the last error
+        health.record(&Ok(()), Duration::from_millis(20));
+        assert_eq!(health.status, HealthStatus::Healthy);
+        assert!(health.status.is_ready());
+        assert_eq!(health.consecutive_failures, 0);
+        assert!(health.last_success.is_some());
+        assert_eq!(
+            health
+                .last_error
+                .as_ref()
+                .map(|failure| failure.error.as_str()),
+            Some("Upstream request timed out")
+        );
+
+        let json = serde_json::to_value(&health).expect("expected health to serialize");
+        assert_eq!(json["status"], "healthy");
+        assert_eq!(json["latency_ms"], 20.0);
+    }
+}
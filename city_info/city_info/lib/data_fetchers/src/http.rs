@@ -0,0 +1,195 @@
+use std::time::Duration;
+
+use reqwest::{header::RETRY_AFTER, StatusCode};
+use serde::de::DeserializeOwned;
+
+use crate::{CityDataError, CityDataResult, CityQuery, Coordinates};
+
+/// The most of a response body we'll include in an error, long enough to show what went wrong without
+/// flooding our logs with an entire html error page
+const MAX_SNIPPET_CHARS: usize = 200;
+
+/// Truncate `body` to something reasonable to put in an error message
+pub(crate) fn snippet(body: &str) -> String {
+    match body.char_indices().nth(MAX_SNIPPET_CHARS) {
+        // note: we truncate on a char boundary, slicing a `str` in the middle of a multi-byte char panics
+        Some((end, _)) => format!("{}...", &body[..end]),
+        None => body.to_owned(),
+    }
+}
+
+/// Percent-encode `value` (e.g. a city name the user gave us) so it can go anywhere in a url as a single
+/// value, spaces becoming '+'. Without this a city like "Paris&countrycodes=us" would add parameters of its
+/// own, and a '#' would cut the rest of the url off
+pub(crate) fn url_encode(value: &str) -> String {
+    form_urlencoded::byte_serialize(value.as_bytes()).collect()
+}
+
+/// Read a `Retry-After` header. The header can also be an http date, but neither of the APIs we use send
+/// one of those, so we only handle a number of seconds
+fn retry_after(response: &reqwest::Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// GET `url` (just the once, see `retry::Retrying` for retries), sorting any failure in to a `CityDataError`
+pub(crate) async fn get(
+    http_client: &reqwest::Client,
+    url: &str,
+) -> CityDataResult<reqwest::Response> {
+    let response = http_client.get(url).send().await?;
+
+    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        return Err(CityDataError::RateLimited {
+            retry_after: retry_after(&response),
+        });
+    }
+
+    Ok(response.error_for_status()?)
+}
+
+/// Deserialize a JSON response body. Rather than reqwest's `Response::json()` we go through
+/// `serde_path_to_error` (<https://docs.rs/serde_path_to_error>), which tells us *where* in the document
+/// deserialization failed, so when an upstream changes its response format the error says what changed
+pub(crate) async fn parse_json<T>(response: reqwest::Response) -> CityDataResult<T>
+where
+    T: DeserializeOwned,
+{
+    let body = response.text().await?;
+    let deserializer = &mut serde_json::Deserializer::from_str(&body);
+
+    serde_path_to_error::deserialize(deserializer).map_err(|e| {
+        let error = CityDataError::MalformedResponse {
+            path: e.path().to_string(),
+            message: e.inner().to_string(),
+            snippet: snippet(&body),
+        };
+        tracing::error!("Got error: {error}");
+        error
+    })
+}
+
+/// What health checks ask upstreams about, somewhere every upstream we use is sure to know. Each fetcher
+/// turns this in to a request (see e.g. `city_stats_api::probe_url`) for `probe` to send
+pub(crate) fn probe_query() -> CityQuery {
+    CityQuery::new("London").with_coordinates(PROBE_COORDINATES)
+}
+
+/// Where London is, see `probe_query`
+pub(crate) const PROBE_COORDINATES: Coordinates = Coordinates {
+    latitude: 51.5072,
+    longitude: -0.1276,
+};
+
+/// Check that an upstream is up by sending it a request (`url`) it should always be able to answer. Anything
+/// but a 2xx fails the check: a 5xx or a 429 means the upstream is struggling, and a 401 or 403 usually
+/// means it has blocked us, which is just as much of an outage as far as we're concerned
+pub(crate) async fn probe(http_client: &reqwest::Client, url: &str) -> CityDataResult<()> {
+    let status = http_client.get(url).send().await?.status();
+
+    if !status.is_success() {
+        return Err(CityDataError::UpstreamStatus {
+            status: status.as_u16(),
+        });
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use crate::CityDataError;
+
+    use super::snippet;
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)] /* we only care whether this deserializes */
+    struct Nested {
+        outer: Vec<Inner>,
+    }
+
+    #[derive(Debug, Deserialize)]
+    #[allow(dead_code)] /* we only care whether this deserializes */
+    struct Inner {
+        value: u32,
+    }
+
+    #[test]
+    fn test_snippet() {
+        assert_eq!(snippet("short"), "short");
+
+        let long = "é".repeat(300);
+        let truncated = snippet(&long);
+        assert_eq!(truncated.chars().count(), 203);
+        assert!(truncated.ends_with("..."));
+    }
+
+    #[tokio::test]
+    async fn test_probe() {
+        use axum::{http::StatusCode, routing::get, Router};
+
+        use crate::test_utils::spawn_fixture_server;
+
+        let router = Router::new()
+            .route("/up", get(|| async { StatusCode::OK }))
+            .route("/down", get(|| async { StatusCode::BAD_GATEWAY }))
+            .route("/busy", get(|| async { StatusCode::TOO_MANY_REQUESTS }))
+            .route("/blocked", get(|| async { StatusCode::FORBIDDEN }));
+        let base_url = spawn_fixture_server(router).await;
+        let client = reqwest::Client::new();
+
+        assert!(super::probe(&client, &format!("{base_url}/up"))
+            .await
+            .is_ok());
+        for (path, status) in [
+            ("down", 502),
+            ("busy", 429),
+            ("blocked", 403),
+            ("missing", 404),
+        ] {
+            let result = super::probe(&client, &format!("{base_url}/{path}")).await;
+            assert!(
+                matches!(result, Err(CityDataError::UpstreamStatus { status: actual }) if actual == status),
+                "expected /{path} to fail with a {status}, got {result:?}"
+            );
+        }
+
+        // nothing listening
+        let result = super::probe(&client, "http://127.0.0.1:1/").await;
+        assert!(matches!(result, Err(CityDataError::ConnectionFailed(_))));
+    }
+
+    #[tokio::test]
+    async fn test_malformed_response_path() {
+        use axum::{routing::get, Router};
+
+        use crate::test_utils::spawn_fixture_server;
+
+        let router = Router::new().route(
+            "/",
+            get(|| async { r#"{"outer": [{"value": 1}, {"value": "two"}]}"# }),
+        );
+        let base_url = spawn_fixture_server(router).await;
+        let response = reqwest::get(base_url)
+            .await
+            .expect("Expected the fixture server to respond");
+
+        let error = super::parse_json::<Nested>(response)
+            .await
+            .expect_err("Expected deserialization to fail");
+        let CityDataError::MalformedResponse { path, snippet, .. } = error else {
+            panic!("Expected a malformed response error, got {error:?}");
+        };
+        assert_eq!(path, "outer[1].value");
+        assert!(snippet.contains("two"));
+    }
+}
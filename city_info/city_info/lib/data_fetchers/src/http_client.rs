@@ -0,0 +1,213 @@
+use std::{path::PathBuf, time::Duration};
+
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use thiserror::Error;
+
+/// The user agent we identify ourselves with unless told otherwise. Public APIs like nominatim ask that
+/// this includes a way to contact whoever is running the service, see `HttpClientConfig::user_agent`
+pub const DEFAULT_USER_AGENT: &str = concat!("city_info/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Debug, Error)]
+pub enum HttpClientError {
+    #[error("Invalid default header {name:?}: {message}")]
+    InvalidHeader { name: String, message: String },
+    #[error("Invalid proxy url {url:?}: {source}")]
+    InvalidProxy { url: String, source: reqwest::Error },
+    #[error("Failed to read root certificate {path:?}: {source}")]
+    CertificateUnreadable {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Invalid root certificate {path:?}, expected PEM: {source}")]
+    InvalidCertificate {
+        path: PathBuf,
+        source: reqwest::Error,
+    },
+    #[error("Failed to build http client: {0}")]
+    BuildFailed(reqwest::Error),
+}
+
+/// How to build the http client our fetchers send their requests with
+///
+/// Build one client with `HttpClientConfig::build` and hand it to every fetcher: `reqwest::Client` is
+/// reference counted internally, so clones are cheap and share one connection pool (and one set of TLS
+/// roots) rather than each fetcher setting up its own
+#[derive(Clone, Debug)]
+pub struct HttpClientConfig {
+    /// sent with every request. Nominatim's usage policy (<https://operations.osmfoundation.org/policies/nominatim/>)
+    /// asks for one that identifies the application along with contact details, e.g.
+    /// "city_info/0.1 (admin@example.com)"
+    pub user_agent: String,
+    /// how long to wait for a connection to be established
+    pub connect_timeout: Option<Duration>,
+    /// how long to wait between reads of a response (rather than for the whole response)
+    pub read_timeout: Option<Duration>,
+    /// how many idle connections to keep open to each host
+    pub pool_max_idle_per_host: usize,
+    /// how long an idle connection is kept open before it's closed
+    pub pool_idle_timeout: Option<Duration>,
+    /// send every request through this proxy, e.g. "http://proxy.internal:3128". When unset, the
+    /// `HTTPS_PROXY`/`HTTP_PROXY` environment variables are still respected
+    pub proxy: Option<String>,
+    /// PEM encoded certificates to trust on top of the system's roots, e.g. for a TLS intercepting proxy
+    pub root_certificates: Vec<PathBuf>,
+    /// headers to send with every request, as (name, value) pairs
+    pub default_headers: Vec<(String, String)>,
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        Self {
+            user_agent: String::from(DEFAULT_USER_AGENT),
+            connect_timeout: Some(Duration::from_secs(5)),
+            read_timeout: Some(Duration::from_secs(10)),
+            pool_max_idle_per_host: 8,
+            pool_idle_timeout: Some(Duration::from_secs(90)),
+            proxy: None,
+            root_certificates: Vec::new(),
+            default_headers: Vec::new(),
+        }
+    }
+}
+
+impl HttpClientConfig {
+    /// Build a client from this config
+    ///
+    /// # Errors
+    /// If a default header or the proxy url isn't valid, or a root certificate can't be read
+    pub fn build(&self) -> Result<reqwest::Client, HttpClientError> {
+        let mut builder = reqwest::Client::builder()
+            .user_agent(&self.user_agent)
+            .default_headers(self.header_map()?)
+            .pool_max_idle_per_host(self.pool_max_idle_per_host)
+            .pool_idle_timeout(self.pool_idle_timeout);
+
+        if let Some(connect_timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+        if let Some(read_timeout) = self.read_timeout {
+            builder = builder.read_timeout(read_timeout);
+        }
+        if let Some(url) = &self.proxy {
+            let proxy =
+                reqwest::Proxy::all(url).map_err(|source| HttpClientError::InvalidProxy {
+                    url: url.clone(),
+                    source,
+                })?;
+            builder = builder.proxy(proxy);
+        }
+        for path in &self.root_certificates {
+            let pem =
+                std::fs::read(path).map_err(|source| HttpClientError::CertificateUnreadable {
+                    path: path.clone(),
+                    source,
+                })?;
+            let certificate = reqwest::Certificate::from_pem(&pem).map_err(|source| {
+                HttpClientError::InvalidCertificate {
+                    path: path.clone(),
+                    source,
+                }
+            })?;
+            builder = builder.add_root_certificate(certificate);
+        }
+
+        builder.build().map_err(HttpClientError::BuildFailed)
+    }
+
+    fn header_map(&self) -> Result<HeaderMap, HttpClientError> {
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.default_headers {
+            let invalid = |message: String| HttpClientError::InvalidHeader {
+                name: name.clone(),
+                message,
+            };
+            let header_name =
+                HeaderName::from_bytes(name.as_bytes()).map_err(|e| invalid(e.to_string()))?;
+            let header_value = HeaderValue::from_str(value).map_err(|e| invalid(e.to_string()))?;
+            headers.append(header_name, header_value);
+        }
+
+        Ok(headers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use axum::{http::HeaderMap, routing::get, Router};
+
+    use crate::test_utils::spawn_fixture_server;
+
+    use super::{HttpClientConfig, HttpClientError, DEFAULT_USER_AGENT};
+
+    #[tokio::test]
+    async fn test_headers_sent() {
+        // echo back the headers we care about
+        let router = Router::new().route(
+            "/",
+            get(|headers: HeaderMap| async move {
+                let header = |name: &str| {
+                    headers
+                        .get(name)
+                        .and_then(|value| value.to_str().ok())
+                        .unwrap_or_default()
+                        .to_owned()
+                };
+                format!("{}|{}", header("user-agent"), header("x-contact"))
+            }),
+        );
+        let base_url = spawn_fixture_server(router).await;
+
+        let client = HttpClientConfig {
+            user_agent: String::from("city_info_test (test@example.com)"),
+            default_headers: vec![(String::from("X-Contact"), String::from("ops"))],
+            ..HttpClientConfig::default()
+        }
+        .build()
+        .expect("expected the client to build");
+
+        // clones share the same configuration
+        let response = client
+            .clone()
+            .get(format!("{base_url}/"))
+            .send()
+            .await
+            .expect("expected the request to succeed")
+            .text()
+            .await
+            .expect("expected a body");
+        assert_eq!(response, "city_info_test (test@example.com)|ops");
+
+        assert!(DEFAULT_USER_AGENT.starts_with("city_info/"));
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let result = HttpClientConfig {
+            default_headers: vec![(String::from("Bad Header"), String::from("value"))],
+            ..HttpClientConfig::default()
+        }
+        .build();
+        assert!(
+            matches!(result, Err(HttpClientError::InvalidHeader { name, .. }) if name == "Bad Header")
+        );
+
+        let result = HttpClientConfig {
+            proxy: Some(String::from("not a url")),
+            ..HttpClientConfig::default()
+        }
+        .build();
+        assert!(matches!(result, Err(HttpClientError::InvalidProxy { .. })));
+
+        let result = HttpClientConfig {
+            root_certificates: vec![PathBuf::from("/nonexistent/ca.pem")],
+            ..HttpClientConfig::default()
+        }
+        .build();
+        assert!(matches!(
+            result,
+            Err(HttpClientError::CertificateUnreadable { .. })
+        ));
+    }
+}
@@ -1,61 +1,515 @@
-use futures::{stream::FuturesUnordered, StreamExt};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fmt::Display,
+    future::Future,
+    pin::pin,
+    time::Duration,
+};
+
+use futures::{
+    future::{AbortHandle, AbortRegistration, Abortable, BoxFuture, Fuse, FusedFuture},
+    stream::FuturesUnordered,
+    FutureExt, StreamExt,
+};
+use serde::Serialize;
 use thiserror::Error;
-use tokio::sync::{mpsc, oneshot};
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    time::{Instant, Interval, MissedTickBehavior},
+};
 use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use cache::{CacheConfig, Cached};
+use circuit_breaker::{CircuitBreaker, CircuitBreakerConfig};
+use fallback::FallbackChain;
+use health::{HealthCheckConfig, HealthStatus, SourceHealth};
+use rate_limit::{RateLimitConfig, RateLimited};
+use retry::{RetryPolicy, Retrying};
+use sun::SunReport;
+use timezone::LocalTimeReport;
 
+pub mod cache;
+pub mod circuit_breaker;
 pub mod city_stats_fetcher;
+pub mod fallback;
+pub mod fuzzy;
+pub mod gazetteer;
+pub mod health;
+pub mod http_client;
+pub mod metrics;
+pub mod open_meteo_fetcher;
+pub mod query;
+pub mod rate_limit;
+pub mod registry;
+pub mod retry;
+pub mod sun;
+pub mod timezone;
+pub mod timezone_boundaries;
 pub mod weather_fetcher;
+pub mod wikipedia_fetcher;
 
 // internal modules containing simple implementations for a couple public APIs
 mod city_stats_api;
+mod open_meteo_api;
 mod weather_api;
+mod wikipedia_api;
+
+// internal helpers shared by the modules above
+mod http;
+
+#[cfg(test)]
+mod test_utils;
+
+// re-export the structured records our data sources produce so consumers don't need to know
+// which (private) api module they live in
+pub use city_stats_api::PlaceRecord;
+pub use query::{CityQuery, Coordinates, ForecastOptions, Units};
+pub use weather_api::{DailyForecast, HourlyForecast, WeatherReport};
+pub use wikipedia_api::WikipediaSummary;
 
 // We leverage thiserror (<https://docs.rs/thiserror/latest/thiserror/>), a handy macro
 // that effectively automates some of the pain out of custom error types, especially the
 // #[from] directive which reduces the amount of
 // `.map_err(|e: SomeOtherCrateError| MyErrorType(SomeOtherCrateError))` calls you need
 // to make
-#[derive(Debug, Error)]
+//
+// Note: we derive `Clone` here so a single result can be fanned out to several requesters (see
+// `CityDataSourceTask::run`), which is why `HandleSendError` doesn't hold on to the failed request
+#[derive(Clone, Debug, Error)]
 pub enum CityDataError {
-    #[error("Data fetch failed with error: {0}")]
-    FetchError(String),
+    #[error("No data found for city: {0}")]
+    NotFound(String),
+    #[error("No coordinates for {0}, this source can only look up geocoded cities")]
+    NotGeocoded(String),
+    #[error("Invalid query: {0}")]
+    InvalidQuery(String),
+    #[error("Upstream responded with HTTP status {status}")]
+    UpstreamStatus { status: u16 },
+    #[error("Upstream request timed out")]
+    Timeout,
+    #[error("Rate limited, retry after {retry_after:?}")]
+    RateLimited { retry_after: Option<Duration> },
+    #[error("Failed to connect to upstream: {0}")]
+    ConnectionFailed(String),
+    #[error("Malformed upstream response at `{path}`: {message}, response began: {snippet:?}")]
+    MalformedResponse {
+        // where in the response things went wrong, e.g. `current_condition[0].temp_C`
+        path: String,
+        message: String,
+        // the start of the offending response (or field), truncated so it won't flood our logs
+        snippet: String,
+    },
+    #[error("Upstream request failed: {0}")]
+    RequestFailed(String),
+    #[error("Source unavailable, upstream is failing so requests are being rejected")]
+    SourceUnavailable,
     #[error("Handle send failed, mpsc dropped unexpectedly?")]
-    HandleSendError(#[from] mpsc::error::SendError<CityDataRequest>),
+    HandleSendError,
     #[error("Handle recv failed, oneshot dropped unexpectedly?")]
     HandleRecvError(#[from] oneshot::error::RecvError),
-    #[error("Task response send failed, oneshot droped unexpectedly?")]
-    TaskSendError,
+}
+
+impl CityDataError {
+    /// A short, stable name for the kind of error this is, e.g. for labelling metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CityDataError::NotFound(_) => "not_found",
+            CityDataError::NotGeocoded(_) => "not_geocoded",
+            CityDataError::InvalidQuery(_) => "invalid_query",
+            CityDataError::UpstreamStatus { .. } => "upstream_status",
+            CityDataError::Timeout => "timeout",
+            CityDataError::RateLimited { .. } => "rate_limited",
+            CityDataError::ConnectionFailed(_) => "connection_failed",
+            CityDataError::MalformedResponse { .. } => "malformed_response",
+            CityDataError::RequestFailed(_) => "request_failed",
+            CityDataError::SourceUnavailable => "source_unavailable",
+            CityDataError::HandleSendError => "handle_send_error",
+            CityDataError::HandleRecvError(_) => "handle_recv_error",
+        }
+    }
+
+    /// Whether this error is likely to go away if the request is simply tried again
+    pub fn is_transient(&self) -> bool {
+        match self {
+            CityDataError::Timeout
+            | CityDataError::ConnectionFailed(_)
+            | CityDataError::RateLimited { .. } => true,
+            CityDataError::UpstreamStatus { status } => *status >= 500,
+            _ => false,
+        }
+    }
+}
+
+impl From<mpsc::error::SendError<CityDataRequest>> for CityDataError {
+    fn from(_: mpsc::error::SendError<CityDataRequest>) -> Self {
+        CityDataError::HandleSendError
+    }
+}
+
+/// Sort reqwest's errors in to our own, more specific, variants
+impl From<reqwest::Error> for CityDataError {
+    fn from(e: reqwest::Error) -> Self {
+        // note: check for timeouts first, as a connection timeout is both a timeout and a connect error
+        if e.is_timeout() {
+            CityDataError::Timeout
+        } else if e.is_connect() {
+            CityDataError::ConnectionFailed(e.to_string())
+        } else if let Some(status) = e.status() {
+            CityDataError::UpstreamStatus {
+                status: status.as_u16(),
+            }
+        } else {
+            CityDataError::RequestFailed(e.to_string())
+        }
+    }
 }
 
 pub type CityDataResult<T> = Result<T, CityDataError>;
 
+/// Why a data source task couldn't be started. These all come down to how we were configured (or called), so
+/// they're reported when spawning rather than as every request failing later on
+#[derive(Debug, Error)]
+pub enum SpawnError {
+    #[error("No tokio runtime to spawn the task on, tasks must be spawned from within one")]
+    NoRuntime,
+    #[error("Invalid endpoint for {fetcher}, {url:?} is not a valid url: {message}")]
+    InvalidEndpoint {
+        fetcher: &'static str,
+        url: String,
+        message: String,
+    },
+    #[error("Invalid config for {fetcher}: {message}")]
+    InvalidConfig {
+        fetcher: &'static str,
+        message: String,
+    },
+}
+
+pub type SpawnResult<T> = Result<T, SpawnError>;
+
+/// Where a fetcher should send its requests. A request url is built as
+/// `{base_url}{city}{query_args}`, so `base_url` is everything up to (and including) the point where
+/// the city name goes, e.g. `https://nominatim.openstreetmap.org/search?q=`
+///
+/// This lets us point fetchers at a self-hosted instance, a mirror, or a local stub server in tests
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ApiEndpoint {
+    pub base_url: String,
+    pub query_args: String,
+}
+
+impl ApiEndpoint {
+    pub fn new(base_url: impl Into<String>, query_args: impl Into<String>) -> Self {
+        Self {
+            base_url: base_url.into(),
+            query_args: query_args.into(),
+        }
+    }
+
+    /// Build the full request url for a city, `city` is expected to already be url-safe (see
+    /// `http::url_encode`)
+    fn url_for(&self, city: &str) -> String {
+        format!("{}{city}{}", self.base_url, self.query_args)
+    }
+
+    /// Check that this endpoint makes for valid request urls, so a typo in a base url is caught when
+    /// `fetcher` starts rather than on its first request
+    ///
+    /// # Errors
+    /// If the url (without a city) doesn't parse
+    pub fn validate(&self, fetcher: &'static str) -> SpawnResult<()> {
+        let url = self.url_for("");
+        match reqwest::Url::parse(&url) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(SpawnError::InvalidEndpoint {
+                fetcher,
+                url,
+                message: e.to_string(),
+            }),
+        }
+    }
+}
+
+/// The default number of requests a `CityDataSourceTask` will work on at once
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 8;
+
+/// Configuration for a single fetcher task
+#[derive(Clone, Debug, PartialEq)]
+pub struct FetcherConfig {
+    /// where the fetcher sends its requests
+    pub endpoint: ApiEndpoint,
+    /// the maximum number of requests the fetcher task will work on concurrently, further requests
+    /// wait in the task's channel until one completes
+    pub max_in_flight: usize,
+    /// if set, the fetcher's results are cached
+    pub cache: Option<CacheConfig>,
+    /// if set, requests that reach the upstream (i.e. cache misses) are throttled
+    pub rate_limit: Option<RateLimitConfig>,
+    /// how upstream requests that fail for transient reasons are retried
+    pub retry: RetryPolicy,
+    /// if set, requests fail fast while the upstream is failing
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+    /// if set, and the fetcher falls back to other providers, how long each provider gets to answer (retries
+    /// included) before the next one is asked. See `FallbackChain::with_attempt_timeout`
+    pub fallback_timeout: Option<Duration>,
+    /// if set, the upstream is checked periodically (see `CityDataSource::health_check`). Off by default as
+    /// every check is a request to the upstream
+    pub health_check: Option<HealthCheckConfig>,
+}
+
+impl FetcherConfig {
+    pub fn new(endpoint: ApiEndpoint) -> Self {
+        Self {
+            endpoint,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            cache: None,
+            rate_limit: None,
+            retry: RetryPolicy::default(),
+            circuit_breaker: None,
+            fallback_timeout: None,
+            health_check: None,
+        }
+    }
+
+    /// Check this config for mistakes that would otherwise only show up once `fetcher` starts failing requests
+    ///
+    /// # Errors
+    /// If the endpoint isn't a valid url, the rate limit can never let a request through, health checks
+    /// would run back to back, or fallback providers would have no time at all to answer
+    pub fn validate(&self, fetcher: &'static str) -> SpawnResult<()> {
+        self.endpoint.validate(fetcher)?;
+
+        if self
+            .health_check
+            .as_ref()
+            .is_some_and(|health_check| health_check.interval.is_zero())
+        {
+            return Err(SpawnError::InvalidConfig {
+                fetcher,
+                message: String::from("the health check interval must be longer than zero"),
+            });
+        }
+
+        if self
+            .fallback_timeout
+            .is_some_and(|timeout| timeout.is_zero())
+        {
+            return Err(SpawnError::InvalidConfig {
+                fetcher,
+                message: String::from("the fallback timeout must be longer than zero"),
+            });
+        }
+
+        if let Some(rate_limit) = &self.rate_limit {
+            rate_limit
+                .validate()
+                .map_err(|e| SpawnError::InvalidConfig {
+                    fetcher,
+                    message: e.to_string(),
+                })?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Normalize a city name so that differently formatted requests for the same city compare equal,
+/// e.g. " San  JOSE" and "san jose"
+pub fn normalize_city_name(city: &str) -> String {
+    city.split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// The structured data a `CityDataSource` can produce. Every source returns one of these so that
+/// handles for different sources can be treated the same way by the dispatcher, while consumers
+/// that care about the details can match on the variant (or serialize the whole thing with serde)
+///
+/// Note: `#[serde(tag = "kind")]` serializes this as an "internally tagged" enum, e.g.
+/// `{"kind": "weather", "temperature_c": 20.0, ...}`. See <https://serde.rs/enum-representations.html>
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum CityData {
+    Weather(WeatherReport),
+    Place(PlaceRecord),
+    Sun(SunReport),
+    LocalTime(LocalTimeReport),
+    Wikipedia(WikipediaSummary),
+}
+
+/// Render the wrapped record as human-readable text
+impl Display for CityData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CityData::Weather(report) => report.fmt(f),
+            CityData::Place(record) => record.fmt(f),
+            CityData::Sun(report) => report.fmt(f),
+            CityData::LocalTime(report) => report.fmt(f),
+            CityData::Wikipedia(summary) => summary.fmt(f),
+        }
+    }
+}
+
+/// The sending half of the oneshot a `CityDataSourceTask` responds on
+pub type CityDataResponder = oneshot::Sender<CityDataResult<CityData>>;
+
 pub struct CityDataRequest {
-    pub city: String,
-    pub responder: oneshot::Sender<CityDataResult<String>>,
+    pub query: CityQuery,
+    /// when the requester will give up waiting. Once it passes, the requester is sent
+    /// `CityDataError::Timeout` and any work being done solely on its behalf is stopped
+    pub deadline: Option<Instant>,
+    pub responder: CityDataResponder,
 }
 
-pub trait CityDataSource {
+/// Anything that can fetch data for a city
+///
+/// Note: this is written out as a fn returning `impl Future + Send` rather than an `async fn` so that code
+/// which is generic over `CityDataSource` (like `Cached` or `spawn_data_source_task`) can move the future
+/// on to another thread. Implementors can still just write `async fn fetch_data(...)`
+pub trait CityDataSource: Send + Sync {
     /// Fetch city-specific data
-    #[allow(async_fn_in_trait)] /* allow this as it's only used internally */
-    async fn fetch_data(&self, city: String) -> CityDataResult<String>;
+    fn fetch_data(&self, query: CityQuery)
+        -> impl Future<Output = CityDataResult<CityData>> + Send;
+
+    /// Check whether this source is able to answer requests (e.g. that its upstream is reachable) without
+    /// asking about any particular city. `CityDataSourceTask` runs this periodically and keeps track of the
+    /// results, see `CityDataSourceHandle::health`
+    ///
+    /// Returns `None` if the source has no health check, which is the default. Sources that don't depend on
+    /// anything that can go away (like `SunDataSource`) have no reason to implement this
+    fn health_check(&self) -> impl Future<Output = Option<CityDataResult<()>>> + Send {
+        std::future::ready(None)
+    }
+
+    /// The key requests for `query` are cached and coalesced under, queries with the same key must get the
+    /// same answer from this source. Defaults to `CityQuery::key`, which only says which place is being asked
+    /// about, so sources whose answers depend on more of the query (e.g. weather forecasts) override this
+    fn request_key(&self, query: &CityQuery) -> String {
+        query.key()
+    }
+}
+
+/// An object safe version of `CityDataSource`, for when sources of different types need to be stored
+/// together, e.g. `Vec<Box<dyn DynCityDataSource>>` (see `registry::SourceRegistry`)
+///
+/// `CityDataSource` can't be used as `dyn` since each implementation returns its own future type, so this
+/// boxes the future instead. That costs an allocation per request, which is why the rest of the crate
+/// sticks to generics. Every `CityDataSource` is a `DynCityDataSource`, and a `Box<dyn DynCityDataSource>`
+/// is a `CityDataSource` again, so boxed sources can still be wrapped in decorators or spawned as tasks
+pub trait DynCityDataSource: Send + Sync {
+    /// Fetch city-specific data, see `CityDataSource::fetch_data`
+    fn fetch_data_boxed(&self, query: CityQuery) -> BoxFuture<'_, CityDataResult<CityData>>;
+
+    /// Check this source's health, see `CityDataSource::health_check`
+    fn health_check_boxed(&self) -> BoxFuture<'_, Option<CityDataResult<()>>>;
+
+    /// The key requests for `query` are cached and coalesced under, see `CityDataSource::request_key`
+    fn request_key_dyn(&self, query: &CityQuery) -> String;
+}
+
+impl<T> DynCityDataSource for T
+where
+    T: CityDataSource,
+{
+    fn fetch_data_boxed(&self, query: CityQuery) -> BoxFuture<'_, CityDataResult<CityData>> {
+        Box::pin(self.fetch_data(query))
+    }
+
+    fn health_check_boxed(&self) -> BoxFuture<'_, Option<CityDataResult<()>>> {
+        Box::pin(self.health_check())
+    }
+
+    fn request_key_dyn(&self, query: &CityQuery) -> String {
+        self.request_key(query)
+    }
+}
+
+impl CityDataSource for Box<dyn DynCityDataSource> {
+    fn fetch_data(
+        &self,
+        query: CityQuery,
+    ) -> impl Future<Output = CityDataResult<CityData>> + Send {
+        // note: deref to the `dyn` first, `Box<dyn DynCityDataSource>` is itself a `DynCityDataSource` (by
+        // way of this impl) so calling the method on the box would just recurse
+        (**self).fetch_data_boxed(query)
+    }
+
+    fn health_check(&self) -> impl Future<Output = Option<CityDataResult<()>>> + Send {
+        (**self).health_check_boxed()
+    }
+
+    fn request_key(&self, query: &CityQuery) -> String {
+        (**self).request_key_dyn(query)
+    }
 }
 
 pub struct CityDataSourceHandle {
     pub data_request_sender: mpsc::Sender<CityDataRequest>,
+    /// the latest results of the task's health checks, see `CityDataSourceTask::health`
+    pub health: watch::Receiver<SourceHealth>,
 }
 
 impl CityDataSourceHandle {
-    /// Request city-specific data
+    /// What we know about the source's health, as of its last health check
+    pub fn health(&self) -> SourceHealth {
+        self.health.borrow().clone()
+    }
+
+    /// Request city-specific data, giving up at `deadline` (if there is one)
     ///
     /// # Errors
-    /// If sending the request to the task or receiving a response fails
-    pub async fn request_data(&self, city: String) -> CityDataResult<String> {
+    /// If sending the request to the task or receiving a response fails, or the deadline passes
+    pub async fn request_data(
+        &self,
+        query: impl Into<CityQuery>,
+        deadline: Option<Instant>,
+    ) -> CityDataResult<CityData> {
         let (responder, receiver) = oneshot::channel();
-        let request = CityDataRequest { city, responder };
+        let request = CityDataRequest {
+            query: query.into(),
+            deadline,
+            responder,
+        };
 
-        self.data_request_sender.send(request).await?;
+        let send_and_receive = async {
+            self.data_request_sender.send(request).await?;
+            receiver.await?
+        };
 
-        receiver.await?
+        // the task will respond with a timeout itself once the deadline passes, but if its channel is
+        // full (or it's otherwise stuck) we still want to give up on time
+        match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, send_and_receive)
+                .await
+                .map_err(|_| CityDataError::Timeout)?,
+            None => send_and_receive.await,
+        }
+    }
+}
+
+/// A fetch the task is working on, along with everyone waiting on its result
+struct PendingFetch {
+    responders: Vec<CityDataResponder>,
+    // the latest deadline of anyone waiting, or `None` if someone is willing to wait forever
+    deadline: Option<Instant>,
+    // lets us stop the fetch once nobody is waiting for it any more
+    abort_handle: AbortHandle,
+}
+
+/// The later of two optional deadlines, where `None` means "no deadline"
+fn later_deadline(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    Some(a?.max(b?))
+}
+
+/// Wait for the next tick of `interval`, or forever if there is no interval
+async fn next_tick(interval: Option<&mut Interval>) {
+    match interval {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
     }
 }
 
@@ -69,46 +523,223 @@ where
     T: CityDataSource,
 {
     data_source: T,
+    max_in_flight: usize,
+    health_check: Option<HealthCheckConfig>,
+    // where we publish the results of our health checks, read through `CityDataSourceHandle::health`
+    health: watch::Sender<SourceHealth>,
 }
 
 impl<T> CityDataSourceTask<T>
 where
     T: CityDataSource,
 {
+    /// A task for `data_source`. Its health isn't checked unless asked for with `with_health_check`, as every
+    /// check is a request to the upstream
     pub fn new(data_source: T) -> Self {
-        Self { data_source }
+        Self {
+            data_source,
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            health_check: None,
+            health: watch::Sender::new(SourceHealth::new(HealthStatus::Unmonitored)),
+        }
     }
 
-    async fn handle_request(&self, request: CityDataRequest) -> CityDataResult<()> {
-        let city_data_result = self.data_source.fetch_data(request.city).await;
+    /// Limit the number of requests this task works on at once. A limit of 0 is treated as 1
+    #[must_use]
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = max_in_flight.max(1);
+        self
+    }
 
-        request
-            .responder
-            .send(city_data_result)
-            .map_err(|_| CityDataError::TaskSendError)
+    /// Check the source's health as often as `health_check` says, or not at all if it's `None`. Sources without
+    /// a health check are only asked once, and then left alone
+    #[must_use]
+    pub fn with_health_check(mut self, health_check: Option<HealthCheckConfig>) -> Self {
+        let status = if health_check.is_some() {
+            HealthStatus::Pending
+        } else {
+            HealthStatus::Unmonitored
+        };
+        self.health.send_replace(SourceHealth::new(status));
+        self.health_check = health_check;
+        self
+    }
+
+    /// Subscribe to the results of our health checks
+    pub fn health(&self) -> watch::Receiver<SourceHealth> {
+        self.health.subscribe()
+    }
+
+    /// Run the source's health check, giving up after `timeout`. The result is `None` if the source has no
+    /// health check
+    async fn check_health(&self, timeout: Duration) -> Option<(CityDataResult<()>, Duration)> {
+        let started = Instant::now();
+        let result = tokio::time::timeout(timeout, self.data_source.health_check())
+            .await
+            .unwrap_or(Some(Err(CityDataError::Timeout)))?;
+
+        Some((result, started.elapsed()))
+    }
+
+    /// Publish the result of a health check, logging whenever the source changes state
+    fn record_health(&self, result: &CityDataResult<()>, latency: Duration) {
+        let previous_status = self.health.borrow().status;
+        self.health
+            .send_modify(|health| health.record(result, latency));
+
+        match result {
+            Ok(()) if previous_status != HealthStatus::Healthy => {
+                tracing::info!("Health check passed in {latency:?}, source is healthy");
+            }
+            Err(e) if previous_status != HealthStatus::Unhealthy => {
+                tracing::warn!("Health check failed, source is unhealthy: {e}");
+            }
+            _ => {}
+        }
+    }
+
+    /// Fetch data for `query`, handing back the `key` it was requested under alongside the result so we know
+    /// who to respond to. The result is `None` if the fetch was aborted
+    ///
+    /// Note: aborting drops the data source's future part way through, which in turn drops (and so
+    /// cancels) any http request it was waiting on. See <https://docs.rs/futures/latest/futures/future/struct.Abortable.html>
+    async fn fetch(
+        &self,
+        key: String,
+        query: CityQuery,
+        abort_registration: AbortRegistration,
+    ) -> (String, Option<CityDataResult<CityData>>) {
+        let city_data_result =
+            Abortable::new(self.data_source.fetch_data(query), abort_registration).await;
+
+        (key, city_data_result.ok())
+    }
+
+    /// Stop any fetches that nobody is waiting on any more, either because their deadlines have passed (in
+    /// which case the requesters are told they timed out), or because every requester hung up
+    fn expire_pending(pending: &mut HashMap<String, PendingFetch>, now: Instant) {
+        pending.retain(|key, fetch| {
+            fetch.responders.retain(|responder| !responder.is_closed());
+
+            let expired = fetch.deadline.is_some_and(|deadline| deadline <= now);
+            if !expired && !fetch.responders.is_empty() {
+                return true;
+            }
+
+            tracing::info!("Abandoning fetch for {key:?}, nobody is waiting on it any more");
+            fetch.abort_handle.abort();
+            Self::respond(
+                std::mem::take(&mut fetch.responders),
+                &Err(CityDataError::Timeout),
+            );
+            false
+        });
+    }
+
+    /// Send a result to everyone waiting on it
+    fn respond(responders: Vec<CityDataResponder>, result: &CityDataResult<CityData>) {
+        for responder in responders {
+            // the only failure here is the requester hanging up before we responded, nothing to do but
+            // note it
+            if responder.send(result.clone()).is_err() {
+                tracing::warn!("DataSourceTask failed to respond to request, requester hung up");
+            }
+        }
     }
 
     /// Run our task, looping on input from the `request_receiver` until its corresponding sender is dropped,
     /// or the `cancellation_token` is cancelled. This is another example of an Actor/Handle model, this time
     /// made generic over anything that impls `CityDataSource`
     ///
+    /// Up to `max_in_flight` fetches are handled concurrently. Once that limit is reached we stop reading
+    /// from `request_receiver`, so any further requests wait in the channel until a slot frees up
+    ///
+    /// Requests for a city that is already being fetched (compared by `CityQuery::key`) don't start another
+    /// fetch, instead they wait on the one in flight and all receive its result. This keeps bursts of
+    /// requests for a popular city from turning into bursts of upstream calls
+    ///
+    /// Requests can carry a deadline, once every requester waiting on a fetch has either passed its
+    /// deadline or hung up, the fetch is aborted so we don't keep working for nobody
+    ///
+    /// Alongside all of that, the source's health is checked every `HealthCheckConfig::interval` (if it has a
+    /// health check). Checks don't take up one of the `max_in_flight` slots
+    ///
     /// Note: you may want to store `request_receiver` as a member of `self`. However, that creates a mutable
     /// reference issue where `request_receiver.recv()` requires a mutable reference to `request_receiver`,
     /// which would in turn require a mutable reference to `self`. This would then conflict with the various
-    /// calls to `self.handle_input` which use immutable references to `self`, and in rust you can only hold
+    /// calls to `self.fetch` which use immutable references to `self`, and in rust you can only hold
     /// one mutable reference xor one or more immutable references at a time.
     pub async fn run(
         &mut self,
         mut request_receiver: mpsc::Receiver<CityDataRequest>,
         cancellation_token: CancellationToken,
     ) {
-        // TODO uncomment me when implementing below
-        // let mut request_pool = FuturesUnordered::new();
+        let mut request_pool = FuturesUnordered::new();
+        // the fetches we're working on, keyed by `CityQuery::key`
+        let mut pending: HashMap<String, PendingFetch> = HashMap::new();
+        let mut accepting_requests = true;
+        // when the next health check is due (if we're checking health), and the check in progress (if any)
+        let mut health_check_interval = self.health_check.as_ref().map(|config| {
+            let mut interval = tokio::time::interval(config.interval);
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            interval
+        });
+        let mut health_check = pin!(Fuse::terminated());
+
+        while accepting_requests || !request_pool.is_empty() {
+            Self::expire_pending(&mut pending, Instant::now());
+            let next_deadline = pending.values().filter_map(|fetch| fetch.deadline).min();
 
-        loop {
             tokio::select! {
-                // TODO: implement me! Hint: there should be 2 branches here, and `dispatcher/lib.rs` has a
-                // good example to follow
+                // only pull new requests off the channel while we have room for them
+                optional_request = request_receiver.recv(), if accepting_requests && request_pool.len() < self.max_in_flight => {
+                    let Some(request) = optional_request else {
+                        // our senders are gone, but anyone who already sent a request is still waiting on a
+                        // response, so finish off whatever is in flight before exiting
+                        tracing::info!("DataSourceTask request sender dropped, shutting down");
+                        accepting_requests = false;
+                        continue;
+                    };
+
+                    match pending.entry(self.data_source.request_key(&request.query)) {
+                        Entry::Occupied(mut entry) => {
+                            tracing::debug!("Coalescing request for {:?} with one in flight", entry.key());
+                            let fetch = entry.get_mut();
+                            fetch.responders.push(request.responder);
+                            fetch.deadline = later_deadline(fetch.deadline, request.deadline);
+                        }
+                        Entry::Vacant(entry) => {
+                            let (abort_handle, abort_registration) = AbortHandle::new_pair();
+                            request_pool.push(self.fetch(entry.key().clone(), request.query, abort_registration));
+                            entry.insert(PendingFetch {
+                                responders: vec![request.responder],
+                                deadline: request.deadline,
+                                abort_handle,
+                            });
+                        }
+                    }
+                },
+                Some((key, result)) = request_pool.next(), if !request_pool.is_empty() => {
+                    // an aborted fetch has already been dealt with in `expire_pending`
+                    if let Some(result) = result {
+                        let responders = pending.remove(&key).map(|fetch| fetch.responders);
+                        Self::respond(responders.unwrap_or_default(), &result);
+                    }
+                },
+                // wake up when the next deadline passes, `expire_pending` will deal with it on the next loop
+                () = tokio::time::sleep_until(next_deadline.unwrap_or_else(Instant::now)), if next_deadline.is_some() => {},
+                () = next_tick(health_check_interval.as_mut()), if health_check.is_terminated() => {
+                    let timeout = self.health_check.as_ref().map_or(Duration::ZERO, |config| config.timeout);
+                    health_check.set(self.check_health(timeout).fuse());
+                },
+                outcome = &mut health_check, if !health_check.is_terminated() => match outcome {
+                    Some((result, latency)) => self.record_health(&result, latency),
+                    None => {
+                        tracing::debug!("DataSourceTask source has no health check, no longer checking");
+                        self.health.send_replace(SourceHealth::new(HealthStatus::Unmonitored));
+                        health_check_interval = None;
+                    }
+                },
                 () = cancellation_token.cancelled() => {
                     tracing::info!("DataSourceTask cancellation token cancelled, shutting down");
                     break;
@@ -117,3 +748,211 @@ where
         }
     }
 }
+
+impl<T> CityDataSourceTask<T>
+where
+    T: CityDataSource + 'static,
+{
+    /// Spawn this task, returning a handle to it. The task runs inside the caller's current tracing span
+    ///
+    /// # Errors
+    /// If called outside of a tokio runtime
+    pub fn spawn(
+        mut self,
+        cancellation_token: CancellationToken,
+    ) -> SpawnResult<CityDataSourceHandle> {
+        // `tokio::spawn` would panic without a runtime
+        let runtime = tokio::runtime::Handle::try_current().map_err(|_| SpawnError::NoRuntime)?;
+        let (sender, receiver) = mpsc::channel(16);
+        let health = self.health();
+
+        runtime.spawn(
+            async move {
+                self.run(receiver, cancellation_token).await;
+            }
+            .in_current_span(),
+        );
+
+        Ok(CityDataSourceHandle {
+            data_request_sender: sender,
+            health,
+        })
+    }
+}
+
+/// Spawn a task running `data_source` that can handle up to `max_in_flight` requests at once, returning
+/// a handle to it. The task runs inside the caller's current tracing span, and doesn't check the source's
+/// health
+///
+/// # Errors
+/// If called outside of a tokio runtime
+pub fn spawn_data_source_task<T>(
+    data_source: T,
+    max_in_flight: usize,
+    cancellation_token: CancellationToken,
+) -> SpawnResult<CityDataSourceHandle>
+where
+    T: CityDataSource + 'static,
+{
+    CityDataSourceTask::new(data_source)
+        .with_max_in_flight(max_in_flight)
+        .spawn(cancellation_token)
+}
+
+/// Spawn a task for a fetcher, wrapping it in whichever decorators `config` asks for. Each decorator is
+/// applied by its own fn which then hands off to the next, since every combination is a different type.
+/// From the outside in we have: cache -> circuit breaker -> retries -> rate limiter -> fetcher, so cache hits
+/// never use up rate limit, requests rejected by an open circuit don't wait around for a token first, and
+/// every retry waits for a token of its own
+///
+/// `name` identifies the fetcher in the task's logs and metrics, and in any `SpawnError`
+fn spawn_fetcher_task<T>(
+    name: &'static str,
+    fetcher: T,
+    config: &FetcherConfig,
+    cancellation_token: CancellationToken,
+) -> SpawnResult<CityDataSourceHandle>
+where
+    T: CityDataSource + 'static,
+{
+    spawn_fallback_task(name, vec![(name, fetcher)], config, cancellation_token)
+}
+
+/// Like `spawn_fetcher_task`, but for a `FallbackChain` of (named) `providers`. Each provider is its own
+/// upstream, so each gets its own circuit breaker, retries and rate limit inside the chain, only the cache
+/// is shared by the whole chain
+fn spawn_fallback_task<T>(
+    name: &'static str,
+    providers: Vec<(&'static str, T)>,
+    config: &FetcherConfig,
+    cancellation_token: CancellationToken,
+) -> SpawnResult<CityDataSourceHandle>
+where
+    T: CityDataSource + 'static,
+{
+    config.validate(name)?;
+    let _span = tracing::info_span!("Fetcher", source = name).entered();
+
+    match &config.rate_limit {
+        Some(rate_limit_config) => {
+            let providers = providers
+                .into_iter()
+                .map(|(provider, fetcher)| {
+                    RateLimited::new(fetcher, rate_limit_config.clone())
+                        .map(|rate_limited| (provider, rate_limited))
+                })
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| SpawnError::InvalidConfig {
+                    fetcher: name,
+                    message: e.to_string(),
+                })?;
+
+            spawn_retrying_task(name, providers, config, cancellation_token)
+        }
+        None => spawn_retrying_task(name, providers, config, cancellation_token),
+    }
+}
+
+fn spawn_retrying_task<T>(
+    name: &'static str,
+    providers: Vec<(&'static str, T)>,
+    config: &FetcherConfig,
+    cancellation_token: CancellationToken,
+) -> SpawnResult<CityDataSourceHandle>
+where
+    T: CityDataSource + 'static,
+{
+    let providers = providers
+        .into_iter()
+        .map(|(provider, data_source)| {
+            let retrying = Retrying::new(data_source, config.retry.clone()).with_metrics(provider);
+            (provider, retrying)
+        })
+        .collect();
+
+    spawn_circuit_breaker_task(name, providers, config, cancellation_token)
+}
+
+fn spawn_circuit_breaker_task<T>(
+    name: &'static str,
+    providers: Vec<(&'static str, T)>,
+    config: &FetcherConfig,
+    cancellation_token: CancellationToken,
+) -> SpawnResult<CityDataSourceHandle>
+where
+    T: CityDataSource + 'static,
+{
+    match &config.circuit_breaker {
+        Some(breaker_config) => {
+            let providers = providers
+                .into_iter()
+                .map(|(provider, data_source)| {
+                    (
+                        provider,
+                        CircuitBreaker::new(data_source, breaker_config.clone()),
+                    )
+                })
+                .collect();
+
+            spawn_chained_task(name, providers, config, cancellation_token)
+        }
+        None => spawn_chained_task(name, providers, config, cancellation_token),
+    }
+}
+
+/// A lone provider is used as it is, more than one are chained together
+fn spawn_chained_task<T>(
+    name: &'static str,
+    mut providers: Vec<(&'static str, T)>,
+    config: &FetcherConfig,
+    cancellation_token: CancellationToken,
+) -> SpawnResult<CityDataSourceHandle>
+where
+    T: CityDataSource + 'static,
+{
+    if providers.len() == 1 {
+        if let Some((_, data_source)) = providers.pop() {
+            return spawn_cached_task(name, data_source, config, cancellation_token);
+        }
+    }
+
+    let mut chain = FallbackChain::new(providers).with_metrics(name);
+    if let Some(fallback_timeout) = config.fallback_timeout {
+        chain = chain.with_attempt_timeout(fallback_timeout);
+    }
+
+    spawn_cached_task(name, chain, config, cancellation_token)
+}
+
+fn spawn_cached_task<T>(
+    name: &'static str,
+    data_source: T,
+    config: &FetcherConfig,
+    cancellation_token: CancellationToken,
+) -> SpawnResult<CityDataSourceHandle>
+where
+    T: CityDataSource + 'static,
+{
+    match &config.cache {
+        Some(cache_config) => spawn_configured_task(
+            Cached::new(data_source, cache_config.clone()).with_metrics(name),
+            config,
+            cancellation_token,
+        ),
+        None => spawn_configured_task(data_source, config, cancellation_token),
+    }
+}
+
+fn spawn_configured_task<T>(
+    data_source: T,
+    config: &FetcherConfig,
+    cancellation_token: CancellationToken,
+) -> SpawnResult<CityDataSourceHandle>
+where
+    T: CityDataSource + 'static,
+{
+    CityDataSourceTask::new(data_source)
+        .with_max_in_flight(config.max_in_flight)
+        .with_health_check(config.health_check.clone())
+        .spawn(cancellation_token)
+}
@@ -0,0 +1,64 @@
+use std::sync::LazyLock;
+
+use prometheus::{register_int_counter_vec, IntCounter, IntCounterVec};
+
+// Metrics are registered with the `prometheus` crate's default registry the first time they're used, so
+// anything that serves `prometheus::gather()` (see `rest_api`) picks them up without us passing a registry
+// around. Labels are filled in as values are recorded, so a source that has never had a cache lookup won't
+// show up in the cache metrics at all
+//
+// Note: see <https://prometheus.io/docs/practices/naming/> for how these are named
+
+static CACHE_LOOKUPS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "city_info_source_cache_lookups_total",
+        "Cache lookups made by each source, by whether they hit or missed",
+        &["source", "result"]
+    )
+    .expect("metric registered more than once")
+});
+
+static UPSTREAM_RETRIES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "city_info_upstream_retries_total",
+        "Requests retried after a transient failure, by the upstream they were for",
+        &["upstream"]
+    )
+    .expect("metric registered more than once")
+});
+
+static FALLBACK_ANSWERS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "city_info_fallback_answers_total",
+        "Requests answered by each provider in a source's fallback chain",
+        &["source", "provider"]
+    )
+    .expect("metric registered more than once")
+});
+
+/// The counters a `Cached` data source records its hits and misses in
+pub(crate) struct CacheMetrics {
+    pub hits: IntCounter,
+    pub misses: IntCounter,
+}
+
+impl CacheMetrics {
+    pub fn for_source(source: &str) -> Self {
+        Self {
+            hits: CACHE_LOOKUPS.with_label_values(&[source, "hit"]),
+            misses: CACHE_LOOKUPS.with_label_values(&[source, "miss"]),
+        }
+    }
+}
+
+/// The counter a `Retrying` data source records its retries in. `upstream` is the source's name, or within a
+/// fallback chain the provider's, as a source can talk to more than one upstream (e.g. weather falling back to
+/// Open-Meteo)
+pub(crate) fn retries_counter(upstream: &str) -> IntCounter {
+    UPSTREAM_RETRIES.with_label_values(&[upstream])
+}
+
+/// The counter a `FallbackChain` records the requests `provider` answers for `source` in
+pub(crate) fn fallback_answers_counter(source: &str, provider: &str) -> IntCounter {
+    FALLBACK_ANSWERS.with_label_values(&[source, provider])
+}
@@ -0,0 +1,487 @@
+use serde::Deserialize;
+
+use crate::{
+    http::{get, parse_json, probe_query, PROBE_COORDINATES},
+    weather_api::{celsius_to_kelvin, OPEN_METEO_PROVIDER},
+    ApiEndpoint, CityData, CityDataError, CityDataResult, CityQuery, Coordinates, DailyForecast,
+    HourlyForecast, Units, WeatherReport,
+};
+
+pub(crate) const OPEN_METEO_API_PATH: &str = "https://api.open-meteo.com/v1/forecast?";
+// the current conditions we want, and report times in the city's own timezone rather than UTC
+pub(crate) const OPEN_METEO_API_ARGS: &str = "&current=temperature_2m,apparent_temperature,precipitation,weather_code,wind_speed_10m,wind_direction_10m&timezone=auto";
+
+const DAILY_FIELDS: &str =
+    "temperature_2m_max,temperature_2m_min,temperature_2m_mean,sunrise,sunset";
+const HOURLY_FIELDS: &str = "temperature_2m,apparent_temperature,precipitation_probability,precipitation,weather_code,wind_speed_10m,wind_direction_10m";
+
+// Open-Meteo forecasts up to 16 days ahead
+const MAX_FORECAST_DAYS: u8 = 16;
+
+impl ApiEndpoint {
+    /// The public Open-Meteo forecast endpoint. Unlike the other endpoints this is looked up by
+    /// location, so rather than a city name `latitude=...&longitude=...` goes between `base_url` and
+    /// `query_args`
+    pub fn open_meteo() -> Self {
+        Self::new(OPEN_METEO_API_PATH, OPEN_METEO_API_ARGS)
+    }
+}
+
+fn request_path_for_query(
+    endpoint: &ApiEndpoint,
+    query: &CityQuery,
+    coordinates: Coordinates,
+) -> String {
+    let mut url = endpoint.url_for(&format!(
+        "latitude={:.4}&longitude={:.4}",
+        coordinates.latitude, coordinates.longitude
+    ));
+
+    // Open-Meteo can report in imperial itself, and in m/s for SI. It can't do kelvin though, so SI
+    // temperatures are converted from celsius once we have them
+    match query.units {
+        Units::Metric => {}
+        Units::Imperial => {
+            url.push_str(
+                "&temperature_unit=fahrenheit&wind_speed_unit=mph&precipitation_unit=inch",
+            );
+        }
+        Units::Si => url.push_str("&wind_speed_unit=ms"),
+    }
+
+    if query.forecast.days > 0 {
+        let days = query.forecast.days.min(MAX_FORECAST_DAYS);
+        url = format!("{url}&forecast_days={days}&daily={DAILY_FIELDS}");
+
+        if query.forecast.hourly {
+            url = format!("{url}&hourly={HOURLY_FIELDS}");
+        }
+    }
+
+    url
+}
+
+/// A request Open-Meteo should always be able to answer, for health checks (see `http::probe`)
+pub(crate) fn probe_url(endpoint: &ApiEndpoint) -> String {
+    request_path_for_query(endpoint, &probe_query(), PROBE_COORDINATES)
+}
+
+/// Fetches weather for an already geocoded city using Open-Meteo <https://open-meteo.com/en/docs>
+/// Open-Meteo doesn't do its own geocoding, so `query` must have coordinates
+pub(crate) async fn fetch_open_meteo_data(
+    http_client: &reqwest::Client,
+    endpoint: &ApiEndpoint,
+    query: CityQuery,
+) -> CityDataResult<CityData> {
+    let coordinates = query
+        .coordinates
+        .ok_or_else(|| CityDataError::NotGeocoded(query.city.clone()))?;
+
+    let response = get(
+        http_client,
+        &request_path_for_query(endpoint, &query, coordinates),
+    )
+    .await?;
+    let open_meteo_response = parse_json::<OpenMeteoResponse>(response).await?;
+
+    Ok(CityData::Weather(open_meteo_response.into_report(&query)?))
+}
+
+/// A struct representing the JSON response from Open-Meteo. Each measurement is named for what it is
+/// and how high up it's measured, e.g. `temperature_2m`
+///
+/// Note: `daily` and `hourly` are "columnar", with a list per measurement rather than a list of entries. Any
+/// entry in a column can be `null` where Open-Meteo has no value (e.g. `precipitation_probability` beyond
+/// the first few days of forecast, or sunrise during a polar night)
+#[derive(Deserialize)]
+struct OpenMeteoResponse {
+    current: OpenMeteoCurrent,
+    #[serde(default)]
+    daily: Option<OpenMeteoDaily>,
+    #[serde(default)]
+    hourly: Option<OpenMeteoHourly>,
+}
+
+#[derive(Deserialize)]
+struct OpenMeteoCurrent {
+    // local time, as YYYY-MM-DDTHH:MM
+    time: String,
+    temperature_2m: f64,
+    apparent_temperature: f64,
+    precipitation: f64,
+    weather_code: u8,
+    wind_speed_10m: f64,
+    wind_direction_10m: f64,
+}
+
+#[derive(Deserialize)]
+struct OpenMeteoDaily {
+    // dates, as YYYY-MM-DD
+    time: Vec<String>,
+    temperature_2m_max: Vec<Option<f64>>,
+    temperature_2m_min: Vec<Option<f64>>,
+    temperature_2m_mean: Vec<Option<f64>>,
+    sunrise: Vec<Option<String>>,
+    sunset: Vec<Option<String>>,
+}
+
+#[derive(Deserialize)]
+struct OpenMeteoHourly {
+    // local times, as YYYY-MM-DDTHH:MM
+    time: Vec<String>,
+    temperature_2m: Vec<Option<f64>>,
+    apparent_temperature: Vec<Option<f64>>,
+    precipitation_probability: Vec<Option<f64>>,
+    precipitation: Vec<Option<f64>>,
+    weather_code: Vec<Option<u8>>,
+    wind_speed_10m: Vec<Option<f64>>,
+    wind_direction_10m: Vec<Option<f64>>,
+}
+
+/// Get the `index`th value of one of Open-Meteo's columns, which should all be the same length. A `null` entry
+/// isn't an error, it comes back as `None` from columns that allow it
+fn value_at<T: Clone>(values: &[T], column: &str, index: usize) -> CityDataResult<T> {
+    values
+        .get(index)
+        .cloned()
+        .ok_or_else(|| CityDataError::MalformedResponse {
+            path: format!("{column}[{index}]"),
+            message: String::from("missing value"),
+            snippet: String::new(),
+        })
+}
+
+/// Split a local `YYYY-MM-DDTHH:MM` timestamp in to its date and time
+fn split_timestamp(timestamp: &str) -> (&str, &str) {
+    timestamp.split_once('T').unwrap_or((timestamp, ""))
+}
+
+/// Open-Meteo can't report kelvin, so SI temperatures come back in celsius
+fn temperature_in(units: Units, temperature: f64) -> f64 {
+    match units {
+        Units::Si => celsius_to_kelvin(temperature),
+        Units::Metric | Units::Imperial => temperature,
+    }
+}
+
+/// Turn a bearing in degrees in to one of the 16 compass points wttr.in uses, e.g. "NNW"
+fn compass_point(degrees: f64) -> &'static str {
+    const POINTS: [&str; 16] = [
+        "N", "NNE", "NE", "ENE", "E", "ESE", "SE", "SSE", "S", "SSW", "SW", "WSW", "W", "WNW",
+        "NW", "NNW",
+    ];
+
+    // each point covers 22.5 degrees, centred on its bearing
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    let index = ((degrees.rem_euclid(360.0) / 22.5).round() as usize) % POINTS.len();
+
+    POINTS[index]
+}
+
+/// Describe a WMO weather interpretation code, see the bottom of <https://open-meteo.com/en/docs>
+fn describe_weather_code(code: u8) -> &'static str {
+    match code {
+        0 => "Clear sky",
+        1 => "Mainly clear",
+        2 => "Partly cloudy",
+        3 => "Overcast",
+        45 | 48 => "Fog",
+        51 | 53 | 55 => "Drizzle",
+        56 | 57 => "Freezing drizzle",
+        61 | 63 => "Rain",
+        65 => "Heavy rain",
+        66 | 67 => "Freezing rain",
+        71 | 73 | 75 | 77 => "Snow",
+        80..=82 => "Rain showers",
+        85 | 86 => "Snow showers",
+        95 => "Thunderstorm",
+        96 | 99 => "Thunderstorm with hail",
+        _ => "Unknown conditions",
+    }
+}
+
+impl OpenMeteoResponse {
+    fn into_report(self, query: &CityQuery) -> CityDataResult<WeatherReport> {
+        let units = query.units;
+        let current = self.current;
+
+        let forecast = match (self.daily, query.forecast.days) {
+            (Some(daily), days) if days > 0 => {
+                let hourly = self.hourly.filter(|_| query.forecast.hourly);
+                (0..daily.time.len().min(days.into()))
+                    .map(|index| daily.forecast_for(index, hourly.as_ref(), units))
+                    .filter_map(Result::transpose)
+                    .collect::<CityDataResult<_>>()?
+            }
+            _ => Vec::new(),
+        };
+
+        Ok(WeatherReport {
+            units,
+            provider: String::from(OPEN_METEO_PROVIDER),
+            observation_time: split_timestamp(&current.time).1.to_owned(),
+            temperature: temperature_in(units, current.temperature_2m),
+            feels_like: temperature_in(units, current.apparent_temperature),
+            description: describe_weather_code(current.weather_code).to_owned(),
+            wind_direction: compass_point(current.wind_direction_10m).to_owned(),
+            wind_speed: current.wind_speed_10m,
+            precipitation: current.precipitation,
+            forecast,
+        })
+    }
+}
+
+impl OpenMeteoDaily {
+    /// Build the forecast for the `index`th day, picking that day's entries out of `hourly` if there
+    /// are any. A day Open-Meteo has no temperatures for is left out
+    fn forecast_for(
+        &self,
+        index: usize,
+        hourly: Option<&OpenMeteoHourly>,
+        units: Units,
+    ) -> CityDataResult<Option<DailyForecast>> {
+        let date = value_at(&self.time, "daily.time", index)?;
+        let (Some(min_temperature), Some(max_temperature), Some(avg_temperature)) = (
+            value_at(&self.temperature_2m_min, "daily.temperature_2m_min", index)?,
+            value_at(&self.temperature_2m_max, "daily.temperature_2m_max", index)?,
+            value_at(
+                &self.temperature_2m_mean,
+                "daily.temperature_2m_mean",
+                index,
+            )?,
+        ) else {
+            tracing::debug!("Open-Meteo has no temperatures for {date}, leaving it out");
+            return Ok(None);
+        };
+        let sunrise = value_at(&self.sunrise, "daily.sunrise", index)?;
+        let sunset = value_at(&self.sunset, "daily.sunset", index)?;
+
+        let hourly = match hourly {
+            Some(hourly) => hourly.forecasts_on(&date, units)?,
+            None => Vec::new(),
+        };
+
+        Ok(Some(DailyForecast {
+            min_temperature: temperature_in(units, min_temperature),
+            max_temperature: temperature_in(units, max_temperature),
+            avg_temperature: temperature_in(units, avg_temperature),
+            sunrise: sunrise.map(|sunrise| split_timestamp(&sunrise).1.to_owned()),
+            sunset: sunset.map(|sunset| split_timestamp(&sunset).1.to_owned()),
+            date,
+            hourly,
+        }))
+    }
+}
+
+impl OpenMeteoHourly {
+    /// Every hourly forecast falling on `date`
+    fn forecasts_on(&self, date: &str, units: Units) -> CityDataResult<Vec<HourlyForecast>> {
+        self.time
+            .iter()
+            .enumerate()
+            .filter(|(_, timestamp)| split_timestamp(timestamp).0 == date)
+            .map(|(index, timestamp)| self.forecast_at(index, timestamp, units))
+            .filter_map(Result::transpose)
+            .collect()
+    }
+
+    /// The `index`th hour's forecast. Only the chance of rain is optional, an hour that's missing anything
+    /// else is left out
+    fn forecast_at(
+        &self,
+        index: usize,
+        timestamp: &str,
+        units: Units,
+    ) -> CityDataResult<Option<HourlyForecast>> {
+        let (
+            Some(temperature),
+            Some(feels_like),
+            Some(weather_code),
+            Some(wind_direction),
+            Some(wind_speed),
+            Some(precipitation),
+        ) = (
+            value_at(&self.temperature_2m, "hourly.temperature_2m", index)?,
+            value_at(
+                &self.apparent_temperature,
+                "hourly.apparent_temperature",
+                index,
+            )?,
+            value_at(&self.weather_code, "hourly.weather_code", index)?,
+            value_at(&self.wind_direction_10m, "hourly.wind_direction_10m", index)?,
+            value_at(&self.wind_speed_10m, "hourly.wind_speed_10m", index)?,
+            value_at(&self.precipitation, "hourly.precipitation", index)?,
+        )
+        else {
+            tracing::debug!("Open-Meteo's forecast for {timestamp} is incomplete, leaving it out");
+            return Ok(None);
+        };
+
+        Ok(Some(HourlyForecast {
+            time: split_timestamp(timestamp).1.to_owned(),
+            temperature: temperature_in(units, temperature),
+            feels_like: temperature_in(units, feels_like),
+            description: describe_weather_code(weather_code).to_owned(),
+            wind_direction: compass_point(wind_direction).to_owned(),
+            wind_speed,
+            precipitation,
+            chance_of_rain: value_at(
+                &self.precipitation_probability,
+                "hourly.precipitation_probability",
+                index,
+            )?,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::{extract::Query, routing::get, Router};
+
+    use crate::{
+        test_utils::spawn_fixture_server, ApiEndpoint, CityData, CityDataError, CityQuery,
+        Coordinates, ForecastOptions, Units,
+    };
+
+    use super::{
+        compass_point, fetch_open_meteo_data, request_path_for_query, OpenMeteoDaily,
+        OpenMeteoHourly, OPEN_METEO_API_ARGS,
+    };
+
+    const SAN_JOSE: Coordinates = Coordinates {
+        latitude: 37.336_166_3,
+        longitude: -121.890_591,
+    };
+
+    #[tokio::test]
+    async fn test_fetch() {
+        // serve a canned Open-Meteo response, checking we asked for the right place
+        let router = Router::new().route(
+            "/v1/forecast",
+            get(|Query(params): Query<HashMap<String, String>>| async move {
+                assert_eq!(params.get("latitude").map(String::as_str), Some("37.3362"));
+                assert_eq!(
+                    params.get("longitude").map(String::as_str),
+                    Some("-121.8906")
+                );
+                include_str!("../tests/fixtures/open_meteo.json")
+            }),
+        );
+        let base_url = spawn_fixture_server(router).await;
+        let endpoint = ApiEndpoint::new(format!("{base_url}/v1/forecast?"), OPEN_METEO_API_ARGS);
+
+        let query = CityQuery::new("San Jose")
+            .with_coordinates(SAN_JOSE)
+            .with_forecast(ForecastOptions {
+                days: 2,
+                hourly: true,
+            });
+        let result = fetch_open_meteo_data(&reqwest::Client::new(), &endpoint, query).await;
+
+        let Ok(CityData::Weather(report)) = result else {
+            panic!("Expected a weather report, got {result:?}");
+        };
+        assert_eq!(report.provider, "open-meteo");
+        assert_eq!(report.observation_time, "22:00");
+        assert_eq!(report.temperature, 19.5);
+        assert_eq!(report.description, "Mainly clear");
+        assert_eq!(report.wind_direction, "ESE");
+
+        assert_eq!(report.forecast.len(), 2);
+        assert_eq!(report.forecast[1].date, "2024-10-02");
+        assert_eq!(report.forecast[1].sunset.as_deref(), Some("18:49"));
+        // Open-Meteo forecasts every hour
+        assert_eq!(report.forecast[0].hourly.len(), 24);
+        assert_eq!(report.forecast[0].hourly[13].time, "13:00");
+        assert_eq!(report.forecast[0].hourly[13].description, "Rain");
+    }
+
+    #[test]
+    fn test_nulls() {
+        // Open-Meteo stops forecasting the chance of rain a few days out, and can leave gaps in anything
+        let hourly: OpenMeteoHourly = serde_json::from_str(
+            r#"{
+                "time": ["2024-10-05T00:00", "2024-10-05T01:00", "2024-10-05T02:00"],
+                "temperature_2m": [12.0, null, 11.0],
+                "apparent_temperature": [11.0, 10.5, 10.0],
+                "precipitation_probability": [null, null, 40],
+                "precipitation": [0.0, 0.0, 0.2],
+                "weather_code": [1, 2, 61],
+                "wind_speed_10m": [5.0, 5.5, 6.0],
+                "wind_direction_10m": [90.0, 100.0, 110.0]
+            }"#,
+        )
+        .unwrap();
+        let daily: OpenMeteoDaily = serde_json::from_str(
+            r#"{
+                "time": ["2024-10-05", "2024-10-06"],
+                "temperature_2m_max": [18.0, null],
+                "temperature_2m_min": [9.0, null],
+                "temperature_2m_mean": [13.0, null],
+                "sunrise": [null, "2024-10-06T07:01"],
+                "sunset": [null, "2024-10-06T18:40"]
+            }"#,
+        )
+        .unwrap();
+
+        let forecast = daily
+            .forecast_for(0, Some(&hourly), Units::Metric)
+            .unwrap()
+            .expect("a day with temperatures is kept");
+        assert_eq!(forecast.sunrise, None);
+        // the hour with no temperature is left out, while a missing chance of rain is fine
+        assert_eq!(forecast.hourly.len(), 2);
+        assert_eq!(forecast.hourly[0].chance_of_rain, None);
+        assert_eq!(forecast.hourly[1].time, "02:00");
+        assert_eq!(forecast.hourly[1].chance_of_rain, Some(40.0));
+
+        // as is a day with no temperatures
+        assert!(daily
+            .forecast_for(1, Some(&hourly), Units::Metric)
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_requires_coordinates() {
+        let result = fetch_open_meteo_data(
+            &reqwest::Client::new(),
+            &ApiEndpoint::open_meteo(),
+            CityQuery::new("San Jose"),
+        )
+        .await;
+
+        assert!(matches!(result, Err(CityDataError::NotGeocoded(city)) if city == "San Jose"));
+    }
+
+    #[test]
+    fn test_request_path() {
+        let endpoint = ApiEndpoint::new("http://localhost/forecast?", "&current=temperature_2m");
+        let query = CityQuery::new("San Jose").with_units(Units::Imperial);
+
+        assert_eq!(
+            request_path_for_query(&endpoint, &query, SAN_JOSE),
+            "http://localhost/forecast?latitude=37.3362&longitude=-121.8906&current=temperature_2m\
+             &temperature_unit=fahrenheit&wind_speed_unit=mph&precipitation_unit=inch"
+        );
+
+        let query = query.with_units(Units::Si).with_forecast(ForecastOptions {
+            days: 30,
+            hourly: false,
+        });
+        let path = request_path_for_query(&endpoint, &query, SAN_JOSE);
+        assert!(path.contains("&wind_speed_unit=ms&forecast_days=16&daily="));
+        assert!(!path.contains("hourly="));
+    }
+
+    #[test]
+    fn test_compass_point() {
+        assert_eq!(compass_point(0.0), "N");
+        assert_eq!(compass_point(112.0), "ESE");
+        assert_eq!(compass_point(350.0), "N");
+        assert_eq!(compass_point(-90.0), "W");
+        assert_eq!(compass_point(315.0), "NW");
+    }
+}
@@ -0,0 +1,41 @@
+use crate::{
+    http::probe,
+    open_meteo_api::{fetch_open_meteo_data, probe_url},
+    ApiEndpoint, CityData, CityDataResult, CityDataSource, CityQuery,
+};
+
+/// Fetches weather from Open-Meteo. This only works for cities that have already been geocoded (see
+/// `CityQuery::coordinates`), so it's best used behind a geocoder or as a fallback for wttr.in (see
+/// `weather_fetcher::spawn_weather_fetcher_task_with_fallback`)
+pub struct OpenMeteoFetcher {
+    // An http client we can re-use to avoid re-initializing TLS stuff
+    // and do connection pooling, usually shared with our other fetchers
+    http_client: reqwest::Client,
+    // where to send our requests, see `ApiEndpoint::open_meteo()` for the public default
+    endpoint: ApiEndpoint,
+}
+
+impl OpenMeteoFetcher {
+    /// Fetch from `endpoint` using `http_client`, which can be shared with other fetchers (see
+    /// `HttpClientConfig`)
+    pub fn new(endpoint: ApiEndpoint, http_client: reqwest::Client) -> Self {
+        Self {
+            http_client,
+            endpoint,
+        }
+    }
+}
+
+impl CityDataSource for OpenMeteoFetcher {
+    async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+        fetch_open_meteo_data(&self.http_client, &self.endpoint, query).await
+    }
+
+    async fn health_check(&self) -> Option<CityDataResult<()>> {
+        Some(probe(&self.http_client, &probe_url(&self.endpoint)).await)
+    }
+
+    fn request_key(&self, query: &CityQuery) -> String {
+        query.weather_key()
+    }
+}
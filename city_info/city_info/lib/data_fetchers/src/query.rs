@@ -0,0 +1,347 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{normalize_city_name, CityData, CityDataError, CityDataResult};
+
+/// A point on the globe, in decimal degrees
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct Coordinates {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
+/// The mean radius of the earth, in km
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+impl Coordinates {
+    /// The great circle distance to `other`, in km. See <https://en.wikipedia.org/wiki/Haversine_formula>
+    pub fn distance_km(self, other: Coordinates) -> f64 {
+        let (latitude_a, latitude_b) = (self.latitude.to_radians(), other.latitude.to_radians());
+        let half_latitude_delta = (latitude_b - latitude_a) / 2.0;
+        let half_longitude_delta = (other.longitude - self.longitude).to_radians() / 2.0;
+
+        let h = half_latitude_delta.sin().powi(2)
+            + latitude_a.cos() * latitude_b.cos() * half_longitude_delta.sin().powi(2);
+
+        2.0 * EARTH_RADIUS_KM * h.sqrt().asin()
+    }
+}
+
+/// Formats as `latitude,longitude` to 4 decimal places (roughly 10m), which is plenty to pin down a city
+/// and keeps nearby lookups for the same place sharing cache entries
+impl Display for Coordinates {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{:.4},{:.4}", self.latitude, self.longitude))
+    }
+}
+
+/// Which units measurements are reported in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Units {
+    /// celsius, kilometers per hour and millimeters
+    #[default]
+    Metric,
+    /// fahrenheit, miles per hour and inches
+    Imperial,
+    /// kelvin, meters per second and millimeters
+    Si,
+}
+
+impl Units {
+    pub fn temperature_label(self) -> &'static str {
+        match self {
+            Units::Metric => "C",
+            Units::Imperial => "F",
+            Units::Si => "K",
+        }
+    }
+
+    pub fn speed_label(self) -> &'static str {
+        match self {
+            Units::Metric => "kph",
+            Units::Imperial => "mph",
+            Units::Si => "m/s",
+        }
+    }
+
+    pub fn precipitation_label(self) -> &'static str {
+        match self {
+            Units::Metric | Units::Si => "mm",
+            Units::Imperial => "in",
+        }
+    }
+}
+
+/// How much of a forecast to include alongside current conditions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ForecastOptions {
+    /// how many days of forecast to include, starting with today. Sources provide as many as they can,
+    /// up to this many (wttr.in provides 3)
+    pub days: u8,
+    /// whether to include an hour by hour breakdown of each day (as fine grained as the source provides)
+    pub hourly: bool,
+}
+
+/// What a `CityDataSource` is being asked about. At minimum this is the city name the user asked for,
+/// but as the dispatcher hears back from sources it can fill in more detail (like where the city actually
+/// is) so that later sources all describe the same place
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CityQuery {
+    /// the city as the user asked for it
+    pub city: String,
+    /// only match cities in this country, as an ISO 3166-1 alpha-2 code (e.g. "us")
+    pub country_code: Option<String>,
+    /// only match cities in this state (or province, region, ...), compared case-insensitively
+    pub state: Option<String>,
+    /// where the city is, if it has already been geocoded. Sources that can look up by location should
+    /// prefer this over `city`, as names like "San Jose" are ambiguous
+    pub coordinates: Option<Coordinates>,
+    /// what the place the city was geocoded to is called, which can differ from `city` when the user
+    /// misspelled it or used another of its names (e.g. "Bombay" for Mumbai)
+    pub place_name: Option<String>,
+    /// the IANA timezone the place the city was geocoded to is in (e.g. "America/Chicago"), if the geocoder
+    /// knows
+    pub timezone: Option<String>,
+    /// for sources that provide forecasts, how much of one to include
+    pub forecast: ForecastOptions,
+    /// which units measurements should be reported in
+    pub units: Units,
+}
+
+impl CityQuery {
+    pub fn new(city: impl Into<String>) -> Self {
+        Self {
+            city: city.into(),
+            ..Self::default()
+        }
+    }
+
+    #[must_use]
+    pub fn with_country_code(mut self, country_code: impl Into<String>) -> Self {
+        self.country_code = Some(country_code.into());
+        self
+    }
+
+    #[must_use]
+    pub fn with_state(mut self, state: impl Into<String>) -> Self {
+        self.state = Some(state.into());
+        self
+    }
+
+    #[must_use]
+    pub fn with_forecast(mut self, forecast: ForecastOptions) -> Self {
+        self.forecast = forecast;
+        self
+    }
+
+    #[must_use]
+    pub fn with_units(mut self, units: Units) -> Self {
+        self.units = units;
+        self
+    }
+
+    #[must_use]
+    pub fn with_coordinates(mut self, coordinates: Coordinates) -> Self {
+        self.coordinates = Some(coordinates);
+        self
+    }
+
+    /// A key identifying the place this query is about, so differently formatted requests for the same place
+    /// compare equal (see `normalize_city_name`). Used for caching and coalescing requests to sources that
+    /// only care about where the city is, see `CityDataSource::request_key`
+    pub fn key(&self) -> String {
+        let mut key = normalize_city_name(&self.city);
+
+        if let Some(state) = &self.state {
+            key = format!("{key}, {}", normalize_city_name(state));
+        }
+        if let Some(country_code) = &self.country_code {
+            key = format!("{key}, {}", country_code.trim().to_lowercase());
+        }
+        if let Some(coordinates) = self.coordinates {
+            key = format!("{key}@{coordinates}");
+        }
+
+        key
+    }
+
+    /// Like `key`, but also telling apart queries for different amounts of forecast or different units, for
+    /// weather sources
+    pub fn weather_key(&self) -> String {
+        let mut key = self.key();
+
+        if self.forecast.days > 0 {
+            let hourly = if self.forecast.hourly { " hourly" } else { "" };
+            key = format!("{key} +{}d{hourly}", self.forecast.days);
+        }
+        if self.units != Units::Metric {
+            key = format!("{key} in {:?}", self.units);
+        }
+
+        key
+    }
+
+    /// Check that the query makes sense before any source is asked about it. Free text like `city` is
+    /// escaped by each source as it builds its request, but a country code has to actually be one
+    ///
+    /// # Errors
+    /// If `country_code` is set but isn't two ASCII letters
+    pub fn validate(&self) -> CityDataResult<()> {
+        match &self.country_code {
+            Some(country_code) if !is_country_code(country_code.trim()) => {
+                Err(CityDataError::InvalidQuery(format!(
+                    "country code {country_code:?} should be two letters, e.g. \"us\""
+                )))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Fill in anything we can learn about the city from another source's result, e.g. a geocoded place
+    /// gives us coordinates and the country it's in. Anything already set is kept
+    ///
+    /// Note: the geocoded state isn't kept, as sources take a state to mean the user narrowed their search
+    /// (e.g. Wikipedia looks up "Paris, Île-de-France" rather than "Paris")
+    pub fn enrich_from(&mut self, data: &CityData) {
+        if let CityData::Place(place) = data {
+            self.coordinates.get_or_insert(place.coordinates());
+            self.place_name.get_or_insert_with(|| place.name.clone());
+            if self.country_code.is_none() {
+                self.country_code.clone_from(&place.country_code);
+            }
+            if self.timezone.is_none() {
+                self.timezone.clone_from(&place.timezone);
+            }
+        }
+    }
+}
+
+/// Whether `code` looks like an ISO 3166-1 alpha-2 country code. We don't check it's actually assigned,
+/// an unknown country just doesn't match anything
+fn is_country_code(code: &str) -> bool {
+    code.len() == 2 && code.bytes().all(|byte| byte.is_ascii_alphabetic())
+}
+
+impl From<String> for CityQuery {
+    fn from(city: String) -> Self {
+        Self::new(city)
+    }
+}
+
+impl From<&str> for CityQuery {
+    fn from(city: &str) -> Self {
+        Self::new(city)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CityData, CityDataError, PlaceRecord};
+
+    use super::{CityQuery, Coordinates, ForecastOptions, Units};
+
+    #[test]
+    fn test_key() {
+        assert_eq!(CityQuery::new(" San  JOSE ").key(), "san jose");
+
+        let located = CityQuery::new("San Jose").with_coordinates(Coordinates {
+            latitude: 37.336_166_3,
+            longitude: -121.890_591,
+        });
+        assert_eq!(located.key(), "san jose@37.3362,-121.8906");
+
+        let narrowed = CityQuery::new("Springfield")
+            .with_state("Illinois")
+            .with_country_code("US");
+        assert_eq!(narrowed.key(), "springfield, illinois, us");
+
+        let forecast = CityQuery::new("Springfield").with_forecast(ForecastOptions {
+            days: 2,
+            hourly: true,
+        });
+        assert_eq!(forecast.weather_key(), "springfield +2d hourly");
+        // only weather depends on the forecast, everything else can share answers with plain queries
+        assert_eq!(forecast.key(), "springfield");
+
+        let imperial = CityQuery::new("Springfield").with_units(Units::Imperial);
+        assert_eq!(imperial.weather_key(), "springfield in Imperial");
+        assert_eq!(imperial.key(), "springfield");
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(CityQuery::new("Paris").validate().is_ok());
+        assert!(CityQuery::new("Paris")
+            .with_country_code(" FR ")
+            .validate()
+            .is_ok());
+
+        for country_code in ["fra", "f", "us&limit=50", "u%"] {
+            assert!(
+                matches!(
+                    CityQuery::new("Paris")
+                        .with_country_code(country_code)
+                        .validate(),
+                    Err(CityDataError::InvalidQuery(_))
+                ),
+                "expected {country_code:?} to be rejected"
+            );
+        }
+    }
+
+    #[test]
+    fn test_enrich_from_place() {
+        let mut query = CityQuery::new("San Jose");
+        query.enrich_from(&CityData::Place(PlaceRecord {
+            name: String::from("San José"),
+            display_name: String::from("San José, Santa Clara County, California, United States"),
+            latitude: 37.3,
+            longitude: -121.9,
+            ..PlaceRecord::default()
+        }));
+
+        assert_eq!(
+            query.coordinates,
+            Some(Coordinates {
+                latitude: 37.3,
+                longitude: -121.9
+            })
+        );
+        assert_eq!(query.place_name.as_deref(), Some("San José"));
+        assert_eq!(query.country_code, None);
+
+        // a user's narrowing is kept over what the geocoder found
+        let mut query = CityQuery::new("Nashville").with_country_code("US");
+        query.enrich_from(&CityData::Place(PlaceRecord {
+            name: String::from("Nashville"),
+            country_code: Some(String::from("us")),
+            timezone: Some(String::from("America/Chicago")),
+            ..PlaceRecord::default()
+        }));
+        assert_eq!(query.country_code.as_deref(), Some("US"));
+        assert_eq!(query.timezone.as_deref(), Some("America/Chicago"));
+
+        let mut query = CityQuery::new("Nashville");
+        query.enrich_from(&CityData::Place(PlaceRecord {
+            country_code: Some(String::from("us")),
+            ..PlaceRecord::default()
+        }));
+        assert_eq!(query.country_code.as_deref(), Some("us"));
+    }
+
+    #[test]
+    fn test_distance() {
+        let paris = Coordinates {
+            latitude: 48.856_6,
+            longitude: 2.352_2,
+        };
+        let london = Coordinates {
+            latitude: 51.507_2,
+            longitude: -0.127_6,
+        };
+        assert_eq!(paris.distance_km(paris), 0.0);
+        assert!((paris.distance_km(london) - 344.0).abs() < 1.0);
+    }
+}
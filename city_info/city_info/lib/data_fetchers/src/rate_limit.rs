@@ -0,0 +1,337 @@
+use std::{
+    sync::{Mutex, PoisonError},
+    time::Duration,
+};
+
+use thiserror::Error;
+use tokio::time::Instant;
+
+use crate::{CityData, CityDataError, CityDataResult, CityDataSource, CityQuery};
+
+/// A `RateLimitConfig` that could never let a request through
+#[derive(Debug, Error)]
+#[error("requests_per_second must be a positive number, not {0}")]
+pub struct InvalidRateLimit(pub f64);
+
+/// How a `RateLimited` data source should throttle its requests
+#[derive(Clone, Debug, PartialEq)]
+pub struct RateLimitConfig {
+    /// the sustained number of requests allowed per second
+    pub requests_per_second: f64,
+    /// how many requests can be made back to back before the rate kicks in
+    pub burst: u32,
+    /// the longest a request will queue for a token before giving up with `CityDataError::RateLimited`
+    pub max_wait: Duration,
+}
+
+impl RateLimitConfig {
+    /// Allow `requests_per_second` with no bursting, queueing requests for up to 5 seconds
+    pub fn per_second(requests_per_second: f64) -> Self {
+        Self {
+            requests_per_second,
+            burst: 1,
+            max_wait: Duration::from_secs(5),
+        }
+    }
+
+    /// The nominatim usage policy allows an absolute maximum of 1 request per second
+    /// <https://operations.osmfoundation.org/policies/nominatim/>
+    pub fn nominatim() -> Self {
+        Self::per_second(1.0)
+    }
+
+    /// # Errors
+    /// If `requests_per_second` isn't a positive number, as then no request would ever get a token
+    pub fn validate(&self) -> Result<(), InvalidRateLimit> {
+        let rate = self.requests_per_second;
+        if !rate.is_finite() || rate <= 0.0 {
+            return Err(InvalidRateLimit(rate));
+        }
+
+        Ok(())
+    }
+}
+
+/// A classic token bucket: it holds up to `burst` tokens, refills at `requests_per_second`, and every
+/// request takes one token.
+///
+/// Note: `tokens` is allowed to go negative. A request that has to wait "reserves" its token up front,
+/// so the next request in line sees an even emptier bucket and waits that much longer. This gives us
+/// first-come-first-served queueing without having to keep an actual queue around
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+    // turns that were reserved by requests which were dropped before their turn came, see `Reservation`
+    abandoned: Vec<Instant>,
+}
+
+/// A request's reserved turn to use its token. If the request is dropped before its turn comes round
+/// (e.g. because its deadline passed) the turn is handed to the next request that comes along, rather than
+/// being left as a gap which holds up everyone queued behind it
+///
+/// Note: we can't simply put the token back in the bucket, as the requests queued behind this one have
+/// already worked out their turns assuming it was taken. Giving the same turn to someone else keeps requests
+/// spaced out just as they would have been
+struct Reservation<'a> {
+    bucket: &'a Mutex<TokenBucket>,
+    turn: Instant,
+    used: bool,
+}
+
+impl Reservation<'_> {
+    /// How long until our turn
+    fn wait(&self) -> Duration {
+        self.turn.saturating_duration_since(Instant::now())
+    }
+
+    /// Wait for our turn, after which the token is ours to use
+    async fn take(mut self) {
+        tokio::time::sleep_until(self.turn).await;
+        self.used = true;
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if !self.used && self.turn > Instant::now() {
+            lock(self.bucket).abandoned.push(self.turn);
+        }
+    }
+}
+
+/// The bucket is only ever locked briefly and never across an `.await`, so a panic can't leave it half
+/// updated and a poisoned lock is safe to keep using
+fn lock(bucket: &Mutex<TokenBucket>) -> std::sync::MutexGuard<'_, TokenBucket> {
+    bucket.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// A rate limiting decorator for any `CityDataSource`. Requests beyond the configured rate queue for a
+/// token, and if they would have to wait longer than `max_wait` they fail immediately without ever
+/// reaching the wrapped data source
+pub struct RateLimited<T>
+where
+    T: CityDataSource,
+{
+    inner: T,
+    config: RateLimitConfig,
+    // abandoned turns are given back from `Reservation::drop`, which can't `.await` a tokio Mutex
+    bucket: Mutex<TokenBucket>,
+}
+
+impl<T> RateLimited<T>
+where
+    T: CityDataSource,
+{
+    /// # Errors
+    /// If `config` is invalid, see `RateLimitConfig::validate`
+    pub fn new(inner: T, config: RateLimitConfig) -> Result<Self, InvalidRateLimit> {
+        config.validate()?;
+        let bucket = TokenBucket {
+            tokens: f64::from(config.burst.max(1)),
+            last_refill: Instant::now(),
+            abandoned: Vec::new(),
+        };
+
+        Ok(Self {
+            inner,
+            config,
+            bucket: Mutex::new(bucket),
+        })
+    }
+
+    /// Take a token from the bucket, returning the caller's turn to use it
+    fn reserve(&self) -> CityDataResult<Reservation<'_>> {
+        let mut bucket = lock(&self.bucket);
+        let rate = self.config.requests_per_second;
+        let capacity = f64::from(self.config.burst.max(1));
+
+        // top the bucket up for the time that has passed since we last looked
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(capacity);
+        bucket.last_refill = now;
+
+        let reservation = |turn| Reservation {
+            bucket: &self.bucket,
+            turn,
+            used: false,
+        };
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(reservation(now));
+        }
+
+        // someone else's abandoned turn is as good as a fresh token, as long as it hasn't already gone by
+        bucket.abandoned.retain(|turn| *turn > now);
+        if let Some(earliest) = bucket.abandoned.iter().min().copied() {
+            bucket.abandoned.retain(|turn| *turn != earliest);
+            return Ok(reservation(earliest));
+        }
+
+        let wait = Duration::from_secs_f64((1.0 - bucket.tokens) / rate);
+        if wait > self.config.max_wait {
+            return Err(CityDataError::RateLimited {
+                retry_after: Some(wait),
+            });
+        }
+
+        bucket.tokens -= 1.0;
+        Ok(reservation(now + wait))
+    }
+}
+
+impl<T> CityDataSource for RateLimited<T>
+where
+    T: CityDataSource,
+{
+    async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+        let reservation = self.reserve().inspect_err(|_| {
+            tracing::warn!(
+                "Rate limit exceeded, rejecting request for {:?}",
+                query.city
+            )
+        })?;
+
+        let wait = reservation.wait();
+        if !wait.is_zero() {
+            tracing::debug!(
+                "Rate limiting request for {:?}, waiting {wait:?}",
+                query.city
+            );
+        }
+        reservation.take().await;
+
+        self.inner.fetch_data(query).await
+    }
+
+    /// Health checks reach the upstream too, so they take a token like any other request. If we're too busy
+    /// to give them one the check fails, as a request made now would too
+    async fn health_check(&self) -> Option<CityDataResult<()>> {
+        match self.reserve() {
+            Ok(reservation) => {
+                reservation.take().await;
+                self.inner.health_check().await
+            }
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    fn request_key(&self, query: &CityQuery) -> String {
+        self.inner.request_key(query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::Ordering, time::Duration};
+
+    use futures::future::join_all;
+    use tokio::time::Instant;
+
+    use crate::{test_utils::CountingDataSource, CityDataError, CityDataSource, CityQuery};
+
+    use super::{RateLimitConfig, RateLimited};
+
+    #[tokio::test(start_paused = true)]
+    async fn test_requests_are_spaced_out() {
+        let source = CountingDataSource::default();
+        let limited = RateLimited::new(source.clone(), RateLimitConfig::nominatim())
+            .expect("expected a valid config");
+        let start = Instant::now();
+
+        // three requests at once should be spread over ~2 seconds
+        let results =
+            join_all((0..3).map(|i| limited.fetch_data(CityQuery::new(format!("Queue Town {i}")))))
+                .await;
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(source.calls.load(Ordering::SeqCst), 3);
+        assert!(start.elapsed() >= Duration::from_secs(2));
+        assert!(start.elapsed() < Duration::from_secs(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_burst() {
+        let source = CountingDataSource::default();
+        let config = RateLimitConfig {
+            burst: 3,
+            ..RateLimitConfig::per_second(1.0)
+        };
+        let limited = RateLimited::new(source.clone(), config).expect("expected a valid config");
+        let start = Instant::now();
+
+        // the first 3 requests go straight through
+        for i in 0..3 {
+            limited
+                .fetch_data(CityQuery::new(format!("Burst City {i}")))
+                .await
+                .unwrap();
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_rejects_beyond_max_wait() {
+        let source = CountingDataSource::default();
+        let config = RateLimitConfig {
+            max_wait: Duration::from_millis(1500),
+            ..RateLimitConfig::nominatim()
+        };
+        let limited = RateLimited::new(source.clone(), config).expect("expected a valid config");
+
+        // the first request goes straight through and the second queues for a second, but a third would
+        // need to wait 2 seconds which is beyond our limit
+        let results =
+            join_all((0..3).map(|i| limited.fetch_data(CityQuery::new(format!("Busy Burg {i}")))))
+                .await;
+        assert!(results[0].is_ok());
+        assert!(results[1].is_ok());
+        assert!(matches!(
+            results[2],
+            Err(CityDataError::RateLimited {
+                retry_after: Some(_)
+            })
+        ));
+        // the rejected request never reached the data source
+        assert_eq!(source.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_abandoned_turns_reused() {
+        let source = CountingDataSource::default();
+        let limited = RateLimited::new(source.clone(), RateLimitConfig::nominatim())
+            .expect("expected a valid config");
+        let start = Instant::now();
+
+        limited
+            .fetch_data(CityQuery::new("First Falls"))
+            .await
+            .unwrap();
+        // this one's turn would be a second from now, but its requester gives up before then
+        let abandoned = tokio::time::timeout(
+            Duration::from_millis(500),
+            limited.fetch_data(CityQuery::new("Impatient Isle")),
+        )
+        .await;
+        assert!(abandoned.is_err());
+
+        // so the next request gets that turn, rather than queueing behind it
+        limited
+            .fetch_data(CityQuery::new("Patient Point"))
+            .await
+            .unwrap();
+        assert_eq!(start.elapsed(), Duration::from_secs(1));
+        assert_eq!(source.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_invalid_rate() {
+        for requests_per_second in [0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let config = RateLimitConfig::per_second(requests_per_second);
+            assert!(
+                RateLimited::new(CountingDataSource::default(), config).is_err(),
+                "expected {requests_per_second} to be rejected"
+            );
+        }
+    }
+}
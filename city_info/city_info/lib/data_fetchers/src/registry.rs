@@ -0,0 +1,255 @@
+use std::sync::Arc;
+
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    gazetteer::Gazetteer, spawn_data_source_task, timezone_boundaries::TimezoneBoundaries,
+    CityDataSourceHandle, DynCityDataSource, SpawnError, SpawnResult, DEFAULT_MAX_IN_FLIGHT,
+};
+
+#[derive(Debug, Error)]
+pub enum RegistryError {
+    #[error("Unknown data source {name:?}, expected one of: {}", available.join(", "))]
+    UnknownSource {
+        name: String,
+        available: Vec<String>,
+    },
+    #[error("Data source {name:?} is listed more than once")]
+    DuplicateSource { name: String },
+    #[error("Failed to start data source {name:?}: {source}")]
+    SpawnFailed { name: String, source: SpawnError },
+}
+
+/// What a `SourceFactory` is given to build its source with, these are shared by every source
+pub struct SourceContext {
+    /// the http client to make any requests with, so every source shares one connection pool
+    pub http_client: reqwest::Client,
+    /// the local index of city names, for sources that can answer offline
+    pub gazetteer: Arc<Gazetteer>,
+    /// the areas each timezone covers, if they were configured
+    pub timezone_boundaries: Option<Arc<TimezoneBoundaries>>,
+    /// cancelled when the source's task should shut down
+    pub cancellation_token: CancellationToken,
+}
+
+/// Builds and spawns a data source, returning a handle to its task
+pub type SourceFactory =
+    Box<dyn Fn(&SourceContext) -> SpawnResult<CityDataSourceHandle> + Send + Sync>;
+
+/// Maps data source names to the factories that start them, so which sources are asked about a city (and
+/// in what order) can come from configuration, see `SourceRegistry::spawn`
+///
+/// Sources don't have to live in this crate, anything that can build a `CityDataSource` can be registered
+/// (see `register_data_source`), or swap out one of the built in sources by registering under its name
+#[derive(Default)]
+pub struct SourceRegistry {
+    // in registration order
+    factories: Vec<(String, SourceFactory)>,
+}
+
+impl SourceRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register `factory` under `name`. Registering a name that's already taken replaces its factory, so
+    /// built in sources can be swapped out
+    pub fn register<F>(&mut self, name: impl Into<String>, factory: F)
+    where
+        F: Fn(&SourceContext) -> SpawnResult<CityDataSourceHandle> + Send + Sync + 'static,
+    {
+        let name = name.into();
+        let factory: SourceFactory = Box::new(factory);
+
+        match self
+            .factories
+            .iter_mut()
+            .find(|(existing, _)| *existing == name)
+        {
+            Some((_, existing_factory)) => *existing_factory = factory,
+            None => self.factories.push((name, factory)),
+        }
+    }
+
+    /// Register a plain data source under `name`, running it in its own task with the default limit on
+    /// requests in flight. `factory` is called once, when the source is spawned
+    pub fn register_data_source<F>(&mut self, name: impl Into<String>, factory: F)
+    where
+        F: Fn(&SourceContext) -> Box<dyn DynCityDataSource> + Send + Sync + 'static,
+    {
+        let name = name.into();
+        let span_name = name.clone();
+        self.register(name, move |context| {
+            let _span = tracing::info_span!("Fetcher", source = span_name.as_str()).entered();
+
+            spawn_data_source_task(
+                factory(context),
+                DEFAULT_MAX_IN_FLIGHT,
+                context.cancellation_token.clone(),
+            )
+        });
+    }
+
+    /// Whether there's a source registered as `name`
+    pub fn contains(&self, name: &str) -> bool {
+        self.factories.iter().any(|(existing, _)| existing == name)
+    }
+
+    /// Every registered name, in the order they were registered
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.factories.iter().map(|(name, _)| name.as_str())
+    }
+
+    /// Spawn the sources called `names`, returning their handles in the same order. Sources that aren't
+    /// named aren't started at all
+    ///
+    /// # Errors
+    /// If a name isn't registered or is listed twice, or a source fails to start. Every name is checked
+    /// before any source is started
+    pub fn spawn(
+        &self,
+        names: &[String],
+        context: &SourceContext,
+    ) -> Result<Vec<CityDataSourceHandle>, RegistryError> {
+        let mut factories = Vec::with_capacity(names.len());
+        for (index, name) in names.iter().enumerate() {
+            if names[..index].contains(name) {
+                return Err(RegistryError::DuplicateSource { name: name.clone() });
+            }
+            let Some((_, factory)) = self.factories.iter().find(|(existing, _)| existing == name)
+            else {
+                return Err(RegistryError::UnknownSource {
+                    name: name.clone(),
+                    available: self.names().map(String::from).collect(),
+                });
+            };
+            factories.push((name, factory));
+        }
+
+        factories
+            .into_iter()
+            .map(|(name, factory)| {
+                factory(context).map_err(|source| RegistryError::SpawnFailed {
+                    name: name.clone(),
+                    source,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tokio_util::sync::CancellationToken;
+
+    use crate::{
+        gazetteer::Gazetteer, sun::SunDataSource, timezone::TimezoneDataSource, CityData,
+        CityDataResult, CityDataSource, CityQuery, Coordinates, DynCityDataSource, SpawnError,
+    };
+
+    use super::{RegistryError, SourceContext, SourceRegistry};
+
+    /// A third party source, as far as the registry is concerned
+    struct EchoDataSource;
+
+    impl CityDataSource for EchoDataSource {
+        async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+            Err(crate::CityDataError::NotFound(query.city))
+        }
+    }
+
+    fn make_context() -> SourceContext {
+        SourceContext {
+            http_client: reqwest::Client::new(),
+            gazetteer: Arc::new(Gazetteer::embedded()),
+            timezone_boundaries: None,
+            cancellation_token: CancellationToken::new(),
+        }
+    }
+
+    fn make_registry() -> SourceRegistry {
+        let mut registry = SourceRegistry::new();
+        registry.register_data_source("sun", |_| Box::new(SunDataSource::new()));
+        registry.register_data_source("timezone", |_| Box::new(TimezoneDataSource::new()));
+        registry.register_data_source("echo", |_| Box::new(EchoDataSource));
+
+        registry
+    }
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|&name| String::from(name)).collect()
+    }
+
+    #[tokio::test]
+    async fn test_spawn_in_order() {
+        let registry = make_registry();
+        assert_eq!(
+            registry.names().collect::<Vec<_>>(),
+            vec!["sun", "timezone", "echo"]
+        );
+
+        // only the named sources are started, in the order asked for
+        let handles = registry
+            .spawn(&names(&["timezone", "sun"]), &make_context())
+            .expect("expected the sources to start");
+        assert_eq!(handles.len(), 2);
+
+        let query = CityQuery::new("Tokyo").with_coordinates(Coordinates {
+            latitude: 35.689_5,
+            longitude: 139.691_7,
+        });
+        let first = handles[0].request_data(query.clone(), None).await;
+        assert!(matches!(first, Ok(CityData::LocalTime(_))));
+        let second = handles[1].request_data(query, None).await;
+        assert!(matches!(second, Ok(CityData::Sun(_))));
+    }
+
+    #[tokio::test]
+    async fn test_register_replaces() {
+        let mut registry = make_registry();
+        registry.register("sun", |_| Err(SpawnError::NoRuntime));
+
+        // the replacement keeps its place
+        assert_eq!(
+            registry.names().collect::<Vec<_>>(),
+            vec!["sun", "timezone", "echo"]
+        );
+        let result = registry.spawn(&names(&["sun"]), &make_context());
+        assert!(matches!(
+            result,
+            Err(RegistryError::SpawnFailed { name, source: SpawnError::NoRuntime }) if name == "sun"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_bad_names() {
+        let registry = make_registry();
+
+        let result = registry.spawn(&names(&["sun", "moon"]), &make_context());
+        let Err(error @ RegistryError::UnknownSource { .. }) = result else {
+            panic!("Expected an unknown source error");
+        };
+        assert_eq!(
+            error.to_string(),
+            "Unknown data source \"moon\", expected one of: sun, timezone, echo"
+        );
+
+        let result = registry.spawn(&names(&["sun", "echo", "sun"]), &make_context());
+        assert!(matches!(result, Err(RegistryError::DuplicateSource { name }) if name == "sun"));
+    }
+
+    #[tokio::test]
+    async fn test_dyn_data_source() {
+        // different sources behind the same type
+        let sources: Vec<Box<dyn DynCityDataSource>> =
+            vec![Box::new(EchoDataSource), Box::new(SunDataSource::new())];
+
+        let result = sources[0].fetch_data(CityQuery::new("Nowhere")).await;
+        assert!(matches!(result, Err(crate::CityDataError::NotFound(_))));
+        let result = sources[1].fetch_data(CityQuery::new("Nowhere")).await;
+        assert!(matches!(result, Err(crate::CityDataError::NotGeocoded(_))));
+    }
+}
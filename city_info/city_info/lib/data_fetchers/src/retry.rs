@@ -0,0 +1,348 @@
+use std::time::Duration;
+
+use prometheus::IntCounter;
+use rand::Rng;
+
+use crate::{
+    metrics::retries_counter, CityData, CityDataError, CityDataResult, CityDataSource, CityQuery,
+};
+
+/// How (and how hard) a `Retrying` data source retries requests that fail for transient reasons:
+/// connection errors, timeouts, 5xx responses and 429s. Anything else (a 404, a response we can't parse,
+/// ...) is assumed to fail the same way every time and is returned immediately
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// the total number of attempts, including the first. 1 disables retries
+    pub max_attempts: u32,
+    /// the backoff before the first retry, doubling for each retry after that
+    pub initial_backoff: Duration,
+    /// the largest backoff we'll wait between attempts. A 429 asking us to wait longer than this fails
+    /// straight away
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Don't retry at all
+    pub fn none() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Self::default()
+        }
+    }
+
+    /// The backoff before retry number `retry` (starting from 0), using "equal jitter": half the
+    /// exponential backoff, plus a random duration up to the other half. Jitter keeps a crowd of clients
+    /// that all failed at the same moment from all retrying at the same moment too, and keeping half of the
+    /// backoff fixed means we never retry straight away.
+    /// See <https://aws.amazon.com/blogs/architecture/exponential-backoff-and-jitter/>
+    fn backoff(&self, retry: u32) -> Duration {
+        let exponential = self
+            .initial_backoff
+            .saturating_mul(2_u32.saturating_pow(retry))
+            .min(self.max_backoff);
+        let half = exponential / 2;
+
+        half + rand::thread_rng().gen_range(Duration::ZERO..=exponential - half)
+    }
+}
+
+/// A retrying decorator for any `CityDataSource`, requests which fail for transient reasons (see
+/// `CityDataError::is_transient`) are tried again according to a `RetryPolicy`
+///
+/// Note: this wraps a `RateLimited` source rather than the other way around (see `spawn_fetcher_task`), so
+/// every attempt waits for its own token, and retrying can never take us over an upstream's rate limit
+pub struct Retrying<T>
+where
+    T: CityDataSource,
+{
+    inner: T,
+    retry_policy: RetryPolicy,
+    retries: Option<IntCounter>,
+}
+
+impl<T> Retrying<T>
+where
+    T: CityDataSource,
+{
+    pub fn new(inner: T, retry_policy: RetryPolicy) -> Self {
+        Self {
+            inner,
+            retry_policy,
+            retries: None,
+        }
+    }
+
+    /// Count our retries in the `city_info_upstream_retries_total` metric, labelled with `upstream`
+    #[must_use]
+    pub fn with_metrics(mut self, upstream: &str) -> Self {
+        self.retries = Some(retries_counter(upstream));
+        self
+    }
+}
+
+impl<T> CityDataSource for Retrying<T>
+where
+    T: CityDataSource,
+{
+    /// Returns the first success, or the error from the last attempt
+    async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+        let mut retry = 0;
+
+        loop {
+            let error = match self.inner.fetch_data(query.clone()).await {
+                Ok(data) => return Ok(data),
+                Err(error) if !error.is_transient() => return Err(error),
+                Err(error) => error,
+            };
+
+            if retry + 1 >= self.retry_policy.max_attempts {
+                tracing::warn!(
+                    "Giving up on {:?} after {} attempts: {error}",
+                    query.city,
+                    retry + 1
+                );
+                return Err(error);
+            }
+
+            // if the upstream told us how long to wait, do as we're told (unless it's unreasonably long)
+            let backoff = match error {
+                CityDataError::RateLimited {
+                    retry_after: Some(retry_after),
+                } if retry_after > self.retry_policy.max_backoff => {
+                    tracing::warn!(
+                        "Asked to retry {:?} after {retry_after:?}, giving up",
+                        query.city
+                    );
+                    return Err(error);
+                }
+                CityDataError::RateLimited {
+                    retry_after: Some(retry_after),
+                } => retry_after,
+                _ => self.retry_policy.backoff(retry),
+            };
+
+            tracing::info!(
+                "Request for {:?} failed with: {error}, retrying in {backoff:?}",
+                query.city
+            );
+            if let Some(retries) = &self.retries {
+                retries.inc();
+            }
+            tokio::time::sleep(backoff).await;
+            retry += 1;
+        }
+    }
+
+    /// Health checks aren't retried, a check that only passes on the third attempt isn't a pass
+    async fn health_check(&self) -> Option<CityDataResult<()>> {
+        self.inner.health_check().await
+    }
+
+    fn request_key(&self, query: &CityQuery) -> String {
+        self.inner.request_key(query)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use axum::{extract::State, http::StatusCode, response::AppendHeaders, routing::get, Router};
+    use tokio::time::Instant;
+
+    use crate::{
+        http,
+        metrics::retries_counter,
+        rate_limit::{RateLimitConfig, RateLimited},
+        test_utils::spawn_fixture_server,
+        CityData, CityDataError, CityDataResult, CityDataSource, CityQuery, PlaceRecord,
+    };
+
+    use super::{RetryPolicy, Retrying};
+
+    fn fast_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Duration::from_millis(1),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+
+    /// A data source which GETs `url` for every request
+    struct GetDataSource {
+        url: String,
+    }
+
+    impl CityDataSource for GetDataSource {
+        async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+            http::get(&reqwest::Client::new(), &self.url).await?;
+            Ok(CityData::Place(PlaceRecord {
+                name: query.city.clone(),
+                display_name: query.city,
+                ..PlaceRecord::default()
+            }))
+        }
+    }
+
+    /// Serve a route which fails with `status` (and `headers`) for the first `failures` requests, then
+    /// succeeds. Returns a source which requests it and a count of requests served
+    async fn flaky_server(
+        status: StatusCode,
+        headers: Vec<(&'static str, &'static str)>,
+        failures: usize,
+    ) -> (Retrying<GetDataSource>, Arc<AtomicUsize>) {
+        let requests = Arc::new(AtomicUsize::new(0));
+        let router = Router::new()
+            .route(
+                "/flaky",
+                get(move |State(requests): State<Arc<AtomicUsize>>| {
+                    let headers = headers.clone();
+                    async move {
+                        if requests.fetch_add(1, Ordering::SeqCst) < failures {
+                            (status, AppendHeaders(headers), "nope")
+                        } else {
+                            (StatusCode::OK, AppendHeaders(Vec::new()), "ok")
+                        }
+                    }
+                }),
+            )
+            .with_state(requests.clone());
+
+        let base_url = spawn_fixture_server(router).await;
+        let source = GetDataSource {
+            url: format!("{base_url}/flaky"),
+        };
+        (Retrying::new(source, fast_policy()), requests)
+    }
+
+    #[tokio::test]
+    async fn test_retries_server_errors() {
+        let (source, requests) = flaky_server(StatusCode::SERVICE_UNAVAILABLE, Vec::new(), 2).await;
+        let source = source.with_metrics("retry_test_upstream");
+        let retries = retries_counter("retry_test_upstream");
+        let before = retries.get();
+
+        source
+            .fetch_data(CityQuery::new("Flaky Falls"))
+            .await
+            .expect("Expected the third attempt to succeed");
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+        assert_eq!(retries.get() - before, 2);
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_max_attempts() {
+        let (source, requests) = flaky_server(StatusCode::BAD_GATEWAY, Vec::new(), 5).await;
+
+        assert!(source
+            .fetch_data(CityQuery::new("Flaky Falls"))
+            .await
+            .is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_does_not_retry_client_errors() {
+        let (source, requests) = flaky_server(StatusCode::NOT_FOUND, Vec::new(), 1).await;
+
+        assert!(source
+            .fetch_data(CityQuery::new("Flaky Falls"))
+            .await
+            .is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_honors_retry_after() {
+        let (source, requests) =
+            flaky_server(StatusCode::TOO_MANY_REQUESTS, vec![("retry-after", "1")], 1).await;
+        let start = Instant::now();
+
+        source
+            .fetch_data(CityQuery::new("Flaky Falls"))
+            .await
+            .expect("Expected the retry to succeed");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+        assert!(start.elapsed() >= Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn test_retry_after_too_long() {
+        let (source, requests) = flaky_server(
+            StatusCode::TOO_MANY_REQUESTS,
+            vec![("retry-after", "3600")],
+            1,
+        )
+        .await;
+
+        let result = source.fetch_data(CityQuery::new("Flaky Falls")).await;
+        assert!(matches!(
+            result,
+            Err(CityDataError::RateLimited {
+                retry_after: Some(retry_after)
+            }) if retry_after == Duration::from_secs(3600)
+        ));
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_backoff_has_a_floor() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(200),
+            ..RetryPolicy::default()
+        };
+
+        for _ in 0..100 {
+            let backoff = policy.backoff(2);
+            assert!(backoff >= Duration::from_millis(400), "{backoff:?}");
+            assert!(backoff <= Duration::from_millis(800), "{backoff:?}");
+        }
+    }
+
+    /// Fails every request as if the upstream were overloaded, counting the attempts
+    #[derive(Default)]
+    struct OverloadedDataSource {
+        attempts: AtomicUsize,
+    }
+
+    impl CityDataSource for OverloadedDataSource {
+        async fn fetch_data(&self, _query: CityQuery) -> CityDataResult<CityData> {
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+            Err(CityDataError::UpstreamStatus { status: 503 })
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_retries_are_rate_limited() {
+        let rate_limited = RateLimited::new(
+            OverloadedDataSource::default(),
+            RateLimitConfig::nominatim(),
+        )
+        .expect("expected a valid config");
+        let source = Retrying::new(rate_limited, fast_policy());
+        let start = Instant::now();
+
+        assert!(source
+            .fetch_data(CityQuery::new("Overloaded Oaks"))
+            .await
+            .is_err());
+        // every attempt waited for a token of its own, despite our tiny backoff
+        assert!(start.elapsed() >= Duration::from_secs(2));
+    }
+}
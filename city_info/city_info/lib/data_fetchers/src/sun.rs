@@ -0,0 +1,379 @@
+use std::{fmt::Display, time::SystemTime};
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    spawn_data_source_task, CityData, CityDataError, CityDataResult, CityDataSource,
+    CityDataSourceHandle, CityQuery, Coordinates, SpawnResult, DEFAULT_MAX_IN_FLIGHT,
+};
+
+// The Julian day at noon on 2000-01-01, the epoch the solar position formulas below are written against
+const J2000: f64 = 2_451_545.0;
+const J2000_DATE: NaiveDate = match NaiveDate::from_ymd_opt(2000, 1, 1) {
+    Some(date) => date,
+    None => panic!("2000-01-01 is a valid date"),
+};
+
+// The Julian day at the unix epoch
+const UNIX_EPOCH_JULIAN_DAY: f64 = 2_440_587.5;
+// The tilt of the earth's axis, in degrees
+const OBLIQUITY: f64 = 23.4397;
+
+/// Where the centre of the sun is (in degrees, relative to the horizon) at sunrise and sunset. It's not 0
+/// as the atmosphere bends the light of a sun that's just set back over the horizon, and sunrise is when
+/// the top of the sun appears rather than its centre
+const SUNRISE_ALTITUDE: f64 = -0.833;
+/// Where the centre of the sun is at the start of dawn and end of dusk, once it's this far below the
+/// horizon it's too dark to do much outside without lights
+const CIVIL_TWILIGHT_ALTITUDE: f64 = -6.0;
+
+/// When the sun rises and sets on a given day, computed offline from a city's coordinates. Times of day
+/// are in UTC, as HH:MM
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SunReport {
+    /// the (local) day this report is for, as YYYY-MM-DD
+    pub date: String,
+    /// when the sun is highest in the sky
+    pub solar_noon: String,
+    /// `None` if the sun doesn't rise or set that day, i.e. it's polar night or the midnight sun
+    pub sunrise: Option<String>,
+    pub sunset: Option<String>,
+    /// when civil twilight starts in the morning, `None` if it never gets that dark (or light)
+    pub civil_dawn: Option<String>,
+    /// when civil twilight ends in the evening
+    pub civil_dusk: Option<String>,
+    /// how long the sun is up for, from 0 (polar night) to 1440 (midnight sun)
+    pub day_length_minutes: u32,
+}
+
+/// How the sun crosses a particular altitude over the course of a day
+enum Crossing {
+    /// it passes through the altitude this many degrees (of hour angle) either side of solar noon
+    At(f64),
+    /// it stays above the altitude all day
+    AlwaysAbove,
+    /// it never gets as high as the altitude
+    AlwaysBelow,
+}
+
+/// The position of the sun on a given day, as seen from a given place. This follows the "sunrise
+/// equation" from <https://en.wikipedia.org/wiki/Sunrise_equation>, which is good to around a minute
+/// everywhere outside the polar regions
+struct SolarDay {
+    latitude: f64,
+    /// solar noon, as a Julian day
+    transit: f64,
+    /// the sun's declination, in radians
+    declination: f64,
+}
+
+impl SolarDay {
+    fn new(coordinates: Coordinates, date: NaiveDate) -> Self {
+        let days_since_j2000 = (date - J2000_DATE).num_days();
+
+        // when the sun is due south (or north), before correcting for the shape of earth's orbit
+        #[allow(clippy::cast_precision_loss)]
+        let mean_solar_noon = days_since_j2000 as f64 - coordinates.longitude / 360.0;
+
+        let mean_anomaly = (357.5291 + 0.985_600_28 * mean_solar_noon)
+            .rem_euclid(360.0)
+            .to_radians();
+        let equation_of_center = 1.9148 * mean_anomaly.sin()
+            + 0.02 * (2.0 * mean_anomaly).sin()
+            + 0.0003 * (3.0 * mean_anomaly).sin();
+        let ecliptic_longitude =
+            (mean_anomaly.to_degrees() + equation_of_center + 180.0 + 102.9372)
+                .rem_euclid(360.0)
+                .to_radians();
+
+        Self {
+            latitude: coordinates.latitude.to_radians(),
+            transit: J2000 + mean_solar_noon + 0.0053 * mean_anomaly.sin()
+                - 0.0069 * (2.0 * ecliptic_longitude).sin(),
+            declination: (ecliptic_longitude.sin() * OBLIQUITY.to_radians().sin()).asin(),
+        }
+    }
+
+    /// When the sun's centre passes through `altitude` degrees
+    fn crossing(&self, altitude: f64) -> Crossing {
+        let cos_hour_angle = (altitude.to_radians().sin()
+            - self.latitude.sin() * self.declination.sin())
+            / (self.latitude.cos() * self.declination.cos());
+
+        if cos_hour_angle > 1.0 {
+            Crossing::AlwaysBelow
+        } else if cos_hour_angle < -1.0 {
+            Crossing::AlwaysAbove
+        } else {
+            Crossing::At(cos_hour_angle.acos().to_degrees())
+        }
+    }
+
+    /// The times the sun passes up and then back down through `altitude`, if it does
+    fn rise_and_set(&self, altitude: f64) -> (Option<String>, Option<String>) {
+        match self.crossing(altitude) {
+            Crossing::At(hour_angle) => (
+                Some(time_of_day(self.transit - hour_angle / 360.0)),
+                Some(time_of_day(self.transit + hour_angle / 360.0)),
+            ),
+            Crossing::AlwaysAbove | Crossing::AlwaysBelow => (None, None),
+        }
+    }
+}
+
+/// Format a Julian day as the UTC time of day, to the nearest minute
+fn time_of_day(julian_day: f64) -> String {
+    #[allow(clippy::cast_possible_truncation)]
+    let minutes = ((julian_day - UNIX_EPOCH_JULIAN_DAY) * 24.0 * 60.0).round() as i64;
+
+    DateTime::from_timestamp(minutes * 60, 0)
+        .map(|time| time.format("%H:%M").to_string())
+        .unwrap_or_default()
+}
+
+impl SunReport {
+    /// Work out when the sun rises and sets at `coordinates` on `date`. This is pure computation, no
+    /// network needed
+    pub fn for_date(coordinates: Coordinates, date: NaiveDate) -> Self {
+        let day = SolarDay::new(coordinates, date);
+        let (sunrise, sunset) = day.rise_and_set(SUNRISE_ALTITUDE);
+        let (civil_dawn, civil_dusk) = day.rise_and_set(CIVIL_TWILIGHT_ALTITUDE);
+
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let day_length_minutes = match day.crossing(SUNRISE_ALTITUDE) {
+            // the sun is up for `hour_angle` degrees either side of noon, at 15 degrees an hour
+            Crossing::At(hour_angle) => (hour_angle * 2.0 / 15.0 * 60.0).round() as u32,
+            Crossing::AlwaysAbove => 24 * 60,
+            Crossing::AlwaysBelow => 0,
+        };
+
+        Self {
+            date: date.format("%Y-%m-%d").to_string(),
+            solar_noon: time_of_day(day.transit),
+            sunrise,
+            sunset,
+            civil_dawn,
+            civil_dusk,
+            day_length_minutes,
+        }
+    }
+}
+
+impl Display for SunReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("Sun on {}: ", self.date))?;
+
+        match (&self.sunrise, &self.sunset) {
+            (Some(sunrise), Some(sunset)) => f.write_fmt(format_args!(
+                "rises at {sunrise} and sets at {sunset} UTC, {}h{:02}m of daylight",
+                self.day_length_minutes / 60,
+                self.day_length_minutes % 60
+            ))?,
+            _ if self.day_length_minutes == 0 => f.write_str("doesn't rise (polar night)")?,
+            _ => f.write_str("doesn't set (midnight sun)")?,
+        }
+
+        f.write_fmt(format_args!(". Solar noon at {} UTC", self.solar_noon))?;
+        if let (Some(dawn), Some(dusk)) = (&self.civil_dawn, &self.civil_dusk) {
+            f.write_fmt(format_args!(", civil twilight from {dawn} to {dusk} UTC"))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A `CityDataSource` reporting when the sun rises and sets in a city. This needs no network, only the
+/// city's coordinates (see `CityQuery::coordinates`), so it keeps working when every upstream is down
+#[derive(Debug, Default)]
+pub struct SunDataSource {
+    // the day to report on, if `None` it's whatever day it currently is in the city
+    date: Option<NaiveDate>,
+}
+
+impl SunDataSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Always report on `date`, rather than the current day
+    #[must_use]
+    pub fn with_date(mut self, date: NaiveDate) -> Self {
+        self.date = Some(date);
+        self
+    }
+
+    /// The current date at `coordinates`. Close to the date line, the local date can be a day either side
+    /// of the UTC date, so we go by mean solar time (roughly an hour per 15 degrees of longitude) instead
+    fn today_at(coordinates: Coordinates) -> NaiveDate {
+        #[allow(clippy::cast_possible_truncation)]
+        let offset = Duration::minutes((coordinates.longitude / 15.0 * 60.0).round() as i64);
+
+        (DateTime::<Utc>::from(SystemTime::now()) + offset).date_naive()
+    }
+}
+
+impl CityDataSource for SunDataSource {
+    async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+        let coordinates = query
+            .coordinates
+            .ok_or(CityDataError::NotGeocoded(query.city))?;
+        let date = self.date.unwrap_or_else(|| Self::today_at(coordinates));
+
+        Ok(CityData::Sun(SunReport::for_date(coordinates, date)))
+    }
+}
+
+/// Spawn a task running a `SunDataSource`, returning a handle to it. There's nothing to cache, rate limit
+/// or retry here, so unlike the fetchers this takes no config
+pub fn spawn_sun_task(cancellation_token: CancellationToken) -> SpawnResult<CityDataSourceHandle> {
+    let _span = tracing::info_span!("Fetcher", source = "sun").entered();
+
+    spawn_data_source_task(
+        SunDataSource::new(),
+        DEFAULT_MAX_IN_FLIGHT,
+        cancellation_token,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use crate::{CityData, CityDataError, CityDataSource, CityQuery, Coordinates};
+
+    use super::{SunDataSource, SunReport};
+
+    const LONDON: Coordinates = Coordinates {
+        latitude: 51.507_4,
+        longitude: -0.127_8,
+    };
+    const QUITO: Coordinates = Coordinates {
+        latitude: -0.180_7,
+        longitude: -78.467_8,
+    };
+    const SYDNEY: Coordinates = Coordinates {
+        latitude: -33.868_8,
+        longitude: 151.209_3,
+    };
+    const TROMSO: Coordinates = Coordinates {
+        latitude: 69.649_2,
+        longitude: 18.955_3,
+    };
+
+    fn date(year: i32, month: u32, day: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(year, month, day).expect("expected a valid date")
+    }
+
+    /// Minutes since midnight for an HH:MM time
+    fn minutes(time: &str) -> i32 {
+        let (hours, minutes) = time.split_once(':').expect("expected HH:MM");
+        hours.parse::<i32>().expect("expected hours") * 60
+            + minutes.parse::<i32>().expect("expected minutes")
+    }
+
+    /// The algorithm is good to about a minute, so allow a couple either side of the almanac
+    fn assert_near(actual: Option<&str>, expected: &str) {
+        let actual = actual.expect("expected a time");
+        let difference = (minutes(actual) - minutes(expected)).rem_euclid(24 * 60);
+        assert!(
+            difference <= 2 || difference >= 24 * 60 - 2,
+            "expected {actual} to be within 2 minutes of {expected}"
+        );
+    }
+
+    // Expected values below are from the NOAA solar calculator <https://gml.noaa.gov/grad/solcalc/>,
+    // converted to UTC
+
+    #[test]
+    fn test_summer_solstice() {
+        let report = SunReport::for_date(LONDON, date(2024, 6, 20));
+
+        assert_eq!(report.date, "2024-06-20");
+        assert_near(report.sunrise.as_deref(), "03:43");
+        assert_near(report.sunset.as_deref(), "20:21");
+        assert_near(Some(&report.solar_noon), "12:02");
+        assert_near(report.civil_dawn.as_deref(), "02:57");
+        assert_near(report.civil_dusk.as_deref(), "21:07");
+        assert!(report.day_length_minutes.abs_diff(16 * 60 + 38) <= 2);
+    }
+
+    #[test]
+    fn test_equator() {
+        // near the equator days are close to 12 hours all year round
+        let report = SunReport::for_date(QUITO, date(2024, 3, 20));
+
+        assert_near(report.sunrise.as_deref(), "11:17");
+        assert_near(report.sunset.as_deref(), "23:24");
+        assert!(report.day_length_minutes.abs_diff(12 * 60 + 7) <= 2);
+    }
+
+    #[test]
+    fn test_southern_hemisphere() {
+        // local midsummer, sunrise is the previous evening in UTC
+        let report = SunReport::for_date(SYDNEY, date(2024, 12, 21));
+
+        assert_near(report.sunrise.as_deref(), "18:41");
+        assert_near(report.sunset.as_deref(), "09:05");
+        assert_near(Some(&report.solar_noon), "01:53");
+        assert!(report.day_length_minutes.abs_diff(14 * 60 + 24) <= 2);
+    }
+
+    #[test]
+    fn test_polar() {
+        let winter = SunReport::for_date(TROMSO, date(2024, 12, 21));
+        assert_eq!(winter.sunrise, None);
+        assert_eq!(winter.day_length_minutes, 0);
+        // it still gets light enough for civil twilight around noon
+        assert!(winter.civil_dawn.is_some());
+        assert_eq!(
+            winter.to_string(),
+            format!(
+                "Sun on 2024-12-21: doesn't rise (polar night). Solar noon at {} UTC, civil twilight from {} to {} UTC",
+                winter.solar_noon,
+                winter.civil_dawn.as_deref().unwrap_or_default(),
+                winter.civil_dusk.as_deref().unwrap_or_default()
+            )
+        );
+
+        let summer = SunReport::for_date(TROMSO, date(2024, 6, 21));
+        assert_eq!(summer.sunset, None);
+        assert_eq!(summer.civil_dusk, None);
+        assert_eq!(summer.day_length_minutes, 24 * 60);
+        assert!(summer.to_string().contains("doesn't set (midnight sun)"));
+    }
+
+    #[test]
+    fn test_format() {
+        let report = SunReport {
+            date: String::from("2024-06-20"),
+            solar_noon: String::from("12:02"),
+            sunrise: Some(String::from("03:43")),
+            sunset: Some(String::from("20:21")),
+            civil_dawn: Some(String::from("02:57")),
+            civil_dusk: Some(String::from("21:07")),
+            day_length_minutes: 998,
+        };
+
+        assert_eq!(
+            report.to_string(),
+            "Sun on 2024-06-20: rises at 03:43 and sets at 20:21 UTC, 16h38m of daylight. \
+             Solar noon at 12:02 UTC, civil twilight from 02:57 to 21:07 UTC"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_data_source() {
+        let source = SunDataSource::new().with_date(date(2024, 6, 20));
+
+        let result = source
+            .fetch_data(CityQuery::new("London").with_coordinates(LONDON))
+            .await;
+        assert!(matches!(result, Ok(CityData::Sun(report)) if report.date == "2024-06-20"));
+
+        // without coordinates there's nothing to compute from
+        let result = source.fetch_data(CityQuery::new("London")).await;
+        assert!(matches!(result, Err(CityDataError::NotGeocoded(city)) if city == "London"));
+    }
+}
@@ -0,0 +1,59 @@
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use axum::Router;
+use tokio::net::TcpListener;
+
+use crate::{CityData, CityDataError, CityDataResult, CityDataSource, CityQuery, PlaceRecord};
+
+/// Serve `router` on an ephemeral local port so fetchers can be tested without reaching the public
+/// internet. Returns the server's base url, e.g. `http://127.0.0.1:12345`
+///
+/// The server runs on a background task for the remainder of the test
+pub(crate) async fn spawn_fixture_server(router: Router) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind fixture server");
+    let address = listener
+        .local_addr()
+        .expect("Failed to get fixture server address");
+
+    tokio::spawn(async move {
+        axum::serve(listener, router)
+            .await
+            .expect("Fixture server exited unexpectedly");
+    });
+
+    format!("http://{address}")
+}
+
+/// A data source that counts how many times it was actually called, for testing decorators. Cities
+/// starting with "Nowhere" are not found, and cities starting with "Broken" fail
+#[derive(Clone, Default)]
+pub(crate) struct CountingDataSource {
+    pub calls: Arc<AtomicUsize>,
+}
+
+impl CityDataSource for CountingDataSource {
+    async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+        let city = query.city;
+        self.calls.fetch_add(1, Ordering::SeqCst);
+
+        if city.starts_with("Nowhere") {
+            return Err(CityDataError::NotFound(city));
+        }
+        if city.starts_with("Broken") {
+            return Err(CityDataError::UpstreamStatus { status: 503 });
+        }
+
+        Ok(CityData::Place(PlaceRecord {
+            name: city.clone(),
+            display_name: city,
+            latitude: 0.0,
+            longitude: 0.0,
+            ..PlaceRecord::default()
+        }))
+    }
+}
@@ -0,0 +1,433 @@
+use std::{
+    fmt::Display,
+    sync::{Arc, LazyLock},
+    time::SystemTime,
+};
+
+use chrono::{DateTime, Offset, Utc};
+use chrono_tz::{OffsetComponents, OffsetName, Tz};
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    spawn_data_source_task, timezone_boundaries::TimezoneBoundaries, CityData, CityDataError,
+    CityDataResult, CityDataSource, CityDataSourceHandle, CityQuery, Coordinates, SpawnResult,
+    DEFAULT_MAX_IN_FLIGHT,
+};
+
+/// The tz database's table of timezones, one row per (country, timezone) pair along with the location of
+/// the timezone's principal city. See <https://data.iana.org/time-zones/tz-link.html>, this file is in the
+/// public domain
+const ZONE_TAB: &str = include_str!("../data/zone.tab");
+
+/// The bundled timezones, parsed the first time they're needed
+static TIMEZONES: LazyLock<Vec<TimezoneEntry>> = LazyLock::new(|| parse_zone_tab(ZONE_TAB));
+
+/// A single row of `zone.tab`
+#[derive(Clone, Debug, PartialEq)]
+struct TimezoneEntry {
+    /// an ISO 3166-1 alpha-2 code, uppercase
+    country_code: String,
+    /// where the timezone's principal city is
+    location: Coordinates,
+    /// the IANA name, e.g. "Europe/Paris"
+    name: String,
+}
+
+/// Parse a `zone.tab` style coordinate, e.g. "+4852" or "-0873900", as `±DDMM[SS]` (latitude) or
+/// `±DDDMM[SS]` (longitude) depending on `degree_digits`
+fn parse_iso6709(value: &str, degree_digits: usize) -> Option<f64> {
+    let sign = match value.get(..1)? {
+        "+" => 1.0,
+        "-" => -1.0,
+        _ => return None,
+    };
+    let digits = &value[1..];
+    let degrees: f64 = digits.get(..degree_digits)?.parse().ok()?;
+    let minutes: f64 = digits.get(degree_digits..degree_digits + 2)?.parse().ok()?;
+    let seconds: f64 = match digits.get(degree_digits + 2..) {
+        Some("") | None => 0.0,
+        Some(seconds) => seconds.parse().ok()?,
+    };
+
+    Some(sign * (degrees + minutes / 60.0 + seconds / 3600.0))
+}
+
+/// Parse the coordinates column of `zone.tab`, e.g. "+4852+00220" (latitude then longitude)
+fn parse_location(value: &str) -> Option<Coordinates> {
+    // the longitude starts at the second sign
+    let split = value[1..].find(['+', '-'])? + 1;
+    let (latitude, longitude) = value.split_at(split);
+
+    Some(Coordinates {
+        latitude: parse_iso6709(latitude, 2)?,
+        longitude: parse_iso6709(longitude, 3)?,
+    })
+}
+
+/// Parse the rows of a `zone.tab` file, skipping comments and (with a warning) anything malformed
+fn parse_zone_tab(contents: &str) -> Vec<TimezoneEntry> {
+    contents
+        .lines()
+        .filter(|line| !line.starts_with('#') && !line.trim().is_empty())
+        .filter_map(|line| {
+            let mut columns = line.split('\t');
+            let entry = match (
+                columns.next(),
+                columns.next().and_then(parse_location),
+                columns.next(),
+            ) {
+                (Some(country_code), Some(location), Some(name)) => Some(TimezoneEntry {
+                    country_code: country_code.to_owned(),
+                    location,
+                    name: name.to_owned(),
+                }),
+                _ => None,
+            };
+
+            if entry.is_none() {
+                tracing::warn!("Skipping malformed zone.tab line: {line:?}");
+            }
+            entry
+        })
+        .collect()
+}
+
+/// Find the timezone for `coordinates`, as the one whose principal city is nearest. If `country_code` is
+/// given (and we know of any timezones in it) only that country's timezones are considered, which stops
+/// cities near a border picking up their neighbour's timezone
+///
+/// Note: this approximates the actual timezone boundaries with the nearest principal city, which is right
+/// for most cities, but is often wrong close to a boundary within a country (e.g. Nashville is nearer
+/// Kentucky's Eastern principal city than Chicago). It's a last resort when we have no boundaries, see
+/// `TimezoneDataSource`
+fn find_timezone<'a>(
+    timezones: &'a [TimezoneEntry],
+    coordinates: Coordinates,
+    country_code: Option<&str>,
+) -> Option<&'a TimezoneEntry> {
+    let nearest = |entries: &mut dyn Iterator<Item = &'a TimezoneEntry>| {
+        entries.min_by(|a, b| {
+            a.location
+                .distance_km(coordinates)
+                .total_cmp(&b.location.distance_km(coordinates))
+        })
+    };
+
+    country_code
+        .and_then(|country_code| {
+            nearest(
+                &mut timezones
+                    .iter()
+                    .filter(|entry| entry.country_code.eq_ignore_ascii_case(country_code.trim())),
+            )
+        })
+        .or_else(|| nearest(&mut timezones.iter()))
+}
+
+/// The current time in a city, along with which timezone it is in
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LocalTimeReport {
+    /// the IANA timezone name, e.g. "Europe/Paris"
+    pub timezone: String,
+    /// as YYYY-MM-DD HH:MM
+    pub local_time: String,
+    /// e.g. "+02:00"
+    pub utc_offset: String,
+    /// e.g. "CEST", some timezones only have a numeric abbreviation like "+03"
+    pub abbreviation: Option<String>,
+    /// whether daylight saving time is in effect
+    pub is_dst: bool,
+}
+
+impl LocalTimeReport {
+    /// Describe the time in `timezone` at `now`
+    pub fn at(timezone: Tz, now: DateTime<Utc>) -> Self {
+        let local = now.with_timezone(&timezone);
+        let offset = local.offset();
+
+        Self {
+            timezone: timezone.name().to_owned(),
+            local_time: local.format("%Y-%m-%d %H:%M").to_string(),
+            utc_offset: offset.fix().to_string(),
+            abbreviation: offset.abbreviation().map(str::to_owned),
+            is_dst: !offset.dst_offset().is_zero(),
+        }
+    }
+}
+
+impl Display for LocalTimeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("Local time: {}", self.local_time))?;
+        if let Some(abbreviation) = &self.abbreviation {
+            f.write_fmt(format_args!(" {abbreviation}"))?;
+        }
+        f.write_fmt(format_args!(" (UTC{}, {}", self.utc_offset, self.timezone))?;
+        if self.is_dst {
+            f.write_str(", daylight saving time")?;
+        }
+
+        f.write_str(")")
+    }
+}
+
+/// A `CityDataSource` reporting the current local time in a city, so this needs no network. The city's
+/// timezone is the first of:
+/// - the timezone the geocoder gave (see `CityQuery::timezone`), GeoNames knows every city's
+/// - the timezone whose area the city is in, if we were given timezone boundaries (see
+///   `TimezoneBoundaries`), for cities geocoded by a source that doesn't know timezones like nominatim
+/// - the timezone whose principal city is nearest in a bundled copy of the tz database (see
+///   `find_timezone`)
+#[derive(Debug, Default)]
+pub struct TimezoneDataSource {
+    // the time to report, if `None` it's the current time
+    now: Option<DateTime<Utc>>,
+    // the areas each timezone covers, if we have them
+    boundaries: Option<Arc<TimezoneBoundaries>>,
+}
+
+impl TimezoneDataSource {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Always report the local time at `now`, rather than the current time
+    #[must_use]
+    pub fn with_time(mut self, now: DateTime<Utc>) -> Self {
+        self.now = Some(now);
+        self
+    }
+
+    /// Look for the city in `boundaries` before falling back to the tz database's principal cities
+    #[must_use]
+    pub fn with_boundaries(mut self, boundaries: Arc<TimezoneBoundaries>) -> Self {
+        self.boundaries = Some(boundaries);
+        self
+    }
+
+    /// The name of the timezone the city `query` is about is in, see `TimezoneDataSource`
+    fn timezone_name<'a>(
+        &'a self,
+        query: &'a CityQuery,
+        coordinates: Coordinates,
+    ) -> Option<&'a str> {
+        let boundary = || self.boundaries.as_ref()?.lookup(coordinates);
+        let principal_city = || {
+            find_timezone(&TIMEZONES, coordinates, query.country_code.as_deref())
+                .map(|entry| entry.name.as_str())
+        };
+
+        query
+            .timezone
+            .as_deref()
+            .or_else(boundary)
+            .or_else(principal_city)
+    }
+}
+
+impl CityDataSource for TimezoneDataSource {
+    async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+        let coordinates = query
+            .coordinates
+            .ok_or_else(|| CityDataError::NotGeocoded(query.city.clone()))?;
+
+        let name = self
+            .timezone_name(&query, coordinates)
+            .ok_or_else(|| CityDataError::NotFound(query.city.clone()))?;
+        // our copies of zone.tab, the gazetteer and the boundaries and chrono-tz's copy of the tz database
+        // could be from different releases
+        let timezone: Tz = name.parse().map_err(|_| {
+            tracing::error!("Unknown timezone {name:?}");
+            CityDataError::NotFound(query.city.clone())
+        })?;
+
+        let now = self
+            .now
+            .unwrap_or_else(|| DateTime::from(SystemTime::now()));
+
+        Ok(CityData::LocalTime(LocalTimeReport::at(timezone, now)))
+    }
+}
+
+/// Spawn a task running a `TimezoneDataSource` that looks for cities in `boundaries` (if given), returning a
+/// handle to it. Like `sun::spawn_sun_task` there's no upstream to protect, so this takes no config
+pub fn spawn_timezone_task(
+    boundaries: Option<Arc<TimezoneBoundaries>>,
+    cancellation_token: CancellationToken,
+) -> SpawnResult<CityDataSourceHandle> {
+    let _span = tracing::info_span!("Fetcher", source = "timezone").entered();
+
+    let data_source = match boundaries {
+        Some(boundaries) => TimezoneDataSource::new().with_boundaries(boundaries),
+        None => TimezoneDataSource::new(),
+    };
+    spawn_data_source_task(data_source, DEFAULT_MAX_IN_FLIGHT, cancellation_token)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::{DateTime, Utc};
+
+    use crate::{
+        timezone_boundaries::TimezoneBoundaries, CityData, CityDataError, CityDataSource,
+        CityQuery, Coordinates,
+    };
+
+    use super::{find_timezone, parse_location, LocalTimeReport, TimezoneDataSource, TIMEZONES};
+
+    const PARIS: Coordinates = Coordinates {
+        latitude: 48.856_6,
+        longitude: 2.352_2,
+    };
+    // just across the river from Detroit
+    const WINDSOR: Coordinates = Coordinates {
+        latitude: 42.314_9,
+        longitude: -83.036_4,
+    };
+
+    fn utc(timestamp: &str) -> DateTime<Utc> {
+        timestamp.parse().expect("expected a valid timestamp")
+    }
+
+    #[test]
+    fn test_parse_location() {
+        let paris = parse_location("+4852+00220").expect("expected a location");
+        assert!((paris.latitude - 48.866_7).abs() < 0.001);
+        assert!((paris.longitude - 2.333_3).abs() < 0.001);
+
+        let chicago = parse_location("+415100-0873900").expect("expected a location");
+        assert!((chicago.latitude - 41.85).abs() < 0.001);
+        assert!((chicago.longitude + 87.65).abs() < 0.001);
+
+        assert_eq!(parse_location("4852+00220"), None);
+        assert_eq!(parse_location("+48+002"), None);
+    }
+
+    #[test]
+    fn test_bundled_timezones() {
+        // every line of zone.tab parsed, and chrono-tz knows every timezone in it
+        assert!(TIMEZONES.len() > 400);
+        for entry in TIMEZONES.iter() {
+            assert!(
+                entry.name.parse::<chrono_tz::Tz>().is_ok(),
+                "unknown timezone {:?}",
+                entry.name
+            );
+        }
+    }
+
+    #[test]
+    fn test_find_timezone() {
+        let find = |coordinates, country_code| {
+            find_timezone(&TIMEZONES, coordinates, country_code).map(|entry| entry.name.as_str())
+        };
+
+        assert_eq!(find(PARIS, None), Some("Europe/Paris"));
+        let chicago = Coordinates {
+            latitude: 41.878_1,
+            longitude: -87.629_8,
+        };
+        assert_eq!(find(chicago, Some("us")), Some("America/Chicago"));
+
+        // Windsor is closest to Detroit, but is in Canada
+        assert_eq!(find(WINDSOR, None), Some("America/Detroit"));
+        assert_eq!(find(WINDSOR, Some("CA")), Some("America/Toronto"));
+        // a country we know nothing about is ignored
+        assert_eq!(find(WINDSOR, Some("zz")), Some("America/Detroit"));
+    }
+
+    #[test]
+    fn test_report() {
+        let summer = LocalTimeReport::at(chrono_tz::Europe::Paris, utc("2024-07-01T12:00:00Z"));
+        assert_eq!(summer.local_time, "2024-07-01 14:00");
+        assert_eq!(summer.utc_offset, "+02:00");
+        assert_eq!(summer.abbreviation.as_deref(), Some("CEST"));
+        assert!(summer.is_dst);
+        assert_eq!(
+            summer.to_string(),
+            "Local time: 2024-07-01 14:00 CEST (UTC+02:00, Europe/Paris, daylight saving time)"
+        );
+
+        let winter = LocalTimeReport::at(chrono_tz::Europe::Paris, utc("2024-12-31T23:30:00Z"));
+        assert_eq!(winter.local_time, "2025-01-01 00:30");
+        assert_eq!(winter.utc_offset, "+01:00");
+        assert!(!winter.is_dst);
+
+        let kolkata = LocalTimeReport::at(chrono_tz::Asia::Kolkata, utc("2024-07-01T12:00:00Z"));
+        assert_eq!(kolkata.local_time, "2024-07-01 17:30");
+        assert_eq!(kolkata.utc_offset, "+05:30");
+    }
+
+    #[tokio::test]
+    async fn test_data_source() {
+        let source = TimezoneDataSource::new().with_time(utc("2024-07-01T12:00:00Z"));
+
+        let result = source
+            .fetch_data(CityQuery::new("Paris").with_coordinates(PARIS))
+            .await;
+        assert!(matches!(
+            result,
+            Ok(CityData::LocalTime(report)) if report.timezone == "Europe/Paris"
+        ));
+
+        let result = source
+            .fetch_data(
+                CityQuery::new("Windsor")
+                    .with_country_code("ca")
+                    .with_coordinates(WINDSOR),
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Ok(CityData::LocalTime(report)) if report.timezone == "America/Toronto"
+        ));
+
+        let result = source.fetch_data(CityQuery::new("Paris")).await;
+        assert!(matches!(result, Err(CityDataError::NotGeocoded(city)) if city == "Paris"));
+    }
+
+    #[tokio::test]
+    async fn test_boundaries() {
+        // as nominatim would geocode it, without a timezone. It's nearer Kentucky's Eastern principal city than
+        // Chicago's
+        let nashville = CityQuery::new("Nashville").with_coordinates(Coordinates {
+            latitude: 36.162_2,
+            longitude: -86.774_4,
+        });
+        let timezone_of = |source: &TimezoneDataSource, query: &CityQuery| {
+            let coordinates = query.coordinates.expect("expected coordinates");
+            source.timezone_name(query, coordinates).map(str::to_owned)
+        };
+
+        let boundaries =
+            TimezoneBoundaries::parse(include_str!("../tests/fixtures/timezone_boundaries.json"))
+                .expect("expected the fixture to parse");
+        let source = TimezoneDataSource::new().with_boundaries(Arc::new(boundaries));
+        assert_eq!(
+            timezone_of(&source, &nashville).as_deref(),
+            Some("America/Chicago")
+        );
+        // somewhere the boundaries don't cover falls back to the nearest principal city
+        let query = CityQuery::new("Paris").with_coordinates(PARIS);
+        assert_eq!(
+            timezone_of(&source, &query).as_deref(),
+            Some("Europe/Paris")
+        );
+
+        // without boundaries, it's only as good as the nearest principal city
+        let source = TimezoneDataSource::new();
+        assert_eq!(
+            timezone_of(&source, &nashville).as_deref(),
+            Some("America/Kentucky/Monticello")
+        );
+        // unless the geocoder said which timezone it's in
+        let mut query = nashville;
+        query.timezone = Some(String::from("America/Chicago"));
+        assert_eq!(
+            timezone_of(&source, &query).as_deref(),
+            Some("America/Chicago")
+        );
+    }
+}
@@ -0,0 +1,250 @@
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::Coordinates;
+
+#[derive(Debug, Error)]
+pub enum TimezoneBoundariesError {
+    #[error("Failed to read timezone boundaries file {path:?}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Malformed timezone boundaries at {path}: {message}")]
+    Malformed { path: String, message: String },
+}
+
+/// A GeoJSON position, as longitude then latitude
+type Position = [f64; 2];
+
+/// The parts of a timezone-boundary-builder release we need, a GeoJSON `FeatureCollection` with one feature per
+/// timezone
+#[derive(Deserialize)]
+struct FeatureCollection {
+    features: Vec<Feature>,
+}
+
+#[derive(Deserialize)]
+struct Feature {
+    properties: FeatureProperties,
+    geometry: Geometry,
+}
+
+#[derive(Deserialize)]
+struct FeatureProperties {
+    /// the IANA name, e.g. "Europe/Paris"
+    tzid: String,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", content = "coordinates")]
+enum Geometry {
+    Polygon(Vec<Vec<Position>>),
+    MultiPolygon(Vec<Vec<Vec<Position>>>),
+}
+
+/// A single polygon of a timezone's area, along with the box around it so most lookups can rule it out
+/// without looking at its rings
+#[derive(Debug)]
+struct Polygon {
+    /// the outer boundary, then any holes cut out of it (e.g. an enclave in another timezone)
+    rings: Vec<Vec<Position>>,
+    /// the smallest and largest longitude and latitude of the outer boundary
+    min: Position,
+    max: Position,
+}
+
+impl Polygon {
+    /// `None` if there's no outer boundary
+    fn new(rings: Vec<Vec<Position>>) -> Option<Self> {
+        let outer = rings.first().filter(|ring| !ring.is_empty())?;
+        let (mut min, mut max) = (outer[0], outer[0]);
+        for &[longitude, latitude] in outer {
+            min = [min[0].min(longitude), min[1].min(latitude)];
+            max = [max[0].max(longitude), max[1].max(latitude)];
+        }
+
+        Some(Self { rings, min, max })
+    }
+
+    fn contains(&self, point: Position) -> bool {
+        let [longitude, latitude] = point;
+        if longitude < self.min[0]
+            || longitude > self.max[0]
+            || latitude < self.min[1]
+            || latitude > self.max[1]
+        {
+            return false;
+        }
+
+        let (outer, holes) = self
+            .rings
+            .split_first()
+            .expect("a polygon has an outer ring");
+        ring_contains(outer, point) && !holes.iter().any(|hole| ring_contains(hole, point))
+    }
+}
+
+/// Whether `point` is inside `ring`, by casting a ray from it eastwards and counting how many of the ring's
+/// edges it crosses, see <https://wrf.ecse.rpi.edu/Research/Short_Notes/pnpoly.html>. Longitude and latitude
+/// are treated as flat, which is how timezone-boundary-builder draws its edges
+fn ring_contains(ring: &[Position], [x, y]: Position) -> bool {
+    let mut inside = false;
+    let mut previous = match ring.last() {
+        Some(&last) => last,
+        None => return false,
+    };
+
+    for &[x_current, y_current] in ring {
+        let [x_previous, y_previous] = previous;
+        if (y_current > y) != (y_previous > y)
+            && x < (x_previous - x_current) * (y - y_current) / (y_previous - y_current) + x_current
+        {
+            inside = !inside;
+        }
+        previous = [x_current, y_current];
+    }
+
+    inside
+}
+
+/// The areas each timezone covers, so a city's timezone can be found from where it is rather than guessed
+/// from what's nearby. Loaded from a release of timezone-boundary-builder
+/// (<https://github.com/evansiroky/timezone-boundary-builder>, ODbL licensed), e.g. `combined.json` from
+/// `timezones.geojson.zip`, or `combined-with-oceans.json` to also cover the sea
+///
+/// Note: lookups check each timezone in turn, but skip any polygon whose bounding box doesn't contain the
+/// point, so only the few timezones near it are looked at in any detail
+#[derive(Debug, Default)]
+pub struct TimezoneBoundaries {
+    // each timezone's IANA name and the polygons its area is made of
+    timezones: Vec<(String, Vec<Polygon>)>,
+}
+
+impl TimezoneBoundaries {
+    /// Load the boundaries from a GeoJSON file, see `TimezoneBoundaries`
+    ///
+    /// # Errors
+    /// If the file can't be read, or isn't a `FeatureCollection` of `Polygon`s and `MultiPolygon`s named by
+    /// their `tzid`
+    pub fn load(path: &Path) -> Result<Self, TimezoneBoundariesError> {
+        let contents =
+            std::fs::read_to_string(path).map_err(|source| TimezoneBoundariesError::Io {
+                path: path.to_owned(),
+                source,
+            })?;
+
+        Self::parse(&contents)
+    }
+
+    /// Parse the boundaries from the contents of a GeoJSON file, see `load`
+    ///
+    /// # Errors
+    /// If `contents` isn't a `FeatureCollection` of `Polygon`s and `MultiPolygon`s named by their `tzid`
+    pub fn parse(contents: &str) -> Result<Self, TimezoneBoundariesError> {
+        let deserializer = &mut serde_json::Deserializer::from_str(contents);
+        let collection: FeatureCollection = serde_path_to_error::deserialize(deserializer)
+            .map_err(|e| TimezoneBoundariesError::Malformed {
+                path: e.path().to_string(),
+                message: e.inner().to_string(),
+            })?;
+
+        let timezones = collection
+            .features
+            .into_iter()
+            .map(|feature| {
+                let polygons = match feature.geometry {
+                    Geometry::Polygon(rings) => vec![rings],
+                    Geometry::MultiPolygon(polygons) => polygons,
+                };
+                let polygons = polygons.into_iter().filter_map(Polygon::new).collect();
+
+                (feature.properties.tzid, polygons)
+            })
+            .collect();
+
+        Ok(Self { timezones })
+    }
+
+    /// The IANA name of the timezone `coordinates` are in, or `None` if they're outside of every timezone
+    /// (e.g. out at sea, unless the boundaries cover the oceans)
+    pub fn lookup(&self, coordinates: Coordinates) -> Option<&str> {
+        let point = [coordinates.longitude, coordinates.latitude];
+
+        self.timezones
+            .iter()
+            .find(|(_, polygons)| polygons.iter().any(|polygon| polygon.contains(point)))
+            .map(|(name, _)| name.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::Coordinates;
+
+    use super::{TimezoneBoundaries, TimezoneBoundariesError};
+
+    /// A few simplified (and far from accurate) timezone areas, covering a boundary between two timezones,
+    /// a timezone made of more than one polygon and an enclave of one timezone in another
+    fn fixture() -> TimezoneBoundaries {
+        TimezoneBoundaries::parse(include_str!("../tests/fixtures/timezone_boundaries.json"))
+            .expect("expected the fixture to parse")
+    }
+
+    fn at(latitude: f64, longitude: f64) -> Coordinates {
+        Coordinates {
+            latitude,
+            longitude,
+        }
+    }
+
+    #[test]
+    fn test_lookup() {
+        let boundaries = fixture();
+
+        // either side of the boundary
+        assert_eq!(
+            boundaries.lookup(at(36.16, -86.78)),
+            Some("America/Chicago")
+        );
+        assert_eq!(
+            boundaries.lookup(at(35.96, -83.92)),
+            Some("America/New_York")
+        );
+        // in the second of New York's polygons
+        assert_eq!(boundaries.lookup(at(35.5, -75.5)), Some("America/New_York"));
+        // Büsingen is surrounded by Zurich's timezone, but isn't in it
+        assert_eq!(boundaries.lookup(at(47.7, 8.63)), Some("Europe/Zurich"));
+        assert_eq!(boundaries.lookup(at(47.695, 8.68)), Some("Europe/Busingen"));
+        // inside New York's bounding box, but on Chicago's side of the boundary
+        assert_eq!(boundaries.lookup(at(36.5, -84.0)), Some("America/Chicago"));
+        // the middle of the Atlantic
+        assert_eq!(boundaries.lookup(at(30.0, -40.0)), None);
+    }
+
+    #[test]
+    fn test_malformed() {
+        assert!(matches!(
+            TimezoneBoundaries::load(Path::new("/nonexistent/combined.json")),
+            Err(TimezoneBoundariesError::Io { .. })
+        ));
+
+        let missing_tzid = r#"{"type": "FeatureCollection", "features": [{"type": "Feature",
+            "properties": {}, "geometry": {"type": "Polygon", "coordinates": []}}]}"#;
+        assert!(matches!(
+            TimezoneBoundaries::parse(missing_tzid),
+            Err(TimezoneBoundariesError::Malformed { path, .. }) if path == "features[0].properties"
+        ));
+
+        let point = r#"{"type": "FeatureCollection", "features": [{"type": "Feature",
+            "properties": {"tzid": "Europe/Paris"}, "geometry": {"type": "Point", "coordinates": [2.35, 48.86]}}]}"#;
+        assert!(matches!(
+            TimezoneBoundaries::parse(point),
+            Err(TimezoneBoundariesError::Malformed { .. })
+        ));
+    }
+}
@@ -1,50 +1,79 @@
 use std::fmt::Display;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::{CityDataError, CityDataResult};
+use crate::{
+    http::{get, parse_json, probe_query, snippet, url_encode},
+    ApiEndpoint, CityData, CityDataError, CityDataResult, CityQuery, Units,
+};
 
-const WEATHER_API_PATH: &str = "http://wttr.in/";
-const WEATHER_API_ARGS: &str = "?format=j1";
+pub(crate) const WEATHER_API_PATH: &str = "http://wttr.in/";
+pub(crate) const WEATHER_API_ARGS: &str = "?format=j1";
 
-fn request_path_for_city(city: &str) -> String {
-    // drop all spaces
-    let space_subbed_city = city.replace(' ', "");
+// how each weather provider identifies itself in a `WeatherReport`
+pub(crate) const WTTR_IN_PROVIDER: &str = "wttr.in";
+pub(crate) const OPEN_METEO_PROVIDER: &str = "open-meteo";
 
-    format!("{WEATHER_API_PATH}{space_subbed_city}{WEATHER_API_ARGS}")
+impl ApiEndpoint {
+    /// The public wttr.in endpoint
+    pub fn wttr_in() -> Self {
+        Self::new(WEATHER_API_PATH, WEATHER_API_ARGS)
+    }
+}
+
+/// wttr.in accepts a location as `latitude,longitude` in place of a city name, so if the city has been
+/// geocoded we look up the weather there. Otherwise wttr.in does its own (possibly different!) geocoding
+fn request_path_for_query(endpoint: &ApiEndpoint, query: &CityQuery) -> String {
+    if let Some(coordinates) = query.coordinates {
+        return endpoint.url_for(&coordinates.to_string());
+    }
+
+    // drop all spaces, wttr.in doesn't need them
+    let space_subbed_city = query.city.replace(' ', "");
+
+    endpoint.url_for(&url_encode(&space_subbed_city))
+}
+
+/// A request wttr.in should always be able to answer, for health checks (see `http::probe`)
+pub(crate) fn probe_url(endpoint: &ApiEndpoint) -> String {
+    request_path_for_query(endpoint, &probe_query())
 }
 
 async fn query_weather_api(
     http_client: &reqwest::Client,
-    city_name: &str,
+    endpoint: &ApiEndpoint,
+    query: &CityQuery,
 ) -> CityDataResult<WeatherResponse> {
-    http_client
-        .get(request_path_for_city(city_name))
-        .send()
-        .await
-        .map_err(|e| CityDataError::FetchError(e.to_string()))?
-        .error_for_status()
-        .map_err(|e| CityDataError::FetchError(e.to_string()))?
-        .json::<WeatherResponse>()
-        .await
-        .inspect_err(|e| tracing::error!("Got error: {e:?}"))
-        .map_err(|_| CityDataError::FetchError(String::from("deserialize failed")))
+    let response = get(http_client, &request_path_for_query(endpoint, query)).await?;
+
+    parse_json::<WeatherResponse>(response).await
 }
 
 /// Fetches weather for a city using wttr.in
 /// <https://github.com/chubin/wttr.in> (this is a super fun command line utility and you should try it!)
 pub(crate) async fn fetch_weather_data(
     http_client: &reqwest::Client,
-    city_name: String,
-) -> CityDataResult<String> {
-    let city_stats_response = query_weather_api(http_client, &city_name).await?;
+    endpoint: &ApiEndpoint,
+    query: CityQuery,
+) -> CityDataResult<CityData> {
+    let weather_response = query_weather_api(http_client, endpoint, &query).await?;
 
-    let entry = city_stats_response
+    let entry = weather_response
         .current_condition
-        .first()
-        .ok_or(CityDataError::FetchError(String::from("no city found")))?;
+        .into_iter()
+        .next()
+        .ok_or(CityDataError::NotFound(query.city))?;
+
+    let mut report = WeatherReport::from_entry(entry, query.units)?;
+    report.forecast = weather_response
+        .weather
+        .into_iter()
+        .take(query.forecast.days.into())
+        .enumerate()
+        .map(|(index, day)| DailyForecast::from_day(index, day, query.units, query.forecast.hourly))
+        .collect::<CityDataResult<_>>()?;
 
-    Ok(entry.to_string())
+    Ok(CityData::Weather(report))
 }
 
 /// A struct representing the JSON response from wttr.in
@@ -53,57 +82,718 @@ pub(crate) async fn fetch_weather_data(
 #[derive(Deserialize)]
 struct WeatherResponse {
     current_condition: Vec<WeatherEntry>,
+    // the forecast, one entry per day starting with today
+    #[serde(default)]
+    weather: Vec<WeatherDay>,
 }
 
+/// A single entry in the `current_condition` list of a wttr.in response. wttr.in reports every
+/// number as a string, and most of them in both metric and imperial, so these are kept as-is here and
+/// the ones matching the requested units are parsed when converting to a `WeatherReport`
 #[derive(Deserialize)]
 struct WeatherEntry {
     observation_time: String,
     #[serde(rename = "temp_C")]
     temp_c: String,
+    #[serde(rename = "temp_F")]
+    temp_f: String,
     #[serde(rename = "FeelsLikeC")]
     feels_like_c: String,
+    #[serde(rename = "FeelsLikeF")]
+    feels_like_f: String,
+    #[serde(rename = "weatherDesc")]
+    weather_desc: Vec<WeatherDescription>,
+    #[serde(rename = "winddir16Point")]
+    wind_dir_16_point: String,
+    #[serde(rename = "windspeedKmph")]
+    wind_speed_kmph: String,
+    #[serde(rename = "windspeedMiles")]
+    wind_speed_miles: String,
+    #[serde(rename = "precipMM")]
+    precip_mm: String,
+    #[serde(rename = "precipInches")]
+    precip_inches: String,
+}
+
+/// wttr.in wraps its descriptions in a list of `{"value": "..."}` objects
+#[derive(Deserialize)]
+struct WeatherDescription {
+    value: String,
+}
+
+/// Join a list of wttr.in descriptions, in practice there is only ever one entry
+fn join_descriptions(descriptions: Vec<WeatherDescription>) -> String {
+    descriptions
+        .into_iter()
+        .map(|d| d.value.trim().to_owned())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// A single day in the `weather` list of a wttr.in response
+#[derive(Deserialize)]
+struct WeatherDay {
+    date: String,
+    #[serde(rename = "mintempC")]
+    min_temp_c: String,
+    #[serde(rename = "mintempF")]
+    min_temp_f: String,
+    #[serde(rename = "maxtempC")]
+    max_temp_c: String,
+    #[serde(rename = "maxtempF")]
+    max_temp_f: String,
+    #[serde(rename = "avgtempC")]
+    avg_temp_c: String,
+    #[serde(rename = "avgtempF")]
+    avg_temp_f: String,
+    #[serde(default)]
+    astronomy: Vec<WeatherAstronomy>,
+    #[serde(default)]
+    hourly: Vec<WeatherHour>,
+}
+
+/// Sunrise and sunset for a day, wttr.in also includes the moon's comings and goings which we ignore
+#[derive(Deserialize)]
+struct WeatherAstronomy {
+    sunrise: String,
+    sunset: String,
+}
+
+/// A single (3 hour) entry in a day's `hourly` list. Note the field names differ slightly from those in
+/// `current_condition`, e.g. `tempC` rather than `temp_C`
+#[derive(Deserialize)]
+struct WeatherHour {
+    // the time of day as "hmm" with no leading zeroes, e.g. "0", "300", "1500"
+    time: String,
+    #[serde(rename = "tempC")]
+    temp_c: String,
+    #[serde(rename = "tempF")]
+    temp_f: String,
+    #[serde(rename = "FeelsLikeC")]
+    feels_like_c: String,
+    #[serde(rename = "FeelsLikeF")]
+    feels_like_f: String,
+    #[serde(rename = "weatherDesc")]
+    weather_desc: Vec<WeatherDescription>,
+    #[serde(rename = "winddir16Point")]
+    wind_dir_16_point: String,
+    #[serde(rename = "windspeedKmph")]
+    wind_speed_kmph: String,
+    #[serde(rename = "windspeedMiles")]
+    wind_speed_miles: String,
+    #[serde(rename = "precipMM")]
+    precip_mm: String,
+    #[serde(rename = "precipInches")]
+    precip_inches: String,
+    #[serde(rename = "chanceofrain")]
+    chance_of_rain: String,
+}
+
+/// Current weather conditions for a city, along with any forecast that was asked for (see
+/// `CityQuery::forecast`). Every measurement is in `units`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct WeatherReport {
+    pub units: Units,
+    /// which weather service this report came from, e.g. "wttr.in" or "open-meteo"
+    pub provider: String,
+    pub observation_time: String,
+    pub temperature: f64,
+    pub feels_like: f64,
+    pub description: String,
+    pub wind_direction: String,
+    pub wind_speed: f64,
+    pub precipitation: f64,
+    /// one entry per day, starting with today
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub forecast: Vec<DailyForecast>,
+}
+
+/// The forecast for a single day, in the units of the `WeatherReport` it belongs to
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DailyForecast {
+    /// as YYYY-MM-DD
+    pub date: String,
+    pub min_temperature: f64,
+    pub max_temperature: f64,
+    pub avg_temperature: f64,
+    pub sunrise: Option<String>,
+    pub sunset: Option<String>,
+    /// only filled in if an hourly forecast was asked for
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub hourly: Vec<HourlyForecast>,
+}
+
+/// The forecast for a single point in a day, in the units of the `WeatherReport` it belongs to
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HourlyForecast {
+    /// the local time of day, as HH:MM
+    pub time: String,
+    pub temperature: f64,
+    pub feels_like: f64,
+    pub description: String,
+    pub wind_direction: String,
+    pub wind_speed: f64,
+    pub precipitation: f64,
+    /// as a percentage, if the source knows it
+    pub chance_of_rain: Option<f64>,
+}
+
+/// Parse a numeric field out of a wttr.in response, naming the field (by its full `path`) in the error if
+/// it's malformed
+fn parse_field(path: &str, value: &str) -> CityDataResult<f64> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| CityDataError::MalformedResponse {
+            path: path.to_owned(),
+            message: String::from("expected a number"),
+            snippet: snippet(value),
+        })
+}
+
+/// A measurement wttr.in reports in both metric and imperial, as `(field name, value)` pairs, along with
+/// how to convert the metric value to SI (which wttr.in doesn't report)
+struct Measurement<'a> {
+    metric: (&'a str, &'a str),
+    imperial: (&'a str, &'a str),
+    metric_to_si: fn(f64) -> f64,
 }
 
-impl Display for WeatherEntry {
+impl<'a> Measurement<'a> {
+    fn temperature(metric: (&'a str, &'a str), imperial: (&'a str, &'a str)) -> Self {
+        Self {
+            metric,
+            imperial,
+            metric_to_si: celsius_to_kelvin,
+        }
+    }
+
+    fn speed(metric: (&'a str, &'a str), imperial: (&'a str, &'a str)) -> Self {
+        Self {
+            metric,
+            imperial,
+            metric_to_si: |kmph| round_to_hundredths(kmph / 3.6),
+        }
+    }
+
+    fn precipitation(metric: (&'a str, &'a str), imperial: (&'a str, &'a str)) -> Self {
+        Self {
+            metric,
+            imperial,
+            // already in mm
+            metric_to_si: |mm| mm,
+        }
+    }
+
+    /// Parse the field matching `units`, converting if need be. `path` is where in the response the
+    /// fields' parent object is
+    fn parse(&self, path: &str, units: Units) -> CityDataResult<f64> {
+        let (field, value) = match units {
+            Units::Metric | Units::Si => self.metric,
+            Units::Imperial => self.imperial,
+        };
+        let parsed = parse_field(&format!("{path}.{field}"), value)?;
+
+        Ok(match units {
+            Units::Si => (self.metric_to_si)(parsed),
+            Units::Metric | Units::Imperial => parsed,
+        })
+    }
+}
+
+pub(crate) fn celsius_to_kelvin(celsius: f64) -> f64 {
+    round_to_hundredths(celsius + 273.15)
+}
+
+/// Conversions produce long fractions (e.g. 12kph is 3.3333...m/s), which nobody wants to read
+fn round_to_hundredths(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+/// wttr.in gives times of day as "hmm", e.g. "0" or "1500", turn that in to "00:00" or "15:00"
+fn parse_time_of_day(path: &str, value: &str) -> CityDataResult<String> {
+    let malformed = || CityDataError::MalformedResponse {
+        path: path.to_owned(),
+        message: String::from("expected a time of day"),
+        snippet: snippet(value),
+    };
+
+    let time: u32 = value.trim().parse().map_err(|_| malformed())?;
+    let (hours, minutes) = (time / 100, time % 100);
+    if hours >= 24 || minutes >= 60 {
+        return Err(malformed());
+    }
+
+    Ok(format!("{hours:02}:{minutes:02}"))
+}
+
+impl WeatherReport {
+    /// Convert the current conditions from a wttr.in response, in `units`
+    fn from_entry(entry: WeatherEntry, units: Units) -> CityDataResult<Self> {
+        let path = "current_condition[0]";
+
+        Ok(Self {
+            units,
+            provider: String::from(WTTR_IN_PROVIDER),
+            temperature: Measurement::temperature(
+                ("temp_C", &entry.temp_c),
+                ("temp_F", &entry.temp_f),
+            )
+            .parse(path, units)?,
+            feels_like: Measurement::temperature(
+                ("FeelsLikeC", &entry.feels_like_c),
+                ("FeelsLikeF", &entry.feels_like_f),
+            )
+            .parse(path, units)?,
+            wind_speed: Measurement::speed(
+                ("windspeedKmph", &entry.wind_speed_kmph),
+                ("windspeedMiles", &entry.wind_speed_miles),
+            )
+            .parse(path, units)?,
+            precipitation: Measurement::precipitation(
+                ("precipMM", &entry.precip_mm),
+                ("precipInches", &entry.precip_inches),
+            )
+            .parse(path, units)?,
+            description: join_descriptions(entry.weather_desc),
+            wind_direction: entry.wind_dir_16_point,
+            observation_time: entry.observation_time,
+            forecast: Vec::new(),
+        })
+    }
+}
+
+impl DailyForecast {
+    /// Convert the `index`th day of a wttr.in forecast in `units`, including its hourly breakdown if
+    /// `hourly` is set
+    fn from_day(index: usize, day: WeatherDay, units: Units, hourly: bool) -> CityDataResult<Self> {
+        let path = format!("weather[{index}]");
+        let astronomy = day.astronomy.into_iter().next();
+
+        let hourly = if hourly {
+            day.hourly
+                .into_iter()
+                .enumerate()
+                .map(|(hour_index, hour)| {
+                    HourlyForecast::from_hour(&format!("{path}.hourly[{hour_index}]"), hour, units)
+                })
+                .collect::<CityDataResult<_>>()?
+        } else {
+            Vec::new()
+        };
+
+        Ok(Self {
+            min_temperature: Measurement::temperature(
+                ("mintempC", &day.min_temp_c),
+                ("mintempF", &day.min_temp_f),
+            )
+            .parse(&path, units)?,
+            max_temperature: Measurement::temperature(
+                ("maxtempC", &day.max_temp_c),
+                ("maxtempF", &day.max_temp_f),
+            )
+            .parse(&path, units)?,
+            avg_temperature: Measurement::temperature(
+                ("avgtempC", &day.avg_temp_c),
+                ("avgtempF", &day.avg_temp_f),
+            )
+            .parse(&path, units)?,
+            sunrise: astronomy.as_ref().map(|a| a.sunrise.clone()),
+            sunset: astronomy.map(|a| a.sunset),
+            date: day.date,
+            hourly,
+        })
+    }
+}
+
+impl HourlyForecast {
+    /// Convert an entry of a wttr.in hourly forecast, found at `path` in the response, in `units`
+    fn from_hour(path: &str, hour: WeatherHour, units: Units) -> CityDataResult<Self> {
+        Ok(Self {
+            time: parse_time_of_day(&format!("{path}.time"), &hour.time)?,
+            temperature: Measurement::temperature(("tempC", &hour.temp_c), ("tempF", &hour.temp_f))
+                .parse(path, units)?,
+            feels_like: Measurement::temperature(
+                ("FeelsLikeC", &hour.feels_like_c),
+                ("FeelsLikeF", &hour.feels_like_f),
+            )
+            .parse(path, units)?,
+            wind_speed: Measurement::speed(
+                ("windspeedKmph", &hour.wind_speed_kmph),
+                ("windspeedMiles", &hour.wind_speed_miles),
+            )
+            .parse(path, units)?,
+            precipitation: Measurement::precipitation(
+                ("precipMM", &hour.precip_mm),
+                ("precipInches", &hour.precip_inches),
+            )
+            .parse(path, units)?,
+            chance_of_rain: Some(parse_field(
+                &format!("{path}.chanceofrain"),
+                &hour.chance_of_rain,
+            )?),
+            description: join_descriptions(hour.weather_desc),
+            wind_direction: hour.wind_dir_16_point,
+        })
+    }
+}
+
+/// Renders current conditions on the first line, followed by a line per forecast day (and hour)
+impl Display for WeatherReport {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        // TODO: implement me! You'll need to parse some extra fields out of the
-        // API's response by editing `WeatherEntry` above. Try hitting
-        // <http://wttr.in/SanJose?format=j1> in your browser to see what fields are
-        // available to you, and check out the serde docs <https://serde.rs/container-attrs.html>
-        // for pointers on deserialization (`city_stats_api.rs` also provides a decent template)
-        f.write_str("Incomplete!")
+        let (temperature, speed) = (self.units.temperature_label(), self.units.speed_label());
+
+        f.write_fmt(format_args!(
+            "Weather at {}: {}{temperature} (feels like {}{temperature}) and {} with winds from {} at {}{speed}",
+            self.observation_time,
+            self.temperature,
+            self.feels_like,
+            self.description,
+            self.wind_direction,
+            self.wind_speed
+        ))?;
+
+        if self.precipitation > 0.0 {
+            f.write_fmt(format_args!(
+                ", {}{} of precipitation",
+                self.precipitation,
+                self.units.precipitation_label()
+            ))?;
+        }
+
+        for day in &self.forecast {
+            f.write_fmt(format_args!("\n"))?;
+            day.fmt_in(f, self.units)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl DailyForecast {
+    /// Render as text, labelled with `units`. Forecasts don't know their own units (they live on the
+    /// report), so this is used in place of a `Display` impl
+    fn fmt_in(&self, f: &mut std::fmt::Formatter<'_>, units: Units) -> std::fmt::Result {
+        let temperature = units.temperature_label();
+
+        f.write_fmt(format_args!(
+            "Forecast for {}: {}{temperature} to {}{temperature} (averaging {}{temperature})",
+            self.date, self.min_temperature, self.max_temperature, self.avg_temperature
+        ))?;
+
+        if let (Some(sunrise), Some(sunset)) = (&self.sunrise, &self.sunset) {
+            f.write_fmt(format_args!(
+                ", sunrise at {sunrise} and sunset at {sunset}"
+            ))?;
+        }
+
+        for hour in &self.hourly {
+            f.write_fmt(format_args!("\n  "))?;
+            hour.fmt_in(f, units)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl HourlyForecast {
+    /// Render as text, labelled with `units`, see `DailyForecast::fmt_in`
+    fn fmt_in(&self, f: &mut std::fmt::Formatter<'_>, units: Units) -> std::fmt::Result {
+        let (temperature, speed) = (units.temperature_label(), units.speed_label());
+
+        f.write_fmt(format_args!(
+            "{}: {}{temperature} (feels like {}{temperature}) and {} with winds from {} at {}{speed}",
+            self.time,
+            self.temperature,
+            self.feels_like,
+            self.description,
+            self.wind_direction,
+            self.wind_speed,
+        ))?;
+
+        if let Some(chance_of_rain) = self.chance_of_rain {
+            f.write_fmt(format_args!(", {chance_of_rain}% chance of rain"))?;
+        }
+
+        if self.precipitation > 0.0 {
+            f.write_fmt(format_args!(
+                " ({}{} expected)",
+                self.precipitation,
+                units.precipitation_label()
+            ))?;
+        }
+
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::weather_api::query_weather_api;
+    use axum::{routing::get, Router};
+
+    use crate::{
+        test_utils::spawn_fixture_server, weather_api::query_weather_api, ApiEndpoint, CityData,
+        CityDataError, CityQuery, Coordinates, ForecastOptions, Units,
+    };
+
+    use super::{
+        fetch_weather_data, parse_time_of_day, request_path_for_query, DailyForecast,
+        HourlyForecast, WeatherDescription, WeatherEntry, WeatherReport, WEATHER_API_ARGS,
+    };
+
+    /// Serve the canned wttr.in response for "San Jose", returning the endpoint to reach it on
+    async fn spawn_wttr_in_fixture() -> ApiEndpoint {
+        let router = Router::new().route(
+            "/SanJose",
+            get(|| async { include_str!("../tests/fixtures/wttr_in.json") }),
+        );
+        let base_url = spawn_fixture_server(router).await;
 
-    use super::WeatherEntry;
+        ApiEndpoint::new(format!("{base_url}/"), WEATHER_API_ARGS)
+    }
 
     #[tokio::test]
     async fn test_query_api() {
+        // serve a canned wttr.in response on the path we expect "San Jose" to be requested on
+        let endpoint = spawn_wttr_in_fixture().await;
+
         let client = reqwest::Client::builder()
             .user_agent("rust_toys_test")
             .build()
             .expect("Failed to build user agent!");
 
-        query_weather_api(&client, "San Jose").await.expect("WARNING: Failed to query or parse geocoding data for a known city, this means the API is not reachable or its response format has changed");
+        let response = query_weather_api(&client, &endpoint, &CityQuery::new("San Jose")).await.expect("Failed to query or parse weather data from the fixture server, this means our response parsing has changed");
+        let report = WeatherReport::from_entry(
+            response
+                .current_condition
+                .into_iter()
+                .next()
+                .expect("expected a current condition"),
+            Units::Metric,
+        )
+        .expect("expected a valid entry");
+        assert_eq!(report.temperature, 20.0);
+    }
+
+    /// Fetch a report from the fixture server, expecting it to succeed
+    async fn fetch_report(
+        client: &reqwest::Client,
+        endpoint: &ApiEndpoint,
+        query: CityQuery,
+    ) -> WeatherReport {
+        match fetch_weather_data(client, endpoint, query).await {
+            Ok(CityData::Weather(report)) => report,
+            other => panic!("Expected a weather report, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fetch_forecast() {
+        let endpoint = spawn_wttr_in_fixture().await;
+        let client = reqwest::Client::new();
+
+        let fetch = |days, hourly| {
+            fetch_report(
+                &client,
+                &endpoint,
+                CityQuery::new("San Jose").with_forecast(ForecastOptions { days, hourly }),
+            )
+        };
+
+        // no forecast unless we ask for one
+        assert!(fetch(0, false).await.forecast.is_empty());
+
+        let report = fetch(2, false).await;
+        assert_eq!(report.forecast.len(), 2);
+        assert_eq!(report.forecast[0].date, "2024-10-01");
+        assert_eq!(report.forecast[0].max_temperature, 27.0);
+        assert_eq!(report.forecast[1].sunrise.as_deref(), Some("07:00 AM"));
+        assert!(report.forecast[0].hourly.is_empty());
+
+        // wttr.in only has 3 days, so that's all we can give
+        let report = fetch(7, true).await;
+        assert_eq!(report.forecast.len(), 3);
+        let hourly = &report.forecast[2].hourly;
+        assert_eq!(hourly.len(), 8);
+        assert_eq!(hourly[0].time, "00:00");
+        assert_eq!(hourly[5].time, "15:00");
+        assert_eq!(hourly[4].chance_of_rain, Some(10.0));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_in_units() {
+        let endpoint = spawn_wttr_in_fixture().await;
+        let client = reqwest::Client::new();
+        let query = CityQuery::new("San Jose").with_forecast(ForecastOptions {
+            days: 1,
+            hourly: true,
+        });
+
+        // imperial picks out wttr.in's imperial fields
+        let report = fetch_report(
+            &client,
+            &endpoint,
+            query.clone().with_units(Units::Imperial),
+        )
+        .await;
+        assert_eq!(report.units, Units::Imperial);
+        assert_eq!(report.temperature, 68.0);
+        assert_eq!(report.feels_like, 70.0);
+        assert_eq!(report.wind_speed, 7.0);
+        assert_eq!(report.forecast[0].max_temperature, 81.0);
+        assert_eq!(report.forecast[0].hourly[0].temperature, 63.0);
+
+        // SI is converted from metric
+        let report = fetch_report(&client, &endpoint, query.with_units(Units::Si)).await;
+        assert_eq!(report.temperature, 293.15);
+        assert_eq!(report.wind_speed, 3.33);
+        assert_eq!(report.forecast[0].min_temperature, 289.15);
     }
 
     #[test]
-    fn test_format_response() {
-        let entry = WeatherEntry {
+    fn test_parse_time_of_day() {
+        assert_eq!(parse_time_of_day("time", "0").unwrap(), "00:00");
+        assert_eq!(parse_time_of_day("time", "930").unwrap(), "09:30");
+        assert_eq!(parse_time_of_day("time", "2100").unwrap(), "21:00");
+
+        assert!(matches!(
+            parse_time_of_day("weather[0].hourly[1].time", "2500"),
+            Err(CityDataError::MalformedResponse { path, .. }) if path == "weather[0].hourly[1].time"
+        ));
+        assert!(parse_time_of_day("time", "noon").is_err());
+    }
+
+    #[test]
+    fn test_request_path() {
+        let endpoint = ApiEndpoint::wttr_in();
+        let query = CityQuery::new("San Jose");
+        assert_eq!(
+            request_path_for_query(&endpoint, &query),
+            "http://wttr.in/SanJose?format=j1"
+        );
+        assert_eq!(
+            request_path_for_query(&endpoint, &CityQuery::new("a&b=c#d")),
+            "http://wttr.in/a%26b%3Dc%23d?format=j1"
+        );
+
+        // once geocoded, the coordinates win out over the name
+        let query = query.with_coordinates(Coordinates {
+            latitude: 9.932_5,
+            longitude: -84.079_6,
+        });
+        assert_eq!(
+            request_path_for_query(&endpoint, &query),
+            "http://wttr.in/9.9325,-84.0796?format=j1"
+        );
+    }
+
+    fn make_test_entry() -> WeatherEntry {
+        WeatherEntry {
             observation_time: String::from("10:09 PM"),
             temp_c: String::from("20"),
+            temp_f: String::from("68"),
             feels_like_c: String::from("21"),
-        };
+            feels_like_f: String::from("70"),
+            weather_desc: vec![WeatherDescription {
+                value: String::from("Sunny"),
+            }],
+            wind_dir_16_point: String::from("ESE"),
+            wind_speed_kmph: String::from("12"),
+            wind_speed_miles: String::from("7"),
+            precip_mm: String::from("0.0"),
+            precip_inches: String::from("0.0"),
+        }
+    }
+
+    #[test]
+    fn test_format_response() {
+        let report = WeatherReport::from_entry(make_test_entry(), Units::Metric)
+            .expect("expected a valid entry");
 
         let expected_format = String::from(
             "Weather at 10:09 PM: 20C (feels like 21C) and Sunny with winds from ESE at 12kph",
         );
 
-        assert_eq!(format!("{entry}"), expected_format);
-        assert_eq!(entry.to_string(), expected_format);
+        assert_eq!(format!("{report}"), expected_format);
+        assert_eq!(report.to_string(), expected_format);
+    }
+
+    #[test]
+    fn test_format_in_units() {
+        let mut entry = make_test_entry();
+        entry.precip_inches = String::from("0.1");
+        let report =
+            WeatherReport::from_entry(entry, Units::Imperial).expect("expected a valid entry");
+
+        assert_eq!(
+            report.to_string(),
+            "Weather at 10:09 PM: 68F (feels like 70F) and Sunny with winds from ESE at 7mph, 0.1in of precipitation"
+        );
+
+        let report = WeatherReport::from_entry(make_test_entry(), Units::Si)
+            .expect("expected a valid entry");
+        assert_eq!(
+            report.to_string(),
+            "Weather at 10:09 PM: 293.15K (feels like 294.15K) and Sunny with winds from ESE at 3.33m/s"
+        );
+    }
+
+    #[test]
+    fn test_format_forecast() {
+        let mut report = WeatherReport::from_entry(make_test_entry(), Units::Metric)
+            .expect("expected a valid entry");
+        report.forecast = vec![DailyForecast {
+            date: String::from("2024-10-01"),
+            min_temperature: 16.0,
+            max_temperature: 27.0,
+            avg_temperature: 21.0,
+            sunrise: Some(String::from("06:59 AM")),
+            sunset: Some(String::from("06:50 PM")),
+            hourly: vec![HourlyForecast {
+                time: String::from("12:00"),
+                temperature: 25.0,
+                feels_like: 26.0,
+                description: String::from("Sunny"),
+                wind_direction: String::from("NW"),
+                wind_speed: 14.0,
+                precipitation: 0.0,
+                chance_of_rain: Some(10.0),
+            }],
+        }];
+
+        assert_eq!(
+            report.to_string(),
+            "Weather at 10:09 PM: 20C (feels like 21C) and Sunny with winds from ESE at 12kph\n\
+             Forecast for 2024-10-01: 16C to 27C (averaging 21C), sunrise at 06:59 AM and sunset at 06:50 PM\n  \
+             12:00: 25C (feels like 26C) and Sunny with winds from NW at 14kph, 10% chance of rain"
+        );
+    }
+
+    #[test]
+    fn test_malformed_entry() {
+        let mut entry = make_test_entry();
+        entry.temp_c = String::from("warm");
+
+        let error = WeatherReport::from_entry(entry, Units::Metric)
+            .expect_err("expected a malformed entry");
+        assert!(matches!(
+            error,
+            CityDataError::MalformedResponse { path, snippet, .. }
+                if path == "current_condition[0].temp_C" && snippet == "warm"
+        ));
+    }
+
+    #[test]
+    fn test_serialize_report() {
+        let report = WeatherReport::from_entry(make_test_entry(), Units::Metric)
+            .expect("expected a valid entry");
+
+        let json = serde_json::to_value(crate::CityData::Weather(report))
+            .expect("expected report to serialize");
+        assert_eq!(json["kind"], "weather");
+        assert_eq!(json["units"], "metric");
+        assert_eq!(json["provider"], "wttr.in");
+        assert_eq!(json["temperature"], 20.0);
+        assert_eq!(json["wind_direction"], "ESE");
     }
 }
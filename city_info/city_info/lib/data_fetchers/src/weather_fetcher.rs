@@ -1,46 +1,223 @@
-use tokio::sync::mpsc;
 use tokio_util::sync::CancellationToken;
 
 use crate::{
-    weather_api::fetch_weather_data, CityDataResult, CityDataSource, CityDataSourceHandle,
-    CityDataSourceTask,
+    http::probe,
+    open_meteo_fetcher::OpenMeteoFetcher,
+    spawn_fallback_task, spawn_fetcher_task,
+    weather_api::{fetch_weather_data, probe_url, OPEN_METEO_PROVIDER, WTTR_IN_PROVIDER},
+    ApiEndpoint, CityData, CityDataResult, CityDataSource, CityDataSourceHandle, CityQuery,
+    FetcherConfig, SpawnResult,
 };
 
 pub struct WeatherDataFetcher {
     // An http client we can re-use to avoid re-initializing TLS stuff
-    // and do connection pooling
+    // and do connection pooling, usually shared with our other fetchers
     http_client: reqwest::Client,
+    // where to send our requests, see `ApiEndpoint::wttr_in()` for the public default
+    endpoint: ApiEndpoint,
 }
 
 impl WeatherDataFetcher {
-    fn new() -> Self {
-        let http_client = reqwest::Client::builder()
-            .user_agent("rust_toys_test") // this API requires a user-agent for usage tracking
-            .build()
-            // in the interest of simplicity, we use `expect()` which will panic if we fail to build the
-            // client. This should almost always be avoided in production code, but is fine here as
-            // build() should rarely fail for our use case
-            .expect("Failed to build user agent!");
-        Self { http_client }
+    /// Fetch from `endpoint` using `http_client`, which can be shared with other fetchers (see
+    /// `HttpClientConfig`)
+    pub fn new(endpoint: ApiEndpoint, http_client: reqwest::Client) -> Self {
+        Self {
+            http_client,
+            endpoint,
+        }
     }
 }
 
 impl CityDataSource for WeatherDataFetcher {
-    async fn fetch_data(&self, city: String) -> CityDataResult<String> {
-        fetch_weather_data(&self.http_client, city).await
+    async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+        fetch_weather_data(&self.http_client, &self.endpoint, query).await
+    }
+
+    async fn health_check(&self) -> Option<CityDataResult<()>> {
+        Some(probe(&self.http_client, &probe_url(&self.endpoint)).await)
+    }
+
+    fn request_key(&self, query: &CityQuery) -> String {
+        query.weather_key()
+    }
+}
+
+/// Any of the weather services we know how to talk to, so they can be chained together in a
+/// `FallbackChain` (which needs all its providers to be the same type)
+pub enum WeatherProvider {
+    WttrIn(WeatherDataFetcher),
+    OpenMeteo(OpenMeteoFetcher),
+}
+
+impl WeatherProvider {
+    /// The name this provider goes by in logs and `FallbackStats`
+    pub fn name(&self) -> &'static str {
+        match self {
+            WeatherProvider::WttrIn(_) => WTTR_IN_PROVIDER,
+            WeatherProvider::OpenMeteo(_) => OPEN_METEO_PROVIDER,
+        }
+    }
+}
+
+impl CityDataSource for WeatherProvider {
+    async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+        match self {
+            WeatherProvider::WttrIn(fetcher) => fetcher.fetch_data(query).await,
+            WeatherProvider::OpenMeteo(fetcher) => fetcher.fetch_data(query).await,
+        }
+    }
+
+    async fn health_check(&self) -> Option<CityDataResult<()>> {
+        match self {
+            WeatherProvider::WttrIn(fetcher) => fetcher.health_check().await,
+            WeatherProvider::OpenMeteo(fetcher) => fetcher.health_check().await,
+        }
+    }
+
+    fn request_key(&self, query: &CityQuery) -> String {
+        match self {
+            WeatherProvider::WttrIn(fetcher) => fetcher.request_key(query),
+            WeatherProvider::OpenMeteo(fetcher) => fetcher.request_key(query),
+        }
     }
 }
 
-pub fn spawn_weather_fetcher_task(cancellation_token: CancellationToken) -> CityDataSourceHandle {
-    let fetcher = WeatherDataFetcher::new();
-    let (sender, receiver) = mpsc::channel(16);
+pub fn spawn_weather_fetcher_task(
+    config: FetcherConfig,
+    http_client: reqwest::Client,
+    cancellation_token: CancellationToken,
+) -> SpawnResult<CityDataSourceHandle> {
+    let fetcher = WeatherDataFetcher::new(config.endpoint.clone(), http_client);
+
+    spawn_fetcher_task("weather", fetcher, &config, cancellation_token)
+}
+
+/// Like `spawn_weather_fetcher_task`, but when wttr.in (at `config.endpoint`) fails, Open-Meteo (at
+/// `fallback_endpoint`) is asked instead. The rest of `config` applies to both
+///
+/// Each provider gets its own circuit breaker, retries and rate limit (if configured), so while wttr.in is
+/// down requests go straight to Open-Meteo rather than waiting on wttr.in to fail first. Caching applies to
+/// the chain as a whole
+pub fn spawn_weather_fetcher_task_with_fallback(
+    config: FetcherConfig,
+    fallback_endpoint: ApiEndpoint,
+    http_client: reqwest::Client,
+    cancellation_token: CancellationToken,
+) -> SpawnResult<CityDataSourceHandle> {
+    fallback_endpoint.validate("weather")?;
+    let providers = [
+        WeatherProvider::WttrIn(WeatherDataFetcher::new(
+            config.endpoint.clone(),
+            http_client.clone(),
+        )),
+        WeatherProvider::OpenMeteo(OpenMeteoFetcher::new(fallback_endpoint, http_client)),
+    ];
+
+    spawn_fallback_task(
+        "weather",
+        Vec::from(providers.map(|provider| (provider.name(), provider))),
+        &config,
+        cancellation_token,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use axum::{extract::Query, http::StatusCode, routing::get, Router};
+
+    use crate::{
+        fallback::FallbackChain, open_meteo_api::OPEN_METEO_API_ARGS,
+        open_meteo_fetcher::OpenMeteoFetcher, test_utils::spawn_fixture_server,
+        weather_api::WEATHER_API_ARGS, ApiEndpoint, CityData, CityDataSource, CityQuery,
+        Coordinates,
+    };
+
+    use super::{WeatherDataFetcher, WeatherProvider};
+
+    /// Serve wttr.in's canned response for "San Jose", but fail with a 503 for anything else
+    async fn spawn_wttr_in_stub() -> ApiEndpoint {
+        let router = Router::new()
+            .route(
+                "/SanJose",
+                get(|| async { include_str!("../tests/fixtures/wttr_in.json") }),
+            )
+            .fallback(|| async { StatusCode::SERVICE_UNAVAILABLE });
+        let base_url = spawn_fixture_server(router).await;
+
+        ApiEndpoint::new(format!("{base_url}/"), WEATHER_API_ARGS)
+    }
+
+    /// Serve Open-Meteo's canned response, for any location
+    async fn spawn_open_meteo_stub() -> ApiEndpoint {
+        let router = Router::new().route(
+            "/v1/forecast",
+            get(|Query(_): Query<HashMap<String, String>>| async {
+                include_str!("../tests/fixtures/open_meteo.json")
+            }),
+        );
+        let base_url = spawn_fixture_server(router).await;
+
+        ApiEndpoint::new(format!("{base_url}/v1/forecast?"), OPEN_METEO_API_ARGS)
+    }
+
+    async fn make_chain() -> FallbackChain<WeatherProvider> {
+        let providers = [
+            WeatherProvider::WttrIn(WeatherDataFetcher::new(
+                spawn_wttr_in_stub().await,
+                reqwest::Client::new(),
+            )),
+            WeatherProvider::OpenMeteo(OpenMeteoFetcher::new(
+                spawn_open_meteo_stub().await,
+                reqwest::Client::new(),
+            )),
+        ];
+
+        FallbackChain::new(Vec::from(
+            providers.map(|provider| (provider.name(), provider)),
+        ))
+    }
+
+    fn provider_of(data: &CityData) -> &str {
+        match data {
+            CityData::Weather(report) => &report.provider,
+            _ => panic!("Expected a weather report, got {data:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_primary_answers() {
+        let chain = make_chain().await;
+
+        let data = chain
+            .fetch_data(CityQuery::new("San Jose"))
+            .await
+            .expect("expected wttr.in to answer");
+
+        assert_eq!(provider_of(&data), "wttr.in");
+        assert_eq!(chain.stats().answered_by("wttr.in"), 1);
+        assert_eq!(chain.stats().answered_by("open-meteo"), 0);
+    }
+
+    #[tokio::test]
+    async fn test_falls_back_to_open_meteo() {
+        let chain = make_chain().await;
 
-    tokio::spawn(async move {
-        let mut task = CityDataSourceTask::new(fetcher);
-        task.run(receiver, cancellation_token).await;
-    });
+        // our wttr.in stub only knows "San Jose" by name, so looking it up by coordinates fails
+        let query = CityQuery::new("San Jose").with_coordinates(Coordinates {
+            latitude: 37.336_166_3,
+            longitude: -121.890_591,
+        });
+        let data = chain
+            .fetch_data(query)
+            .await
+            .expect("expected open-meteo to answer");
 
-    CityDataSourceHandle {
-        data_request_sender: sender,
+        assert_eq!(provider_of(&data), "open-meteo");
+        let stats = chain.stats();
+        assert_eq!(stats.answered_by("wttr.in"), 0);
+        assert_eq!(stats.answered_by("open-meteo"), 1);
+        assert_eq!(stats.last_answered(), Some("open-meteo"));
     }
 }
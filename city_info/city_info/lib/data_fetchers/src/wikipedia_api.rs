@@ -0,0 +1,274 @@
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    http::{get, parse_json, probe_query},
+    ApiEndpoint, CityData, CityDataError, CityDataResult, CityQuery,
+};
+
+impl ApiEndpoint {
+    /// The public Wikipedia REST summary endpoint for `language`, e.g. "en" or "fr" (the subdomain of the
+    /// Wikipedia to read)
+    pub fn wikipedia(language: &str) -> Self {
+        Self::new(
+            format!("https://{language}.wikipedia.org/api/rest_v1/page/summary/"),
+            "",
+        )
+    }
+}
+
+/// The title of the page we expect to describe the city, named as the geocoder found it if it has been
+/// geocoded (so "Bombay" or "new york" find the right page), otherwise as the user asked for it. If a state
+/// was given we ask for e.g. "Springfield, Illinois", which is how Wikipedia names most cities that share a
+/// name
+fn page_title_for_query(query: &CityQuery) -> String {
+    let city = query.place_name.as_deref().unwrap_or(&query.city).trim();
+    let title = match &query.state {
+        Some(state) => format!("{city}, {}", state.trim()),
+        None => city.to_owned(),
+    };
+
+    // Wikipedia titles use underscores for spaces, and anything that would end the path needs escaping
+    title
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join("_")
+        .replace('%', "%25")
+        .replace('/', "%2F")
+        .replace('?', "%3F")
+        .replace('#', "%23")
+}
+
+/// A request Wikipedia should always be able to answer, for health checks (see `http::probe`)
+pub(crate) fn probe_url(endpoint: &ApiEndpoint) -> String {
+    endpoint.url_for(&page_title_for_query(&probe_query()))
+}
+
+/// Fetches the summary of a city's Wikipedia page using the REST API:
+/// <https://en.wikipedia.org/api/rest_v1/#/Page%20content/get_page_summary__title_>
+pub(crate) async fn fetch_wikipedia_summary(
+    http_client: &reqwest::Client,
+    endpoint: &ApiEndpoint,
+    query: CityQuery,
+) -> CityDataResult<CityData> {
+    let url = endpoint.url_for(&page_title_for_query(&query));
+
+    let response = match get(http_client, &url).await {
+        Ok(response) => response,
+        // there's no page by that name
+        Err(CityDataError::UpstreamStatus { status: 404 }) => {
+            return Err(CityDataError::NotFound(query.city))
+        }
+        Err(error) => return Err(error),
+    };
+    let summary_response = parse_json::<WikipediaSummaryResponse>(response).await?;
+
+    // a disambiguation page's summary is just "X may refer to:", but it does at least link to the page
+    // listing every X, so we still return it rather than failing the whole request
+    if summary_response.page_type == "disambiguation" {
+        tracing::info!(
+            "Wikipedia page for {:?} is a disambiguation page, try narrowing by state",
+            query.city
+        );
+    }
+
+    Ok(CityData::Wikipedia(WikipediaSummary::from(
+        summary_response,
+    )))
+}
+
+/// A struct representing the parts of Wikipedia's page summary we're interested in
+#[derive(Deserialize)]
+struct WikipediaSummaryResponse {
+    #[serde(rename = "type")]
+    page_type: String,
+    title: String,
+    #[serde(default)]
+    description: Option<String>,
+    extract: String,
+    #[serde(default)]
+    thumbnail: Option<WikipediaThumbnail>,
+    content_urls: WikipediaContentUrls,
+    lang: String,
+}
+
+#[derive(Deserialize)]
+struct WikipediaThumbnail {
+    source: String,
+}
+
+#[derive(Deserialize)]
+struct WikipediaContentUrls {
+    desktop: WikipediaPageUrls,
+}
+
+#[derive(Deserialize)]
+struct WikipediaPageUrls {
+    page: String,
+}
+
+/// The lead of a city's Wikipedia page
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct WikipediaSummary {
+    /// the title of the page, which may differ from the city asked for if Wikipedia redirected us
+    pub title: String,
+    /// a short description, e.g. "City in California, United States"
+    pub description: Option<String>,
+    /// the page's lead paragraph, as plain text
+    pub extract: String,
+    pub thumbnail_url: Option<String>,
+    /// the canonical link to the page
+    pub page_url: String,
+    /// which language the summary is in, e.g. "en"
+    pub language: String,
+}
+
+impl From<WikipediaSummaryResponse> for WikipediaSummary {
+    fn from(response: WikipediaSummaryResponse) -> Self {
+        Self {
+            title: response.title,
+            description: response.description,
+            extract: response.extract,
+            thumbnail_url: response.thumbnail.map(|thumbnail| thumbnail.source),
+            page_url: response.content_urls.desktop.page,
+            language: response.lang,
+        }
+    }
+}
+
+impl Display for WikipediaSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("About {}", self.title))?;
+        if let Some(description) = &self.description {
+            f.write_fmt(format_args!(" ({description})"))?;
+        }
+
+        f.write_fmt(format_args!(
+            ": {}\nRead more at {}",
+            self.extract, self.page_url
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{extract::Path, http::StatusCode, response::IntoResponse, routing::get, Router};
+
+    use crate::{
+        test_utils::spawn_fixture_server, ApiEndpoint, CityData, CityDataError, CityDataResult,
+        CityQuery,
+    };
+
+    use super::{fetch_wikipedia_summary, page_title_for_query, WikipediaSummary};
+
+    /// Serve the canned summary for San Jose, a disambiguation page for Springfield, and a 404 for
+    /// anything else. Pages are served under `/{language}/`, so we can check the language is used
+    async fn spawn_wikipedia_stub() -> String {
+        let router = Router::new().route(
+            "/:language/page/summary/:title",
+            get(|Path((language, title)): Path<(String, String)>| async move {
+                match (language.as_str(), title.as_str()) {
+                    (_, "San_Jose,_California") => (
+                        StatusCode::OK,
+                        include_str!("../tests/fixtures/wikipedia_summary.json")
+                            .replace("\"lang\": \"en\"", &format!("\"lang\": \"{language}\"")),
+                    )
+                        .into_response(),
+                    (_, "Springfield") => (
+                        StatusCode::OK,
+                        r#"{"type": "disambiguation", "title": "Springfield", "extract": "Springfield may refer to:",
+                            "content_urls": {"desktop": {"page": "https://en.wikipedia.org/wiki/Springfield"}}, "lang": "en"}"#
+                            .to_owned(),
+                    )
+                        .into_response(),
+                    _ => StatusCode::NOT_FOUND.into_response(),
+                }
+            }),
+        );
+
+        spawn_fixture_server(router).await
+    }
+
+    async fn fetch_summary(language: &str, query: CityQuery) -> CityDataResult<CityData> {
+        let base_url = spawn_wikipedia_stub().await;
+        let endpoint = ApiEndpoint::new(format!("{base_url}/{language}/page/summary/"), "");
+
+        fetch_wikipedia_summary(&reqwest::Client::new(), &endpoint, query).await
+    }
+
+    #[tokio::test]
+    async fn test_fetch() {
+        let result = fetch_summary("en", CityQuery::new("San Jose").with_state("California")).await;
+
+        let Ok(CityData::Wikipedia(summary)) = result else {
+            panic!("Expected a Wikipedia summary, got {result:?}");
+        };
+        assert_eq!(summary.title, "San Jose, California");
+        assert!(summary.extract.starts_with("San Jose is a city"));
+        assert_eq!(
+            summary.page_url,
+            "https://en.wikipedia.org/wiki/San_Jose,_California"
+        );
+        assert!(summary
+            .thumbnail_url
+            .is_some_and(|url| url.ends_with("320px-San_Jose_skyline.jpg")));
+        assert_eq!(summary.language, "en");
+
+        let result = fetch_summary("fr", CityQuery::new("San Jose").with_state("California")).await;
+        assert!(matches!(result, Ok(CityData::Wikipedia(summary)) if summary.language == "fr"));
+    }
+
+    #[tokio::test]
+    async fn test_not_found_or_ambiguous() {
+        let result = fetch_summary("en", CityQuery::new("Nowhere")).await;
+        assert!(matches!(result, Err(CityDataError::NotFound(city)) if city == "Nowhere"));
+
+        // we can't tell which Springfield is meant, but can point at the list of them
+        let result = fetch_summary("en", CityQuery::new("Springfield")).await;
+        assert!(matches!(
+            result,
+            Ok(CityData::Wikipedia(summary)) if summary.page_url.ends_with("/wiki/Springfield")
+        ));
+    }
+
+    #[test]
+    fn test_page_title() {
+        assert_eq!(
+            page_title_for_query(&CityQuery::new(" San  Jose ")),
+            "San_Jose"
+        );
+        assert_eq!(
+            page_title_for_query(&CityQuery::new("Springfield").with_state("Illinois")),
+            "Springfield,_Illinois"
+        );
+        assert_eq!(page_title_for_query(&CityQuery::new("A/B?")), "A%2FB%3F");
+
+        // once geocoded, the page is looked up by what the place is actually called
+        let mut query = CityQuery::new("bombay");
+        query.place_name = Some(String::from("Mumbai"));
+        assert_eq!(page_title_for_query(&query), "Mumbai");
+        assert_eq!(
+            ApiEndpoint::wikipedia("de").base_url,
+            "https://de.wikipedia.org/api/rest_v1/page/summary/"
+        );
+    }
+
+    #[test]
+    fn test_format() {
+        let summary = WikipediaSummary {
+            title: String::from("San Jose, California"),
+            description: Some(String::from("City in California, United States")),
+            extract: String::from("San Jose is a city in California."),
+            thumbnail_url: None,
+            page_url: String::from("https://en.wikipedia.org/wiki/San_Jose,_California"),
+            language: String::from("en"),
+        };
+
+        assert_eq!(
+            summary.to_string(),
+            "About San Jose, California (City in California, United States): San Jose is a city in California.\n\
+             Read more at https://en.wikipedia.org/wiki/San_Jose,_California"
+        );
+    }
+}
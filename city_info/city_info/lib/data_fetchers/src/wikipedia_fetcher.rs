@@ -0,0 +1,48 @@
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    http::probe,
+    spawn_fetcher_task,
+    wikipedia_api::{fetch_wikipedia_summary, probe_url},
+    ApiEndpoint, CityData, CityDataResult, CityDataSource, CityDataSourceHandle, CityQuery,
+    FetcherConfig, SpawnResult,
+};
+
+pub struct WikipediaFetcher {
+    // An http client we can re-use to avoid re-initializing TLS stuff
+    // and do connection pooling, usually shared with our other fetchers
+    http_client: reqwest::Client,
+    // where to send our requests, see `ApiEndpoint::wikipedia()` for the public default
+    endpoint: ApiEndpoint,
+}
+
+impl WikipediaFetcher {
+    /// Fetch from `endpoint` using `http_client`, which can be shared with other fetchers (see
+    /// `HttpClientConfig`)
+    pub fn new(endpoint: ApiEndpoint, http_client: reqwest::Client) -> Self {
+        Self {
+            http_client,
+            endpoint,
+        }
+    }
+}
+
+impl CityDataSource for WikipediaFetcher {
+    async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+        fetch_wikipedia_summary(&self.http_client, &self.endpoint, query).await
+    }
+
+    async fn health_check(&self) -> Option<CityDataResult<()>> {
+        Some(probe(&self.http_client, &probe_url(&self.endpoint)).await)
+    }
+}
+
+pub fn spawn_wikipedia_fetcher_task(
+    config: FetcherConfig,
+    http_client: reqwest::Client,
+    cancellation_token: CancellationToken,
+) -> SpawnResult<CityDataSourceHandle> {
+    let fetcher = WikipediaFetcher::new(config.endpoint.clone(), http_client);
+
+    spawn_fetcher_task("wikipedia", fetcher, &config, cancellation_token)
+}
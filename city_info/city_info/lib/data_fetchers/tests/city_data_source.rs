@@ -1,7 +1,22 @@
-use std::time::Duration;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
-use data_fetchers::{CityDataRequest, CityDataSource, CityDataSourceTask};
-use tokio::sync::{mpsc, oneshot};
+use data_fetchers::{
+    health::{HealthCheckConfig, HealthStatus},
+    rate_limit::RateLimitConfig,
+    spawn_data_source_task, ApiEndpoint, CityData, CityDataError, CityDataRequest, CityDataResult,
+    CityDataSource, CityDataSourceTask, CityQuery, FetcherConfig, PlaceRecord, SpawnError,
+    DEFAULT_MAX_IN_FLIGHT,
+};
+use tokio::{
+    sync::{mpsc, oneshot, Semaphore},
+    time::Instant,
+};
 use tokio_util::sync::CancellationToken;
 
 struct TestDataSource;
@@ -9,8 +24,138 @@ struct TestDataSource;
 // NOTE: This would be a fantastic application for an automock (https://docs.rs/mockall/latest/mockall/attr.automock.html)
 // for now I've implemented a "mock" manually
 impl CityDataSource for TestDataSource {
-    async fn fetch_data(&self, city: String) -> data_fetchers::CityDataResult<String> {
-        Ok(format!("Test result for {city}"))
+    async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+        Ok(test_place(query.city))
+    }
+}
+
+fn test_place(city: String) -> CityData {
+    CityData::Place(PlaceRecord {
+        name: city.clone(),
+        display_name: format!("Test result for {city}"),
+        latitude: 0.0,
+        longitude: 0.0,
+        ..PlaceRecord::default()
+    })
+}
+
+/// A data source where requests for "slow" cities block until the test opens the `gate`, and which
+/// keeps track of how many requests it is working on at once
+#[derive(Clone)]
+struct GatedDataSource {
+    gate: Arc<Semaphore>,
+    calls: Arc<AtomicUsize>,
+    in_flight: Arc<AtomicUsize>,
+    max_in_flight_seen: Arc<AtomicUsize>,
+}
+
+impl GatedDataSource {
+    fn new() -> Self {
+        Self {
+            // the gate starts closed
+            gate: Arc::new(Semaphore::new(0)),
+            calls: Arc::default(),
+            in_flight: Arc::default(),
+            max_in_flight_seen: Arc::default(),
+        }
+    }
+}
+
+impl CityDataSource for GatedDataSource {
+    async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+        let city = query.city;
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        let guard = InFlightGuard::new(&self.in_flight);
+        self.max_in_flight_seen
+            .fetch_max(guard.in_flight, Ordering::SeqCst);
+
+        if city.starts_with("Slow") {
+            self.gate
+                .acquire()
+                .await
+                .expect("gate should never be closed")
+                .forget();
+        }
+
+        drop(guard);
+        Ok(test_place(city))
+    }
+}
+
+/// A data source whose health check fails while `failing` is set, counting how many checks it has run
+#[derive(Clone, Default)]
+struct ProbedDataSource {
+    failing: Arc<AtomicBool>,
+    checks: Arc<AtomicUsize>,
+}
+
+impl CityDataSource for ProbedDataSource {
+    async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+        Ok(test_place(query.city))
+    }
+
+    async fn health_check(&self) -> Option<CityDataResult<()>> {
+        self.checks.fetch_add(1, Ordering::SeqCst);
+        if self.failing.load(Ordering::SeqCst) {
+            Some(Err(CityDataError::ConnectionFailed(String::from(
+                "connection refused",
+            ))))
+        } else {
+            Some(Ok(()))
+        }
+    }
+}
+
+/// Tracks a request in flight, un-tracking it when dropped (including if the request is aborted part way)
+struct InFlightGuard<'a> {
+    counter: &'a AtomicUsize,
+    // how many requests were in flight once this one started
+    in_flight: usize,
+}
+
+impl<'a> InFlightGuard<'a> {
+    fn new(counter: &'a AtomicUsize) -> Self {
+        let in_flight = counter.fetch_add(1, Ordering::SeqCst) + 1;
+        Self { counter, in_flight }
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.counter.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Send a request for `city` in to a task, returning the receiver its response will arrive on
+async fn send_request(
+    request_sender: &mpsc::Sender<CityDataRequest>,
+    city: &str,
+) -> oneshot::Receiver<CityDataResult<CityData>> {
+    send_request_with_deadline(request_sender, city, None).await
+}
+
+async fn send_request_with_deadline(
+    request_sender: &mpsc::Sender<CityDataRequest>,
+    city: &str,
+    deadline: Option<Instant>,
+) -> oneshot::Receiver<CityDataResult<CityData>> {
+    let (responder, response_receiver) = oneshot::channel();
+    request_sender
+        .send(CityDataRequest {
+            query: CityQuery::new(city),
+            deadline,
+            responder,
+        })
+        .await
+        .expect("expected to send a request");
+
+    response_receiver
+}
+
+fn name_of(response: CityDataResult<CityData>) -> String {
+    match response.expect("Expected response not to be an error") {
+        CityData::Place(place) => place.name,
+        other => panic!("Expected a place, got {other:?}"),
     }
 }
 
@@ -35,7 +180,8 @@ async fn test_city_data_source_task() {
     let (response_sender, response_receiver) = oneshot::channel();
     request_sender
         .send(CityDataRequest {
-            city: String::from("Module Test Hamlet"),
+            query: CityQuery::new("Module Test Hamlet"),
+            deadline: None,
             responder: response_sender,
         })
         .await
@@ -46,7 +192,10 @@ async fn test_city_data_source_task() {
         .await
         .expect("Expected to receive a response")
         .expect("Expected response not to be an error");
-    assert_eq!(response, String::from("Test result for Module Test Hamlet"));
+    assert_eq!(
+        response.to_string(),
+        String::from("Stats for Test result for Module Test Hamlet:")
+    );
 
     // assert our task is still running happily
     assert!(!running_task_handle.is_finished());
@@ -58,3 +207,358 @@ async fn test_city_data_source_task() {
     tokio::time::sleep(Duration::from_millis(1)).await;
     assert!(running_task_handle.is_finished());
 }
+
+#[tokio::test]
+async fn test_overlapping_requests() {
+    let data_source = GatedDataSource::new();
+    let mut task = CityDataSourceTask::new(data_source.clone());
+    let (request_sender, request_receiver) = mpsc::channel(4);
+    let cancellation_token = CancellationToken::new();
+
+    tokio::spawn({
+        let child_token = cancellation_token.clone();
+        async move {
+            task.run(request_receiver, child_token).await;
+        }
+    });
+
+    // a slow request followed by a fast one, the fast one shouldn't have to wait for the slow one
+    let slow_response = send_request(&request_sender, "Slow Springs").await;
+    let fast_response = send_request(&request_sender, "Fast Falls").await;
+
+    let fast_result = tokio::time::timeout(Duration::from_secs(1), fast_response)
+        .await
+        .expect("Fast request should not be blocked behind the slow one")
+        .expect("Expected to receive a response");
+    assert_eq!(name_of(fast_result), "Fast Falls");
+
+    // now let the slow request finish
+    data_source.gate.add_permits(1);
+    let slow_result = tokio::time::timeout(Duration::from_secs(1), slow_response)
+        .await
+        .expect("Slow request should complete once the gate is open")
+        .expect("Expected to receive a response");
+    assert_eq!(name_of(slow_result), "Slow Springs");
+
+    assert_eq!(data_source.max_in_flight_seen.load(Ordering::SeqCst), 2);
+    cancellation_token.cancel();
+}
+
+#[tokio::test]
+async fn test_max_in_flight() {
+    let data_source = GatedDataSource::new();
+    let mut task = CityDataSourceTask::new(data_source.clone()).with_max_in_flight(2);
+    let (request_sender, request_receiver) = mpsc::channel(4);
+    let cancellation_token = CancellationToken::new();
+
+    tokio::spawn({
+        let child_token = cancellation_token.clone();
+        async move {
+            task.run(request_receiver, child_token).await;
+        }
+    });
+
+    let mut responses = Vec::new();
+    for city in ["Slow Hollow", "Slow Creek", "Slow Bend"] {
+        responses.push(send_request(&request_sender, city).await);
+    }
+
+    // give the task a chance to pick up as many requests as it's allowed to
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(data_source.in_flight.load(Ordering::SeqCst), 2);
+
+    // open the gate for everything, responses can arrive in any order
+    data_source.gate.add_permits(3);
+    let mut names = Vec::new();
+    for response in responses {
+        let result = tokio::time::timeout(Duration::from_secs(1), response)
+            .await
+            .expect("All requests should complete once the gate is open")
+            .expect("Expected to receive a response");
+        names.push(name_of(result));
+    }
+    names.sort();
+    assert_eq!(names, vec!["Slow Bend", "Slow Creek", "Slow Hollow"]);
+
+    // we never worked on more than our limit at once
+    assert_eq!(data_source.max_in_flight_seen.load(Ordering::SeqCst), 2);
+    cancellation_token.cancel();
+}
+
+#[tokio::test]
+async fn test_in_flight_requests_finish_after_sender_dropped() {
+    let data_source = GatedDataSource::new();
+    let mut task = CityDataSourceTask::new(data_source.clone());
+    let (request_sender, request_receiver) = mpsc::channel(1);
+
+    let running_task_handle = tokio::spawn(async move {
+        task.run(request_receiver, CancellationToken::new()).await;
+    });
+
+    let response = send_request(&request_sender, "Slow Stop").await;
+    drop(request_sender);
+
+    // the task should still answer the request it already accepted, then exit
+    data_source.gate.add_permits(1);
+    let result = tokio::time::timeout(Duration::from_secs(1), response)
+        .await
+        .expect("In flight request should complete")
+        .expect("Expected to receive a response");
+    assert_eq!(name_of(result), "Slow Stop");
+
+    tokio::time::timeout(Duration::from_secs(1), running_task_handle)
+        .await
+        .expect("Task should exit once its senders are gone")
+        .expect("Task should not panic");
+}
+
+#[tokio::test]
+async fn test_identical_requests_coalesced() {
+    let data_source = GatedDataSource::new();
+    let mut task = CityDataSourceTask::new(data_source.clone());
+    let (request_sender, request_receiver) = mpsc::channel(4);
+    let cancellation_token = CancellationToken::new();
+
+    tokio::spawn({
+        let child_token = cancellation_token.clone();
+        async move {
+            task.run(request_receiver, child_token).await;
+        }
+    });
+
+    // several requests for the same city, formatted differently, while the first is still in flight
+    let mut responses = Vec::new();
+    for city in ["Slow Springs", "slow springs", " SLOW  Springs "] {
+        responses.push(send_request(&request_sender, city).await);
+    }
+    tokio::time::sleep(Duration::from_millis(10)).await;
+
+    // everyone gets the single upstream result once it arrives
+    data_source.gate.add_permits(1);
+    for response in responses {
+        let result = tokio::time::timeout(Duration::from_secs(1), response)
+            .await
+            .expect("Coalesced request should complete once the gate is open")
+            .expect("Expected to receive a response");
+        assert_eq!(name_of(result), "Slow Springs");
+    }
+    assert_eq!(data_source.calls.load(Ordering::SeqCst), 1);
+
+    // once that fetch has completed, a new request goes upstream again
+    data_source.gate.add_permits(1);
+    let response = send_request(&request_sender, "Slow Springs").await;
+    tokio::time::timeout(Duration::from_secs(1), response)
+        .await
+        .expect("New request should complete")
+        .expect("Expected to receive a response")
+        .expect("Expected response not to be an error");
+    assert_eq!(data_source.calls.load(Ordering::SeqCst), 2);
+
+    cancellation_token.cancel();
+}
+
+#[tokio::test]
+async fn test_deadline_aborts_fetch() {
+    let data_source = GatedDataSource::new();
+    let mut task = CityDataSourceTask::new(data_source.clone());
+    let (request_sender, request_receiver) = mpsc::channel(4);
+    let cancellation_token = CancellationToken::new();
+
+    tokio::spawn({
+        let child_token = cancellation_token.clone();
+        async move {
+            task.run(request_receiver, child_token).await;
+        }
+    });
+
+    // the gate never opens, so this request can only ever time out
+    let deadline = Instant::now() + Duration::from_millis(20);
+    let response = send_request_with_deadline(&request_sender, "Slow Lane", Some(deadline)).await;
+
+    let result = tokio::time::timeout(Duration::from_secs(1), response)
+        .await
+        .expect("Expected a response once the deadline passed")
+        .expect("Expected to receive a response");
+    assert!(matches!(result, Err(CityDataError::Timeout)));
+    assert!(Instant::now() >= deadline);
+
+    // the fetch itself should have been stopped, not left running in the background
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(data_source.in_flight.load(Ordering::SeqCst), 0);
+    cancellation_token.cancel();
+}
+
+#[tokio::test]
+async fn test_abandoned_fetch_stopped() {
+    let data_source = GatedDataSource::new();
+    let mut task = CityDataSourceTask::new(data_source.clone());
+    let (request_sender, request_receiver) = mpsc::channel(4);
+    let cancellation_token = CancellationToken::new();
+
+    tokio::spawn({
+        let child_token = cancellation_token.clone();
+        async move {
+            task.run(request_receiver, child_token).await;
+        }
+    });
+
+    // a coalesced request with a later deadline keeps the fetch alive past the first deadline
+    let soon = Instant::now() + Duration::from_millis(10);
+    let later = Instant::now() + Duration::from_millis(500);
+    let first = send_request_with_deadline(&request_sender, "Slow Lane", Some(soon)).await;
+    let second = send_request_with_deadline(&request_sender, "Slow Lane", Some(later)).await;
+
+    tokio::time::sleep(Duration::from_millis(30)).await;
+    assert_eq!(data_source.in_flight.load(Ordering::SeqCst), 1);
+
+    // once the second requester hangs up there is nobody left, so the fetch is stopped
+    drop(first);
+    drop(second);
+    // poke the task so it notices
+    let other = send_request(&request_sender, "Fast Track").await;
+    other
+        .await
+        .expect("Expected to receive a response")
+        .expect("Expected response not to be an error");
+
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(data_source.in_flight.load(Ordering::SeqCst), 0);
+    cancellation_token.cancel();
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_health_checks() {
+    let data_source = ProbedDataSource::default();
+    let health_check = HealthCheckConfig {
+        interval: Duration::from_secs(30),
+        timeout: Duration::from_secs(5),
+    };
+    let cancellation_token = CancellationToken::new();
+    let handle = CityDataSourceTask::new(data_source.clone())
+        .with_health_check(Some(health_check))
+        .spawn(cancellation_token.clone())
+        .expect("expected the task to spawn");
+    assert_eq!(handle.health().status, HealthStatus::Pending);
+
+    // the first check runs straight away
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    let health = handle.health();
+    assert_eq!(health.status, HealthStatus::Healthy);
+    assert!(health.last_success.is_some());
+    assert!(health.latency.is_some());
+    assert_eq!(data_source.checks.load(Ordering::SeqCst), 1);
+
+    // and then once per interval
+    data_source.failing.store(true, Ordering::SeqCst);
+    tokio::time::sleep(Duration::from_secs(30)).await;
+    let health = handle.health();
+    assert_eq!(health.status, HealthStatus::Unhealthy);
+    assert_eq!(health.consecutive_failures, 1);
+    assert!(health
+        .last_error
+        .is_some_and(|failure| failure.error.contains("connection refused")));
+    assert_eq!(data_source.checks.load(Ordering::SeqCst), 2);
+
+    // requests are still served while unhealthy, it's up to whoever is asking what to make of it
+    let response = handle.request_data("Sickly Springs", None).await;
+    assert!(response.is_ok());
+
+    data_source.failing.store(false, Ordering::SeqCst);
+    tokio::time::sleep(Duration::from_secs(30)).await;
+    assert_eq!(handle.health().status, HealthStatus::Healthy);
+
+    cancellation_token.cancel();
+}
+
+#[tokio::test(start_paused = true)]
+async fn test_health_checks_unsupported() {
+    let cancellation_token = CancellationToken::new();
+
+    // a source without a health check is asked once, and then left alone
+    let handle = CityDataSourceTask::new(TestDataSource)
+        .with_health_check(Some(HealthCheckConfig::default()))
+        .spawn(cancellation_token.clone())
+        .expect("expected the task to spawn");
+    assert_eq!(handle.health().status, HealthStatus::Pending);
+    tokio::time::sleep(Duration::from_millis(10)).await;
+    assert_eq!(handle.health().status, HealthStatus::Unmonitored);
+
+    // checks are off unless asked for, so a source with one isn't checked either
+    let data_source = ProbedDataSource::default();
+    let handle = CityDataSourceTask::new(data_source.clone())
+        .spawn(cancellation_token.clone())
+        .expect("expected the task to spawn");
+    tokio::time::sleep(Duration::from_secs(60)).await;
+    assert_eq!(handle.health().status, HealthStatus::Unmonitored);
+    assert_eq!(data_source.checks.load(Ordering::SeqCst), 0);
+
+    cancellation_token.cancel();
+}
+
+#[test]
+fn test_spawn_outside_runtime() {
+    // no #[tokio::test], so there's no runtime to spawn on
+    let result = spawn_data_source_task(
+        TestDataSource,
+        DEFAULT_MAX_IN_FLIGHT,
+        CancellationToken::new(),
+    );
+
+    assert!(matches!(result, Err(SpawnError::NoRuntime)));
+}
+
+#[test]
+fn test_fetcher_config_validated() {
+    assert!(FetcherConfig::new(ApiEndpoint::nominatim())
+        .validate("city_stats")
+        .is_ok());
+
+    let result = FetcherConfig::new(ApiEndpoint::new("nominatim.example.com/search?q=", ""))
+        .validate("city_stats");
+    assert!(matches!(
+        result,
+        Err(SpawnError::InvalidEndpoint { fetcher: "city_stats", url, .. }) if url == "nominatim.example.com/search?q="
+    ));
+
+    let result = FetcherConfig {
+        rate_limit: Some(RateLimitConfig::per_second(0.0)),
+        ..FetcherConfig::new(ApiEndpoint::nominatim())
+    }
+    .validate("city_stats");
+    assert!(matches!(
+        result,
+        Err(SpawnError::InvalidConfig {
+            fetcher: "city_stats",
+            ..
+        })
+    ));
+
+    let result = FetcherConfig {
+        fallback_timeout: Some(Duration::ZERO),
+        ..FetcherConfig::new(ApiEndpoint::wttr_in())
+    }
+    .validate("weather");
+    assert!(matches!(
+        result,
+        Err(SpawnError::InvalidConfig {
+            fetcher: "weather",
+            ..
+        })
+    ));
+
+    let result = FetcherConfig {
+        health_check: Some(HealthCheckConfig {
+            interval: Duration::ZERO,
+            ..HealthCheckConfig::default()
+        }),
+        ..FetcherConfig::new(ApiEndpoint::nominatim())
+    }
+    .validate("city_stats");
+    assert!(matches!(
+        result,
+        Err(SpawnError::InvalidConfig {
+            fetcher: "city_stats",
+            ..
+        })
+    ));
+}
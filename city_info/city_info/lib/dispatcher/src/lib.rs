@@ -1,29 +1,152 @@
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
 use data_fetchers::{
-    city_stats_fetcher::spawn_city_stats_fetcher_task, weather_fetcher::spawn_weather_fetcher_task,
-    CityDataSourceHandle,
+    cache::CacheConfig,
+    circuit_breaker::CircuitBreakerConfig,
+    city_stats_fetcher::spawn_city_stats_fetcher_task,
+    gazetteer::{spawn_gazetteer_task, Gazetteer, GazetteerError},
+    health::{HealthCheckConfig, SourceHealth},
+    http_client::HttpClientError,
+    rate_limit::RateLimitConfig,
+    registry::{RegistryError, SourceContext},
+    sun::spawn_sun_task,
+    timezone::spawn_timezone_task,
+    timezone_boundaries::{TimezoneBoundaries, TimezoneBoundariesError},
+    weather_fetcher::{spawn_weather_fetcher_task, spawn_weather_fetcher_task_with_fallback},
+    wikipedia_fetcher::spawn_wikipedia_fetcher_task,
+    CityData, CityDataError, CityDataResult, CityDataSourceHandle, FetcherConfig,
 };
 use futures::{stream::FuturesUnordered, StreamExt};
+use serde::Serialize;
 use thiserror::Error;
-use tokio::sync::{mpsc, oneshot};
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    task::JoinError,
+    time::Instant,
+};
 use tokio_util::sync::CancellationToken;
 use tracing::{info_span, Instrument};
 
+mod metrics;
+
+// re-exported so callers can build queries without depending on `data_fetchers` directly
+pub use data_fetchers::{
+    fuzzy::CitySuggestion, gazetteer::GazetteerSource, http_client::HttpClientConfig,
+    registry::SourceRegistry, ApiEndpoint, CityQuery, ForecastOptions, Units,
+};
+
 #[derive(Debug, Error)]
 pub enum DispatcherError {
     #[error("Failed to send request on mpsc, dropped unexpectedly?")]
     MpscSendFailed(#[from] mpsc::error::SendError<DispatcherRequest>),
     #[error("Failed to send response on oneshot, dropped unexpectedly?")]
     OneshotResponseFailed(#[from] oneshot::error::RecvError),
+    #[error("Failed to make suggestions, panicked?")]
+    SuggestFailed(#[from] JoinError),
 }
 
 /// A custom `Response` type leveraging our `DispatcherError` above
 pub type DispatcherResult<T> = Result<T, DispatcherError>;
 
+/// Why the dispatcher couldn't be started
+#[derive(Debug, Error)]
+pub enum StartupError {
+    #[error("Failed to build the http client: {0}")]
+    HttpClient(#[from] HttpClientError),
+    #[error("Failed to load the gazetteer: {0}")]
+    Gazetteer(#[from] GazetteerError),
+    #[error("Failed to load the timezone boundaries: {0}")]
+    TimezoneBoundaries(#[from] TimezoneBoundariesError),
+    #[error("Failed to start the data sources: {0}")]
+    Sources(#[from] RegistryError),
+}
+
+/// How many "did you mean" suggestions to offer when a city can't be found
+const MAX_SUGGESTIONS: usize = 5;
+
+/// The built in sources the dispatcher asks about a city by default, in order. See `builtin_sources`
+pub const DEFAULT_SOURCES: [&str; 5] = ["city_stats", "weather", "sun", "timezone", "wikipedia"];
+
+/// Configuration for the dispatcher and the data fetchers it starts
+#[derive(Clone, Debug)]
+pub struct DispatcherConfig {
+    /// how to build the http client shared by every fetcher
+    pub http_client: HttpClientConfig,
+    /// the names of the sources to ask about a city, in the order they're asked. Sources later in the list
+    /// can build on the results of earlier ones, so a geocoder ("city_stats" or "gazetteer") should go first
+    pub sources: Vec<String>,
+    /// which of `sources` are geocoders, i.e. answer with where the city is. If one fails before the city
+    /// has been found, the city is looked up in the gazetteer instead
+    pub geocoders: Vec<String>,
+    /// which of `sources` we can't do without, so we're only ready (see `DispatcherHandle::readiness`) while
+    /// they're healthy. Names that aren't in `sources` are ignored. By default these are the geocoders, as
+    /// every other source builds on where they found the city
+    pub required_sources: Vec<String>,
+    /// configuration for the weather fetcher
+    pub weather: FetcherConfig,
+    /// if set, Open-Meteo is asked for the weather at this endpoint whenever the weather fetcher fails
+    pub weather_fallback: Option<ApiEndpoint>,
+    /// configuration for the city stats fetcher
+    pub city_stats: FetcherConfig,
+    /// the local index of cities that misspelled cities are matched against, and which the "gazetteer" source
+    /// geocodes with
+    pub gazetteer: GazetteerSource,
+    /// a timezone-boundary-builder GeoJSON file the "timezone" source finds cities' timezones in (see
+    /// `TimezoneBoundaries`). Without it, cities the geocoder didn't give a timezone for get the timezone
+    /// whose principal city is nearest, which can be wrong near a boundary
+    pub timezone_boundaries: Option<PathBuf>,
+    /// configuration for the Wikipedia summary fetcher
+    pub wikipedia: FetcherConfig,
+}
+
+/// By default, fetchers talk to the public APIs and cache their results. Weather changes throughout
+/// the day so is only cached briefly, whereas a city's location is about as stable as data gets.
+/// Nominatim also asks that we stay under 1 request per second, so we throttle ourselves accordingly.
+/// Every fetcher fails fast while its upstream is down and opts in to having its upstream checked on an
+/// interval (checks are rate limited like any other request, so Nominatim's take one of its tokens every 30
+/// seconds). Weather falls back to Open-Meteo if wttr.in hasn't answered within 4 seconds, well inside the
+/// REST API's 10 second request timeout
+impl Default for DispatcherConfig {
+    fn default() -> Self {
+        Self {
+            http_client: HttpClientConfig::default(),
+            sources: DEFAULT_SOURCES.map(String::from).to_vec(),
+            geocoders: ["city_stats", "gazetteer"].map(String::from).to_vec(),
+            required_sources: ["city_stats", "gazetteer"].map(String::from).to_vec(),
+            weather: FetcherConfig {
+                cache: Some(CacheConfig::new(Duration::from_secs(5 * 60))),
+                circuit_breaker: Some(CircuitBreakerConfig::default()),
+                fallback_timeout: Some(Duration::from_secs(4)),
+                health_check: Some(HealthCheckConfig::default()),
+                ..FetcherConfig::new(ApiEndpoint::wttr_in())
+            },
+            weather_fallback: Some(ApiEndpoint::open_meteo()),
+            city_stats: FetcherConfig {
+                cache: Some(CacheConfig::new(Duration::from_secs(24 * 60 * 60))),
+                rate_limit: Some(RateLimitConfig::nominatim()),
+                circuit_breaker: Some(CircuitBreakerConfig::default()),
+                health_check: Some(HealthCheckConfig::default()),
+                ..FetcherConfig::new(ApiEndpoint::nominatim())
+            },
+            gazetteer: GazetteerSource::Embedded,
+            timezone_boundaries: None,
+            wikipedia: FetcherConfig {
+                cache: Some(CacheConfig::new(Duration::from_secs(24 * 60 * 60))),
+                circuit_breaker: Some(CircuitBreakerConfig::default()),
+                health_check: Some(HealthCheckConfig::default()),
+                ..FetcherConfig::new(ApiEndpoint::wikipedia("en"))
+            },
+        }
+    }
+}
+
 /// A request to our `Dispatcher`
 #[derive(Debug)]
 pub struct DispatcherRequest {
-    // the city our Dispatcher will aggregate info for
-    city_name: String,
+    // the city our Dispatcher will aggregate info for, optionally narrowed to a country or state
+    query: CityQuery,
+    // when the requester will stop waiting for a response, passed on to each fetcher
+    deadline: Option<Instant>,
     // a oneshot channel to send the response
     response_sender: oneshot::Sender<DispatcherResponse>,
 }
@@ -34,6 +157,28 @@ struct DispatcherResponse {
     data: String,
 }
 
+/// Whether the dispatcher is in a fit state to answer requests, see `DispatcherHandle::readiness`
+#[derive(Clone, Debug, Serialize)]
+pub struct Readiness {
+    /// whether the dispatcher is running and every required source is either healthy or has no health check
+    pub ready: bool,
+    /// whether we're ready, but one of the sources that aren't required isn't, so answers may be missing its
+    /// section
+    pub degraded: bool,
+    /// the health of each source, in the order they're asked
+    pub sources: Vec<SourceReadiness>,
+}
+
+/// The health of a single source, as of its last health check
+#[derive(Clone, Debug, Serialize)]
+pub struct SourceReadiness {
+    pub name: String,
+    /// whether we're only ready while this source is, see `DispatcherConfig::required_sources`
+    pub required: bool,
+    #[serde(flatten)]
+    pub health: SourceHealth,
+}
+
 /// The "Handle" we will pass out to anything that wishes to use the `Dispatcher`
 /// Note that we can derive `Clone` because `mpsc::Sender` (multiple producer, single consumer)
 /// impls `Clone`. Every clone of the sender sends messages to the same individual consumer
@@ -41,17 +186,30 @@ struct DispatcherResponse {
 #[derive(Clone)]
 pub struct DispatcherHandle {
     request_sender: mpsc::Sender<DispatcherRequest>,
+    // the local index of city names that suggestions are made from, it's in memory so there's no need to
+    // go through the dispatcher task for these
+    gazetteer: Arc<Gazetteer>,
+    // each source's name, whether it's required and the latest results of its health checks, in the order
+    // they're asked
+    source_health: Arc<[(String, bool, watch::Receiver<SourceHealth>)]>,
 }
 
 impl DispatcherHandle {
-    /// Get city-specific info from the dispatcher task
+    /// Get city-specific info from the dispatcher task. `query` can be just a city name, or a `CityQuery`
+    /// narrowed to a particular country or state. If there is a `deadline`, fetchers stop working on the
+    /// request once it passes
     ///
     /// # Errors
     /// If sending the request or receiving the response fails
-    pub async fn get_city_info(&self, city_name: String) -> DispatcherResult<String> {
+    pub async fn get_city_info(
+        &self,
+        query: impl Into<CityQuery>,
+        deadline: Option<Instant>,
+    ) -> DispatcherResult<String> {
         let (response_sender, response_receiver) = oneshot::channel();
         let request = DispatcherRequest {
-            city_name,
+            query: query.into(),
+            deadline,
             response_sender,
         };
 
@@ -63,53 +221,287 @@ impl DispatcherHandle {
 
         Ok(response)
     }
+
+    /// Up to `limit` cities that `query` may refer to, best match first, for autocompleting a partly typed
+    /// city name or correcting a misspelled one. See `Gazetteer::suggest`
+    ///
+    /// # Errors
+    /// If making the suggestions panicked
+    pub async fn suggest_cities(
+        &self,
+        query: &CityQuery,
+        limit: usize,
+    ) -> DispatcherResult<Vec<CitySuggestion>> {
+        Ok(suggest(&self.gazetteer, query, limit).await?)
+    }
+
+    /// Whether we're able to answer requests right now. We're ready while every required source is (see
+    /// `DispatcherConfig::required_sources`). Any other source that fails is left out of the response rather
+    /// than failing it (see `handle_request`), so while one isn't ready we're degraded, but still ready
+    ///
+    /// Note: a source that hasn't finished its first health check isn't ready. The first check runs as soon
+    /// as the source starts, so a new instance with a required source that's checked is only held up by it for
+    /// as long as that check takes (at most `HealthCheckConfig::timeout`)
+    pub fn readiness(&self) -> Readiness {
+        let sources = self
+            .source_health
+            .iter()
+            .map(|(name, required, health)| SourceReadiness {
+                name: name.clone(),
+                required: *required,
+                health: health.borrow().clone(),
+            })
+            .collect::<Vec<_>>();
+        let (required, optional): (Vec<_>, Vec<_>) =
+            sources.iter().partition(|source| source.required);
+        let ready = !self.request_sender.is_closed()
+            && required
+                .iter()
+                .all(|source| source.health.status.is_ready());
+        let degraded = ready
+            && !optional
+                .iter()
+                .all(|source| source.health.status.is_ready());
+
+        Readiness {
+            ready,
+            degraded,
+            sources,
+        }
+    }
 }
 
 /// Handle a dispatcher request and send a response
-async fn handle_request(request: DispatcherRequest, fetchers: &[CityDataSourceHandle]) {
-    tracing::info!("Got request for city: {:?}", request.city_name);
+///
+/// Fetchers are asked in order, and each one's result can fill in more detail for the ones after it (see
+/// `CityQuery::enrich_from`), e.g. once the city has been geocoded, later fetchers look it up by
+/// coordinates so every source describes the same place
+///
+/// A source that fails is left out of the response, so e.g. a missing Wikipedia page or wttr.in being down
+/// doesn't cost the user everything the other sources found. Only if nothing could be found does the whole
+/// request fail. If the city can't be found at all, the response suggests the closest matches from
+/// `gazetteer` instead
+///
+/// If one of the `geocoders` fails before the city has been found (e.g. nominatim is down), the city is looked
+/// up in `gazetteer` instead, so sources that only need coordinates (like sun and timezone) still have
+/// something to work with
+async fn handle_request(
+    request: DispatcherRequest,
+    fetchers: &[(String, CityDataSourceHandle)],
+    geocoders: &[String],
+    gazetteer: &Arc<Gazetteer>,
+) {
+    tracing::info!("Got request for city: {:?}", request.query.city);
+
+    let mut query = request.query.clone();
 
     // Aggregate all fetcher responses
     let mut data = String::new();
-    for f in fetchers {
-        // Note: we could do this much more efficiently by using a `FuturesOrdered`
-        // and generating all the requests "at once" before await-ing. This is left
-        // as an exercise for the reader ;)
-        let Ok(response) = f.request_data(request.city_name.clone()).await else {
-            // if a single request fails, overwrite data and give up
-            // Note: we could instead make `DispatcherResponse.data` a `Result<String>` so the
-            // rest layer could more intelligently generate status codes, kept it this way for
-            // simplicity
-            data = String::from("Request failed");
-            break;
+    for (name, f) in fetchers {
+        // if the requester has already given up there's no point asking any (more) fetchers
+        if request.response_sender.is_closed()
+            || request
+                .deadline
+                .is_some_and(|deadline| deadline <= Instant::now())
+        {
+            tracing::info!(
+                "Requester for {:?} gave up waiting, abandoning request",
+                request.query.city
+            );
+            return;
+        }
+
+        // Note: we could do this more efficiently by using a `FuturesOrdered` and generating all the
+        // requests "at once" before await-ing, but then fetchers couldn't build on each other's results.
+        // Splitting fetchers in to "stages" which run concurrently within a stage is left as an exercise
+        // for the reader ;)
+        let started = Instant::now();
+        let result = f.request_data(query.clone(), request.deadline).await;
+        record_source_request(name, &result, started.elapsed());
+
+        let response = match result {
+            Ok(response) => response,
+            // nothing has found it yet and the geocoder couldn't either, so the name is probably misspelled
+            Err(CityDataError::NotFound(city))
+                if query.coordinates.is_none() && data.is_empty() =>
+            {
+                tracing::info!("No city found for {city:?}, suggesting alternatives");
+                let suggestions = suggest(gazetteer, &query, MAX_SUGGESTIONS)
+                    .await
+                    .unwrap_or_else(|e| {
+                        tracing::error!("Failed to suggest alternatives to {city:?}: {e}");
+                        Vec::new()
+                    });
+                data = not_found_response(&city, &suggestions);
+                break;
+            }
+            Err(e) => {
+                tracing::warn!(
+                    "{name} request for {:?} failed, leaving it out: {e}",
+                    request.query.city
+                );
+                if query.coordinates.is_some() || !geocoders.contains(name) {
+                    continue;
+                }
+                // the gazetteer's answer stands in for what the geocoder would have said
+                let Some(place) = gazetteer.lookup(&query).into_iter().next() else {
+                    continue;
+                };
+                tracing::info!(
+                    "Geocoded {:?} from the gazetteer instead",
+                    request.query.city
+                );
+                CityData::Place(place)
+            }
         };
 
-        data.push_str(&response);
+        query.enrich_from(&response);
+
+        // render the structured response as text
+        data.push_str(&response.to_string());
         data.push('\n');
     }
 
+    // Note: we could instead make `DispatcherResponse.data` a `Result<String>` so the rest layer could more
+    // intelligently generate status codes, kept it this way for simplicity
+    if data.is_empty() {
+        data = String::from("Request failed");
+    }
+
     // ignore failures from the `response_sender`, this would only fail if the
     // corresponding `oneshot::Receiver` was dropped, in which case there's
     // nothing we can do here
     _ = request.response_sender.send(DispatcherResponse { data });
 }
 
+/// Record a request made of the source called `name` in our metrics
+fn record_source_request(name: &str, result: &CityDataResult<CityData>, elapsed: Duration) {
+    let result_label = match result {
+        Ok(_) => "ok",
+        Err(e) => e.kind(),
+    };
+    metrics::SOURCE_REQUESTS
+        .with_label_values(&[name, result_label])
+        .inc();
+    metrics::SOURCE_REQUEST_DURATION
+        .with_label_values(&[name])
+        .observe(elapsed.as_secs_f64());
+}
+
+/// Up to `limit` suggestions for `query` from `gazetteer`. Suggesting compares against every name the
+/// gazetteer knows, which for a full GeoNames dump takes long enough to hold up everything else on the
+/// worker thread, so it's done on tokio's blocking thread pool instead
+async fn suggest(
+    gazetteer: &Arc<Gazetteer>,
+    query: &CityQuery,
+    limit: usize,
+) -> Result<Vec<CitySuggestion>, JoinError> {
+    let gazetteer = Arc::clone(gazetteer);
+    let query = query.clone();
+
+    tokio::task::spawn_blocking(move || gazetteer.suggest(&query, limit)).await
+}
+
+/// Record how many requests are waiting in the dispatcher's channel and how many it's working on. Only the
+/// dispatcher task calls this, whenever a request arrives or completes, so nothing else races to set them
+fn record_load(queued: usize, in_flight: usize) {
+    metrics::QUEUE_DEPTH.set(i64::try_from(queued).unwrap_or(i64::MAX));
+    metrics::IN_FLIGHT.set(i64::try_from(in_flight).unwrap_or(i64::MAX));
+}
+
+/// The response for a city nobody could find, listing any `suggestions` for what was meant
+fn not_found_response(city: &str, suggestions: &[CitySuggestion]) -> String {
+    if suggestions.is_empty() {
+        return format!("No city found for {city:?}");
+    }
+
+    let suggestions = suggestions
+        .iter()
+        .map(|suggestion| suggestion.place.display_name.as_str())
+        .collect::<Vec<_>>()
+        .join("; ");
+    format!("No city found for {city:?}, did you mean: {suggestions}?")
+}
+
+/// A registry of every source we ship with, configured from `config`:
+/// - "city_stats": geocodes with nominatim
+/// - "gazetteer": geocodes offline from `config.gazetteer`
+/// - "weather": wttr.in, falling back to Open-Meteo (if `config.weather_fallback` is set)
+/// - "sun": sunrise, sunset and day length, worked out locally
+/// - "timezone": local time and timezone, worked out locally
+/// - "wikipedia": the lead of the city's Wikipedia page
+///
+/// More sources can be registered on the result before it's passed to `spawn_dispatcher_with_sources`
+///
+/// Note: the registry only holds factories, nothing is started until `SourceRegistry::spawn` is called with
+/// the names in `config.sources`. Each source then runs as its own "Actor" task and the dispatcher only ever
+/// talks to its handle, so it doesn't matter whether a source was registered as a concrete type or as a
+/// `Box<dyn DynCityDataSource>` (see `SourceRegistry::register_data_source`), and every source's requests can
+/// be executed on other threads rather than being limited to the dispatcher's
+pub fn builtin_sources(config: &DispatcherConfig) -> SourceRegistry {
+    let mut registry = SourceRegistry::new();
+
+    let city_stats = config.city_stats.clone();
+    registry.register("city_stats", move |context| {
+        spawn_city_stats_fetcher_task(
+            city_stats.clone(),
+            context.http_client.clone(),
+            context.cancellation_token.clone(),
+        )
+    });
+    registry.register("gazetteer", |context| {
+        spawn_gazetteer_task(
+            context.gazetteer.clone(),
+            context.cancellation_token.clone(),
+        )
+    });
+    let weather = config.weather.clone();
+    let weather_fallback = config.weather_fallback.clone();
+    registry.register("weather", move |context| match &weather_fallback {
+        Some(fallback_endpoint) => spawn_weather_fetcher_task_with_fallback(
+            weather.clone(),
+            fallback_endpoint.clone(),
+            context.http_client.clone(),
+            context.cancellation_token.clone(),
+        ),
+        None => spawn_weather_fetcher_task(
+            weather.clone(),
+            context.http_client.clone(),
+            context.cancellation_token.clone(),
+        ),
+    });
+    registry.register("sun", |context| {
+        spawn_sun_task(context.cancellation_token.clone())
+    });
+    registry.register("timezone", |context| {
+        spawn_timezone_task(
+            context.timezone_boundaries.clone(),
+            context.cancellation_token.clone(),
+        )
+    });
+    let wikipedia = config.wikipedia.clone();
+    registry.register("wikipedia", move |context| {
+        spawn_wikipedia_fetcher_task(
+            wikipedia.clone(),
+            context.http_client.clone(),
+            context.cancellation_token.clone(),
+        )
+    });
+
+    registry
+}
+
 // The "Actor" loop, this is the thing which handles incoming requests
 async fn run_dispatcher(
+    // each source's name and a handle to its task, in the order they're asked
+    fetcher_handles: Vec<(String, CityDataSourceHandle)>,
+    // which of those sources are geocoders, see `DispatcherConfig::geocoders`
+    geocoders: Vec<String>,
+    // where suggestions for misspelled cities come from
+    suggestions: Arc<Gazetteer>,
     cancellation_token: CancellationToken,
     mut receiver: mpsc::Receiver<DispatcherRequest>,
 ) {
-    // Note: another option would be to have a vec of `Box<dyn dat_fetchers::CityDataSource>`, and directly call
-    // `entry.fetch_data` for each entry in that Vec but that has a couple of disadvantages:
-    // 1. Dynamic dispatch (`dyn` keyword) requires we use a `Box` which uses space on the stack and creates a vtable
-    //    for function dispatch, which is slower. Standalone "Actor" tasks with handles act as "dynamic dispatch" in this way
-    // 2. Every future created will be limited to this thread (due to the use of `tokio::select!`) where as standalone
-    //    tasks can be executed in other threads
-    let fetcher_handles: Vec<CityDataSourceHandle> = vec![
-        spawn_city_stats_fetcher_task(cancellation_token.clone()),
-        spawn_weather_fetcher_task(cancellation_token.clone()),
-    ];
-
     // this FuturesUnordered is a pool of `Future`s you can treat like an async iterator, it will await
     // any futures it contains and `next` will return any completed future
     let mut pending_requests = FuturesUnordered::new();
@@ -134,9 +526,16 @@ async fn run_dispatcher(
                 };
 
                 // push the request to the pending pool
-                pending_requests.push(handle_request(request, &fetcher_handles));
+                pending_requests.push(handle_request(
+                    request,
+                    &fetcher_handles,
+                    &geocoders,
+                    &suggestions,
+                ));
+                record_load(receiver.len(), pending_requests.len());
             },
             _ = pending_requests.next(), if !pending_requests.is_empty() => {
+                record_load(receiver.len(), pending_requests.len());
                 // nothing to actually do here, as `handle_request` isn't fallible, however we need this entry in the
                 // select! statement so pending_requests is continuously polled, otherwise there will be nothing to
                 // drive the futures it contains to completion.
@@ -152,35 +551,142 @@ async fn run_dispatcher(
     }
 }
 
-/// Spawn our dispatcher inside a task, which will allow it to be scheduled on
+/// Spawn our dispatcher inside a task, which will allow it to be scheduled on, asking the built in sources
+/// (see `builtin_sources`) named in `config.sources`
 /// Note: you may have noticed tha nowhere in this file is an actual `Dispatcher` struct. This is because we don't
 /// actually have any state that we might want to store
-pub fn spawn_dispatcher(cancellation_token: CancellationToken) -> DispatcherHandle {
+///
+/// # Errors
+/// If any part of `config` is invalid, or we're called outside of a tokio runtime
+pub fn spawn_dispatcher(
+    config: DispatcherConfig,
+    cancellation_token: CancellationToken,
+) -> Result<DispatcherHandle, StartupError> {
+    let sources = builtin_sources(&config);
+    spawn_dispatcher_with_sources(config, &sources, cancellation_token)
+}
+
+/// Like `spawn_dispatcher`, but `config.sources` are looked up in `sources`, which lets callers add sources
+/// of their own (or replace built in ones)
+///
+/// Everything that can fail (building the http client, loading the gazetteer and timezone boundaries, starting
+/// the sources) happens before this returns, so a bad config stops us starting at all rather than leaving us
+/// half started
+///
+/// # Errors
+/// If any part of `config` is invalid, a source in `config.sources` isn't in `sources`, or we're called
+/// outside of a tokio runtime
+pub fn spawn_dispatcher_with_sources(
+    config: DispatcherConfig,
+    sources: &SourceRegistry,
+    cancellation_token: CancellationToken,
+) -> Result<DispatcherHandle, StartupError> {
+    let _span = info_span!("Dispatcher").entered();
     let (sender, receiver) = mpsc::channel(128);
 
-    tokio::spawn(run_dispatcher(cancellation_token, receiver).instrument(info_span!("Dispatcher")));
+    let context = SourceContext {
+        http_client: config.http_client.build()?,
+        gazetteer: Arc::new(Gazetteer::load(&config.gazetteer)?),
+        timezone_boundaries: match &config.timezone_boundaries {
+            Some(path) => Some(Arc::new(TimezoneBoundaries::load(path)?)),
+            None => None,
+        },
+        cancellation_token,
+    };
+    let fetcher_handles = sources.spawn(&config.sources, &context)?;
+    tracing::info!("Asking data sources: {}", config.sources.join(", "));
+    let fetcher_handles = config
+        .sources
+        .iter()
+        .cloned()
+        .zip(fetcher_handles)
+        .collect::<Vec<_>>();
+    let source_health = fetcher_handles
+        .iter()
+        .map(|(name, handle)| {
+            let required = config.required_sources.contains(name);
+            (name.clone(), required, handle.health.clone())
+        })
+        .collect();
+
+    tokio::spawn(
+        run_dispatcher(
+            fetcher_handles,
+            config.geocoders,
+            context.gazetteer.clone(),
+            context.cancellation_token,
+            receiver,
+        )
+        .in_current_span(),
+    );
 
-    DispatcherHandle {
+    Ok(DispatcherHandle {
         request_sender: sender,
-    }
+        gazetteer: context.gazetteer,
+        source_health,
+    })
 }
 
 #[cfg(test)]
 mod tests {
-    use data_fetchers::{CityDataRequest, CityDataSourceHandle};
-    use tokio::sync::{mpsc, oneshot};
+    use std::{path::PathBuf, sync::Arc, time::Duration};
+
+    use data_fetchers::{
+        gazetteer::{spawn_gazetteer_task, Gazetteer},
+        health::{HealthCheckConfig, HealthStatus, SourceHealth},
+        CityData, CityDataError, CityDataRequest, CityDataResult, CityDataSource,
+        CityDataSourceHandle, CityDataSourceTask, CityQuery, Coordinates, PlaceRecord, Units,
+        WeatherReport, WikipediaSummary,
+    };
+    use tokio::{
+        sync::{mpsc, oneshot, watch},
+        time::Instant,
+    };
+    use tokio_util::sync::CancellationToken;
+
+    use crate::{
+        builtin_sources, handle_request, spawn_dispatcher, spawn_dispatcher_with_sources,
+        ApiEndpoint, DispatcherConfig, DispatcherRequest, DispatcherResponse, GazetteerSource,
+        StartupError,
+    };
 
-    use crate::{handle_request, DispatcherRequest, DispatcherResponse};
+    /// Greets any city that has been geocoded
+    struct GreetingDataSource;
+
+    impl CityDataSource for GreetingDataSource {
+        async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+            if query.coordinates.is_none() {
+                return Err(CityDataError::NotGeocoded(query.city));
+            }
+            Ok(CityData::Wikipedia(WikipediaSummary {
+                title: query.city.clone(),
+                description: None,
+                extract: format!("Hello from {}", query.city),
+                thumbnail_url: None,
+                page_url: String::from("https://example.com"),
+                language: String::from("en"),
+            }))
+        }
+    }
 
-    fn make_test_fetcher() -> (CityDataSourceHandle, mpsc::Receiver<CityDataRequest>) {
+    fn make_test_fetcher(
+        name: &str,
+    ) -> (
+        (String, CityDataSourceHandle),
+        mpsc::Receiver<CityDataRequest>,
+    ) {
         let (sender, receiver) = mpsc::channel(1);
+        let handle = CityDataSourceHandle {
+            data_request_sender: sender,
+            health: watch::channel(SourceHealth::new(HealthStatus::Unmonitored)).1,
+        };
 
-        (
-            CityDataSourceHandle {
-                data_request_sender: sender,
-            },
-            receiver,
-        )
+        ((String::from(name), handle), receiver)
+    }
+
+    /// The names of the test fetchers that play the geocoder
+    fn test_geocoders() -> Vec<String> {
+        vec![String::from("geocoder")]
     }
 
     fn make_test_request(
@@ -188,7 +694,8 @@ mod tests {
     ) -> (DispatcherRequest, oneshot::Receiver<DispatcherResponse>) {
         let (response_sender, response_receiver) = oneshot::channel();
         let test_request = DispatcherRequest {
-            city_name,
+            query: CityQuery::new(city_name),
+            deadline: None,
             response_sender,
         };
 
@@ -197,7 +704,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_handle_request() {
-        let (test_fetcher_handle, mut test_fetcher_receiver) = make_test_fetcher();
+        let (test_fetcher_handle, mut test_fetcher_receiver) = make_test_fetcher("test");
         let test_fetchers = vec![test_fetcher_handle];
 
         let (test_request, mut response_receiver) =
@@ -213,19 +720,31 @@ mod tests {
                 .recv()
                 .await
                 .expect("Expected test_fetcher_sender not to be dropped");
-            assert_eq!(fetcher_request.city, String::from("Unit Test City"));
+            assert_eq!(fetcher_request.query.city, String::from("Unit Test City"));
 
             // now fire a response
             fetcher_request
                 .responder
-                .send(Ok(String::from("test data for Unit Test City")))
+                .send(Ok(CityData::Place(PlaceRecord {
+                    name: String::from("Unit Test City"),
+                    display_name: String::from("Unit Test City, Test County"),
+                    latitude: 0.0,
+                    longitude: 0.0,
+                    ..PlaceRecord::default()
+                })))
                 .expect("expected to send a result");
 
             // now the task will exit, dropping the `test_fetcher_receiver`
         });
 
         // handle the request
-        handle_request(test_request, &test_fetchers).await;
+        handle_request(
+            test_request,
+            &test_fetchers,
+            &test_geocoders(),
+            &Arc::new(Gazetteer::embedded()),
+        )
+        .await;
 
         // we should see a response on the receiver
         let response = response_receiver
@@ -233,7 +752,7 @@ mod tests {
             .expect("Expected to receive a dispatcher response");
         assert_eq!(
             response.data,
-            String::from("test data for Unit Test City\n")
+            String::from("Stats for Unit Test City, Test County:\n")
         );
 
         // if we send another request, it should fail as the "mock fetcher"
@@ -242,7 +761,13 @@ mod tests {
             make_test_request(String::from("Broken Test Town"));
 
         // handle the request
-        handle_request(new_request, &test_fetchers).await;
+        handle_request(
+            new_request,
+            &test_fetchers,
+            &test_geocoders(),
+            &Arc::new(Gazetteer::embedded()),
+        )
+        .await;
 
         // we should see a failed response on the receiver
         let response = failed_response_receiver
@@ -250,4 +775,489 @@ mod tests {
             .expect("Expected to receive a dispatcher response");
         assert_eq!(response.data, String::from("Request failed"));
     }
+
+    #[tokio::test]
+    async fn test_failing_source_left_out() {
+        let (geocoder_handle, mut geocoder_receiver) = make_test_fetcher("geocoder");
+        let (wikipedia_handle, mut wikipedia_receiver) = make_test_fetcher("wikipedia");
+        let test_fetchers = vec![geocoder_handle, wikipedia_handle];
+
+        tokio::spawn(async move {
+            let geocoder_request = geocoder_receiver
+                .recv()
+                .await
+                .expect("Expected the geocoder to be asked first");
+            geocoder_request
+                .responder
+                .send(Ok(CityData::Place(PlaceRecord {
+                    name: String::from("Mumbai"),
+                    display_name: String::from("Mumbai, Maharashtra, IN"),
+                    latitude: 19.072_8,
+                    longitude: 72.882_6,
+                    ..PlaceRecord::default()
+                })))
+                .expect("expected to send a result");
+
+            // later sources should be told what the place is actually called
+            let wikipedia_request = wikipedia_receiver
+                .recv()
+                .await
+                .expect("Expected wikipedia to be asked second");
+            assert_eq!(
+                wikipedia_request.query.place_name.as_deref(),
+                Some("Mumbai")
+            );
+            let city = wikipedia_request.query.city;
+            wikipedia_request
+                .responder
+                .send(Err(CityDataError::NotFound(city)))
+                .expect("expected to send a result");
+        });
+
+        let (test_request, mut response_receiver) = make_test_request(String::from("Bombay"));
+        handle_request(
+            test_request,
+            &test_fetchers,
+            &test_geocoders(),
+            &Arc::new(Gazetteer::embedded()),
+        )
+        .await;
+
+        // the missing page is left out rather than failing everything
+        let response = response_receiver
+            .try_recv()
+            .expect("Expected to receive a dispatcher response");
+        assert_eq!(response.data, "Stats for Mumbai, Maharashtra, IN:\n");
+    }
+
+    #[tokio::test]
+    async fn test_geocoder_down_falls_back_to_gazetteer() {
+        let (weather_handle, mut weather_receiver) = make_test_fetcher("weather");
+        let (geocoder_handle, mut geocoder_receiver) = make_test_fetcher("geocoder");
+        let (sun_handle, mut sun_receiver) = make_test_fetcher("sun");
+        let test_fetchers = vec![weather_handle, geocoder_handle, sun_handle];
+
+        tokio::spawn(async move {
+            // weather isn't a geocoder, so the gazetteer doesn't stand in for it
+            let weather_request = weather_receiver
+                .recv()
+                .await
+                .expect("Expected weather to be asked first");
+            assert_eq!(weather_request.query.coordinates, None);
+            weather_request
+                .responder
+                .send(Err(CityDataError::Timeout))
+                .expect("expected to send a result");
+
+            let geocoder_request = geocoder_receiver
+                .recv()
+                .await
+                .expect("Expected the geocoder to be asked second");
+            geocoder_request
+                .responder
+                .send(Err(CityDataError::ConnectionFailed(String::from(
+                    "connection refused",
+                ))))
+                .expect("expected to send a result");
+
+            // sun should still be asked, about where the gazetteer says Tokyo is
+            let sun_request = sun_receiver
+                .recv()
+                .await
+                .expect("Expected sun to be asked third");
+            assert!(sun_request.query.coordinates.is_some());
+            let city = sun_request.query.city;
+            sun_request
+                .responder
+                .send(Err(CityDataError::NotFound(city)))
+                .expect("expected to send a result");
+        });
+
+        let (test_request, mut response_receiver) = make_test_request(String::from("Tokyo"));
+        handle_request(
+            test_request,
+            &test_fetchers,
+            &test_geocoders(),
+            &Arc::new(Gazetteer::embedded()),
+        )
+        .await;
+
+        let response = response_receiver
+            .try_recv()
+            .expect("Expected to receive a dispatcher response");
+        assert!(
+            response.data.starts_with("Stats for Tokyo, Tokyo, JP:"),
+            "unexpected response {:?}",
+            response.data
+        );
+        assert_eq!(response.data.matches("Stats for").count(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_expired_request_abandoned() {
+        let (test_fetcher_handle, mut test_fetcher_receiver) = make_test_fetcher("test");
+        let test_fetchers = vec![test_fetcher_handle];
+
+        let (mut test_request, _response_receiver) =
+            make_test_request(String::from("Unit Test City"));
+        test_request.deadline = Some(Instant::now() - Duration::from_secs(1));
+
+        handle_request(
+            test_request,
+            &test_fetchers,
+            &test_geocoders(),
+            &Arc::new(Gazetteer::embedded()),
+        )
+        .await;
+
+        // the requester had already given up, so no fetcher should have been asked for anything
+        assert!(test_fetcher_receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_geocode_feeds_later_fetchers() {
+        let (geocoder_handle, mut geocoder_receiver) = make_test_fetcher("geocoder");
+        let (weather_handle, mut weather_receiver) = make_test_fetcher("weather");
+        let test_fetchers = vec![geocoder_handle, weather_handle];
+
+        let (test_request, mut response_receiver) = make_test_request(String::from("San Jose"));
+
+        tokio::spawn(async move {
+            let geocoder_request = geocoder_receiver
+                .recv()
+                .await
+                .expect("Expected the geocoder to be asked first");
+            assert_eq!(geocoder_request.query.coordinates, None);
+            geocoder_request
+                .responder
+                .send(Ok(CityData::Place(PlaceRecord {
+                    name: String::from("San José"),
+                    display_name: String::from("San José, Costa Rica"),
+                    latitude: 9.932_5,
+                    longitude: -84.079_6,
+                    ..PlaceRecord::default()
+                })))
+                .expect("expected to send a result");
+
+            // the weather fetcher should be asked about the place the geocoder found
+            let weather_request = weather_receiver
+                .recv()
+                .await
+                .expect("Expected the weather fetcher to be asked second");
+            assert_eq!(
+                weather_request.query.coordinates,
+                Some(Coordinates {
+                    latitude: 9.932_5,
+                    longitude: -84.079_6,
+                })
+            );
+            weather_request
+                .responder
+                .send(Ok(CityData::Weather(WeatherReport {
+                    units: Units::Metric,
+                    provider: String::from("wttr.in"),
+                    observation_time: String::from("10:09 PM"),
+                    temperature: 20.0,
+                    feels_like: 21.0,
+                    description: String::from("Sunny"),
+                    wind_direction: String::from("ESE"),
+                    wind_speed: 12.0,
+                    precipitation: 0.0,
+                    forecast: Vec::new(),
+                })))
+                .expect("expected to send a result");
+        });
+
+        handle_request(
+            test_request,
+            &test_fetchers,
+            &test_geocoders(),
+            &Arc::new(Gazetteer::embedded()),
+        )
+        .await;
+
+        let response = response_receiver
+            .try_recv()
+            .expect("Expected to receive a dispatcher response");
+        assert!(response
+            .data
+            .starts_with("Stats for San José, Costa Rica:\nWeather at"));
+    }
+
+    #[tokio::test]
+    async fn test_not_found_suggests() {
+        let (test_fetcher_handle, mut test_fetcher_receiver) = make_test_fetcher("test");
+        let test_fetchers = vec![test_fetcher_handle];
+
+        tokio::spawn(async move {
+            while let Some(fetcher_request) = test_fetcher_receiver.recv().await {
+                let city = fetcher_request.query.city;
+                _ = fetcher_request
+                    .responder
+                    .send(Err(CityDataError::NotFound(city)));
+            }
+        });
+
+        let (test_request, response_receiver) = make_test_request(String::from("Chicgo"));
+        handle_request(
+            test_request,
+            &test_fetchers,
+            &test_geocoders(),
+            &Arc::new(Gazetteer::embedded()),
+        )
+        .await;
+        let response = response_receiver
+            .await
+            .expect("Expected to receive a dispatcher response");
+        assert_eq!(
+            response.data,
+            "No city found for \"Chicgo\", did you mean: Chicago, Illinois, US?"
+        );
+
+        // nothing close enough to suggest
+        let (test_request, response_receiver) = make_test_request(String::from("Xyzzy"));
+        handle_request(
+            test_request,
+            &test_fetchers,
+            &test_geocoders(),
+            &Arc::new(Gazetteer::embedded()),
+        )
+        .await;
+        let response = response_receiver
+            .await
+            .expect("Expected to receive a dispatcher response");
+        assert_eq!(response.data, "No city found for \"Xyzzy\"");
+    }
+
+    #[tokio::test]
+    async fn test_startup_errors() {
+        let cancellation_token = CancellationToken::new();
+        let start = |config: DispatcherConfig| spawn_dispatcher(config, cancellation_token.clone());
+
+        assert!(start(DispatcherConfig::default()).is_ok());
+
+        let mut config = DispatcherConfig::default();
+        config.http_client.proxy = Some(String::from("not a url"));
+        assert!(matches!(start(config), Err(StartupError::HttpClient(_))));
+
+        let config = DispatcherConfig {
+            gazetteer: GazetteerSource::Files {
+                cities: PathBuf::from("/nonexistent/cities15000.txt"),
+                admin1_codes: None,
+            },
+            ..DispatcherConfig::default()
+        };
+        assert!(matches!(start(config), Err(StartupError::Gazetteer(_))));
+
+        let config = DispatcherConfig {
+            timezone_boundaries: Some(PathBuf::from("/nonexistent/combined.json")),
+            ..DispatcherConfig::default()
+        };
+        assert!(matches!(
+            start(config),
+            Err(StartupError::TimezoneBoundaries(_))
+        ));
+
+        let config = DispatcherConfig {
+            weather_fallback: Some(ApiEndpoint::new("not a url", "")),
+            ..DispatcherConfig::default()
+        };
+        let result = start(config);
+        assert!(
+            matches!(&result, Err(StartupError::Sources(_))),
+            "Expected the weather fallback to be rejected, got {:?}",
+            result.err()
+        );
+
+        let config = DispatcherConfig {
+            sources: vec![String::from("gazetteer"), String::from("horoscope")],
+            ..DispatcherConfig::default()
+        };
+        assert!(matches!(start(config), Err(StartupError::Sources(_))));
+
+        cancellation_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_custom_sources() {
+        let config = DispatcherConfig {
+            sources: vec![String::from("gazetteer"), String::from("greeting")],
+            ..DispatcherConfig::default()
+        };
+        // a source from outside the dispatcher, which only knows cities the geocoder found
+        let mut sources = builtin_sources(&config);
+        sources.register_data_source("greeting", |_| Box::new(GreetingDataSource));
+
+        let cancellation_token = CancellationToken::new();
+        let dispatcher_handle =
+            spawn_dispatcher_with_sources(config, &sources, cancellation_token.clone())
+                .expect("expected the dispatcher to start");
+
+        let response = dispatcher_handle
+            .get_city_info("Tokyo", None)
+            .await
+            .expect("expected a response");
+        assert!(
+            response.starts_with("Stats for Tokyo, Tokyo, JP:"),
+            "unexpected response {response:?}"
+        );
+        assert!(
+            response.ends_with("About Tokyo: Hello from Tokyo\nRead more at https://example.com\n"),
+            "unexpected response {response:?}"
+        );
+
+        cancellation_token.cancel();
+    }
+
+    #[tokio::test]
+    async fn test_suggest_cities() {
+        let config = DispatcherConfig {
+            sources: vec![String::from("gazetteer")],
+            ..DispatcherConfig::default()
+        };
+        let cancellation_token = CancellationToken::new();
+        let dispatcher_handle = spawn_dispatcher(config, cancellation_token.clone())
+            .expect("expected the dispatcher to start");
+
+        let suggestions = dispatcher_handle
+            .suggest_cities(&CityQuery::new("San J"), 5)
+            .await
+            .expect("expected suggestions");
+        let names = suggestions
+            .iter()
+            .map(|suggestion| suggestion.place.display_name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec!["San Jose, California, US", "San José, San José, CR"]
+        );
+
+        cancellation_token.cancel();
+    }
+
+    /// Answers nothing, and fails every health check
+    struct UnreachableDataSource;
+
+    impl CityDataSource for UnreachableDataSource {
+        async fn fetch_data(&self, query: CityQuery) -> CityDataResult<CityData> {
+            Err(CityDataError::NotFound(query.city))
+        }
+
+        async fn health_check(&self) -> Option<CityDataResult<()>> {
+            Some(Err(CityDataError::ConnectionFailed(String::from(
+                "connection refused",
+            ))))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_readiness() {
+        let config = DispatcherConfig {
+            sources: vec![String::from("gazetteer"), String::from("sun")],
+            ..DispatcherConfig::default()
+        };
+        let mut sources = builtin_sources(&config);
+        sources.register("unreachable", |context| {
+            CityDataSourceTask::new(UnreachableDataSource)
+                .with_health_check(Some(HealthCheckConfig::default()))
+                .spawn(context.cancellation_token.clone())
+        });
+        let cancellation_token = CancellationToken::new();
+
+        // neither source has a health check, so there's nothing to stop us being ready
+        let dispatcher_handle =
+            spawn_dispatcher_with_sources(config.clone(), &sources, cancellation_token.clone())
+                .expect("expected the dispatcher to start");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let readiness = dispatcher_handle.readiness();
+        assert!(readiness.ready);
+        let names = readiness
+            .sources
+            .iter()
+            .map(|source| source.name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(names, vec!["gazetteer", "sun"]);
+        assert!(readiness
+            .sources
+            .iter()
+            .all(|source| source.health.status == HealthStatus::Unmonitored));
+
+        assert!(!readiness.degraded);
+        assert!(readiness.sources[0].required);
+        assert!(!readiness.sources[1].required);
+
+        // we can answer without a source that isn't required, just not in full
+        let config = DispatcherConfig {
+            sources: vec![String::from("gazetteer"), String::from("unreachable")],
+            ..config
+        };
+        let dispatcher_handle =
+            spawn_dispatcher_with_sources(config.clone(), &sources, cancellation_token.clone())
+                .expect("expected the dispatcher to start");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let readiness = dispatcher_handle.readiness();
+        assert!(readiness.ready);
+        assert!(readiness.degraded);
+        assert_eq!(readiness.sources[1].health.status, HealthStatus::Unhealthy);
+
+        // but a single unhealthy required source is enough to not be ready
+        let config = DispatcherConfig {
+            required_sources: vec![String::from("unreachable")],
+            ..config
+        };
+        let dispatcher_handle =
+            spawn_dispatcher_with_sources(config, &sources, cancellation_token.clone())
+                .expect("expected the dispatcher to start");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let readiness = dispatcher_handle.readiness();
+        assert!(!readiness.ready);
+        assert!(!readiness.degraded);
+
+        // nor is a dispatcher that has shut down
+        cancellation_token.cancel();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!dispatcher_handle.readiness().ready);
+    }
+
+    #[tokio::test]
+    async fn test_metrics() {
+        let config = DispatcherConfig {
+            sources: vec![String::from("metrics_geocoder")],
+            ..DispatcherConfig::default()
+        };
+        // the metrics are global, so use a source no other test asks to keep our counts to ourselves
+        let mut sources = builtin_sources(&config);
+        sources.register("metrics_geocoder", |context| {
+            spawn_gazetteer_task(
+                context.gazetteer.clone(),
+                context.cancellation_token.clone(),
+            )
+        });
+        let cancellation_token = CancellationToken::new();
+        let dispatcher_handle =
+            spawn_dispatcher_with_sources(config, &sources, cancellation_token.clone())
+                .expect("expected the dispatcher to start");
+
+        for city in ["Tokyo", "Paris", "Nowhereville"] {
+            dispatcher_handle
+                .get_city_info(city, None)
+                .await
+                .expect("expected a response");
+        }
+
+        let exported = prometheus::TextEncoder::new()
+            .encode_to_string(&prometheus::gather())
+            .expect("expected metrics to encode");
+        for line in [
+            "city_info_source_requests_total{result=\"ok\",source=\"metrics_geocoder\"} 2",
+            "city_info_source_requests_total{result=\"not_found\",source=\"metrics_geocoder\"} 1",
+            "city_info_source_request_duration_seconds_count{source=\"metrics_geocoder\"} 3",
+            "# TYPE city_info_dispatcher_queue_depth gauge",
+            "# TYPE city_info_dispatcher_requests_in_flight gauge",
+        ] {
+            assert!(exported.contains(line), "expected {line:?} in {exported}");
+        }
+
+        cancellation_token.cancel();
+    }
 }
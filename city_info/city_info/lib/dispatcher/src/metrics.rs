@@ -0,0 +1,46 @@
+use std::sync::LazyLock;
+
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge, HistogramVec,
+    IntCounterVec, IntGauge,
+};
+
+// Like `data_fetchers::metrics`, these are registered with the `prometheus` crate's default registry the
+// first time they're used
+
+/// Requests the dispatcher made of each source, by result: "ok", or the kind of error (see
+/// `CityDataError::kind`)
+pub(crate) static SOURCE_REQUESTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "city_info_source_requests_total",
+        "Requests made of each data source, by result",
+        &["source", "result"]
+    )
+    .expect("metric registered more than once")
+});
+
+/// How long each source took to respond, including any time the request spent queued for the source's task
+pub(crate) static SOURCE_REQUEST_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "city_info_source_request_duration_seconds",
+        "How long each data source took to respond",
+        &["source"]
+    )
+    .expect("metric registered more than once")
+});
+
+pub(crate) static QUEUE_DEPTH: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "city_info_dispatcher_queue_depth",
+        "Requests waiting for the dispatcher to pick them up"
+    )
+    .expect("metric registered more than once")
+});
+
+pub(crate) static IN_FLIGHT: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!(
+        "city_info_dispatcher_requests_in_flight",
+        "Requests the dispatcher is working on"
+    )
+    .expect("metric registered more than once")
+});
@@ -1,20 +1,76 @@
-use std::time::Duration;
+use std::{sync::LazyLock, time::Duration};
 
 use axum::{
-    extract::{Path, State},
-    http::StatusCode,
+    extract::{MatchedPath, Path, Query, Request, State},
+    http::{header::CONTENT_TYPE, HeaderName, StatusCode},
+    middleware::{self, Next},
+    response::Response,
     routing::get,
-    Router,
+    Json, Router,
 };
-use dispatcher::DispatcherHandle;
-use tokio::net::TcpListener;
+use dispatcher::{CityQuery, CitySuggestion, DispatcherHandle, ForecastOptions, Readiness, Units};
+use prometheus::{register_int_counter_vec, IntCounterVec, TextEncoder};
+use serde::Deserialize;
+use tokio::{net::TcpListener, time::Instant};
 use tokio_util::sync::CancellationToken;
 
+/// How long a request may take before we give up and respond with a 408
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The most suggestions an autocomplete request can ask for
+const MAX_AUTOCOMPLETE_LIMIT: usize = 50;
+
+/// Responses we've sent, by route and status code. Like the dispatcher's metrics this is registered with the
+/// `prometheus` crate's default registry, which `/metrics` serves
+static HTTP_RESPONSES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "city_info_http_responses_total",
+        "HTTP responses sent, by route and status code",
+        &["route", "status"]
+    )
+    .expect("metric registered more than once")
+});
+
 #[derive(Clone)]
 struct ApiState {
     dispatcher_handle: DispatcherHandle,
 }
 
+/// Optional query parameters for a city info request
+#[derive(Debug, Default, Deserialize)]
+struct CityInfoParams {
+    /// narrows down an ambiguous city name to a country, as an ISO 3166-1 alpha-2 code,
+    /// e.g. `/Paris?country=fr`
+    country: Option<String>,
+    /// narrows down an ambiguous city name to a state, e.g. `/Springfield?state=Illinois`
+    state: Option<String>,
+    /// how many days of weather forecast to include, e.g. `/Chicago?days=3`
+    #[serde(default)]
+    days: u8,
+    /// whether to break the forecast down by hour, e.g. `/Chicago?days=1&hourly=true`
+    #[serde(default)]
+    hourly: bool,
+    /// one of "metric" (the default), "imperial" or "si", e.g. `/Chicago?units=imperial`
+    #[serde(default)]
+    units: Units,
+}
+
+/// Optional query parameters for an autocomplete request
+#[derive(Debug, Deserialize)]
+struct AutocompleteParams {
+    /// only suggest cities in this country, as an ISO 3166-1 alpha-2 code, e.g. `/autocomplete/San?country=cr`
+    country: Option<String>,
+    /// only suggest cities in this state, e.g. `/autocomplete/Spring?state=Illinois`
+    state: Option<String>,
+    /// how many suggestions to return, at most `MAX_AUTOCOMPLETE_LIMIT`, e.g. `/autocomplete/San?limit=3`
+    #[serde(default = "default_autocomplete_limit")]
+    limit: usize,
+}
+
+fn default_autocomplete_limit() -> usize {
+    10
+}
+
 /// Start up the rest API task
 ///
 /// # Errors
@@ -40,6 +96,12 @@ fn setup_rest_app(dispatcher_handle: DispatcherHandle) -> Router {
     // build our application with a route
     Router::new()
         .route("/:city_name", get(get_city_info))
+        .route("/autocomplete/:prefix", get(autocomplete_city))
+        .route("/health/ready", get(readiness))
+        .route("/metrics", get(metrics))
+        // note: a `route_layer` only runs for requests that matched a route, which is what lets us label
+        // responses by route. Our catch-all `/:city_name` route means that's almost every request anyway
+        .route_layer(middleware::from_fn(record_response))
         // this state is passed to any path fn with the State() extractor
         .with_state(ApiState { dispatcher_handle })
 }
@@ -47,16 +109,34 @@ fn setup_rest_app(dispatcher_handle: DispatcherHandle) -> Router {
 /// Get city-specific info for the given city from our dispatcher
 /// Note we return (StatusCode, String) here, which axum conveniently converts
 /// into an HTTP response for us (<https://docs.rs/axum/latest/axum/response/index.html>)
+///
+/// If the city name is ambiguous, the response lists the other places it could have meant, which can be
+/// picked between with the `country` and `state` query parameters (see `CityInfoParams`)
 async fn get_city_info(
     Path(city_name): Path<String>,
+    Query(params): Query<CityInfoParams>,
     State(state): State<ApiState>,
 ) -> (StatusCode, String) {
-    tracing::info!("Querying data for city: {city_name}");
+    tracing::info!("Querying data for city: {city_name} ({params:?})");
+
+    let mut query = CityQuery::new(city_name)
+        .with_forecast(ForecastOptions {
+            days: params.days,
+            hourly: params.hourly,
+        })
+        .with_units(params.units);
+    query.country_code = params.country;
+    query.state = params.state;
+    if let Err(e) = query.validate() {
+        return (StatusCode::BAD_REQUEST, e.to_string());
+    }
 
-    // try to make the request, wrapping it in a timeout
-    let Ok(result) = tokio::time::timeout(
-        Duration::from_secs(10),
-        state.dispatcher_handle.get_city_info(city_name),
+    // try to make the request, wrapping it in a timeout. The deadline is passed along too, so the
+    // dispatcher and fetchers stop working on our behalf once we've given up on them
+    let deadline = Instant::now() + REQUEST_TIMEOUT;
+    let Ok(result) = tokio::time::timeout_at(
+        deadline,
+        state.dispatcher_handle.get_city_info(query, Some(deadline)),
     )
     .await
     else {
@@ -79,3 +159,147 @@ async fn get_city_info(
     // All succeeded, return 200
     (StatusCode::OK, data)
 }
+
+/// Suggest cities for a partly typed (or misspelled) city name, as JSON. Suggestions are ranked by how
+/// well they matched, then by population, e.g. `/autocomplete/San%20J` suggests San Jose, California
+/// before San José, Costa Rica
+async fn autocomplete_city(
+    Path(prefix): Path<String>,
+    Query(params): Query<AutocompleteParams>,
+    State(state): State<ApiState>,
+) -> Result<Json<Vec<CitySuggestion>>, (StatusCode, String)> {
+    let mut query = CityQuery::new(prefix);
+    query.country_code = params.country;
+    query.state = params.state;
+
+    state
+        .dispatcher_handle
+        .suggest_cities(&query, params.limit.min(MAX_AUTOCOMPLETE_LIMIT))
+        .await
+        .map(Json)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, format!("{e:?}")))
+}
+
+/// Whether we're able to answer requests, along with the health of each data source, as JSON. Responds
+/// with a 200 when ready and a 503 when not, so load balancers and orchestrators can act on the status
+/// alone
+async fn readiness(State(state): State<ApiState>) -> (StatusCode, Json<Readiness>) {
+    let readiness = state.dispatcher_handle.readiness();
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(readiness))
+}
+
+/// Every metric we (and the dispatcher and data sources) record, in the Prometheus text format. See
+/// <https://prometheus.io/docs/instrumenting/exposition_formats/>
+async fn metrics() -> (StatusCode, [(HeaderName, &'static str); 1], String) {
+    match TextEncoder::new().encode_to_string(&prometheus::gather()) {
+        Ok(exported) => (
+            StatusCode::OK,
+            [(CONTENT_TYPE, prometheus::TEXT_FORMAT)],
+            exported,
+        ),
+        Err(e) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            [(CONTENT_TYPE, "text/plain")],
+            format!("failed to encode metrics: {e}"),
+        ),
+    }
+}
+
+/// Middleware counting the responses we send by route and status code. Routes are labelled with the
+/// pattern they matched (e.g. `/:city_name`) rather than the actual path, so every city shares a label
+async fn record_response(matched_path: MatchedPath, request: Request, next: Next) -> Response {
+    let response = next.run(request).await;
+    HTTP_RESPONSES
+        .with_label_values(&[matched_path.as_str(), response.status().as_str()])
+        .inc();
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{
+        body::{to_bytes, Body},
+        http::{Request, StatusCode},
+    };
+    use dispatcher::{spawn_dispatcher, DispatcherConfig};
+    use tokio_util::sync::CancellationToken;
+    use tower::ServiceExt;
+
+    use crate::{setup_rest_app, HTTP_RESPONSES};
+
+    #[tokio::test]
+    async fn test_metrics() {
+        // only offline sources, so this works without a network
+        let config = DispatcherConfig {
+            sources: ["gazetteer", "sun", "timezone"].map(String::from).to_vec(),
+            ..DispatcherConfig::default()
+        };
+        let cancellation_token = CancellationToken::new();
+        let dispatcher_handle = spawn_dispatcher(config, cancellation_token.clone())
+            .expect("expected the dispatcher to start");
+        let app = setup_rest_app(dispatcher_handle);
+
+        let city_info_responses = || {
+            HTTP_RESPONSES
+                .with_label_values(&["/:city_name", "200"])
+                .get()
+        };
+        let before = city_info_responses();
+
+        let response = app
+            .clone()
+            .oneshot(Request::get("/Tokyo").body(Body::empty()).unwrap())
+            .await
+            .expect("expected a response");
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(city_info_responses() - before, 1);
+
+        // a country code that isn't one is turned away before it gets anywhere near an upstream
+        let response = app
+            .clone()
+            .oneshot(
+                Request::get("/Tokyo?country=jp%26limit%3D50")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .expect("expected a response");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let response = app
+            .oneshot(Request::get("/metrics").body(Body::empty()).unwrap())
+            .await
+            .expect("expected a response");
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX)
+            .await
+            .expect("expected a body");
+        let exported = String::from_utf8_lossy(&body);
+
+        // the city was labelled with the route it matched rather than its own path
+        assert!(
+            exported
+                .contains(r#"city_info_http_responses_total{route="/:city_name",status="200"}"#),
+            "unexpected metrics {exported}"
+        );
+        assert!(!exported.contains("Tokyo"), "unexpected metrics {exported}");
+        assert!(
+            exported
+                .contains(r#"city_info_http_responses_total{route="/:city_name",status="400"}"#),
+            "unexpected metrics {exported}"
+        );
+        assert!(
+            exported.contains(r#"city_info_source_requests_total{result="ok",source="gazetteer"}"#),
+            "unexpected metrics {exported}"
+        );
+
+        cancellation_token.cancel();
+    }
+}